@@ -17,7 +17,7 @@ _aichat() {
 
     case "${cmd}" in
         aichat)
-            opts="-m -r -s -a -e -c -f -S -h -V --model --prompt --role --session --empty-session --save-session --agent --agent-variable --rag --rebuild-rag --macro --serve --execute --code --file --no-stream --dry-run --info --sync-models --list-models --list-roles --list-sessions --list-agents --list-rags --list-macros --help --version"
+            opts="-m -r -s -a -e -c -f -S -h -V --model --prompt --role --session --empty-session --save-session --agent --agent-variable --install-agent --update-agent --remove-agent --rag --rebuild-rag --eval-rag --macro --replay --serve --mcp --execute --plan --explain-last --fix-last --code --file --no-stream --dry-run --info --sync-models --list-models --list-roles --list-sessions --search-history --export-session --format --import-session --list-agents --list-rags --list-macros --help --version"
             if [[ ${cur} == -* || ${cword} -eq 1 ]] ; then
                 COMPREPLY=( $(compgen -W "${opts}" -- "${cur}") )
                 return 0
@@ -38,12 +38,12 @@ _aichat() {
                     __ltrim_colon_completions "$cur"
                     return 0
                     ;;
-                -s|--session)
+                -s|--session|--export-session)
                     COMPREPLY=($(compgen -W "$("$1" --list-sessions)" -- "${cur}"))
                     __ltrim_colon_completions "$cur"
                     return 0
                     ;;
-                -a|--agent)
+                -a|--agent|--update-agent|--remove-agent)
                     COMPREPLY=($(compgen -W "$("$1" --list-agents)" -- "${cur}"))
                     __ltrim_colon_completions "$cur"
                     return 0
@@ -58,7 +58,11 @@ _aichat() {
                     __ltrim_colon_completions "$cur"
                     return 0
                     ;;
-                -f|--file)
+                --format)
+                    COMPREPLY=($(compgen -W "md html json jsonl" -- "${cur}"))
+                    return 0
+                    ;;
+                -f|--file|--replay|--import-session)
                     local oldifs
                     if [[ -v IFS ]]; then
                         oldifs="$IFS"
@@ -6,11 +6,20 @@ complete -c aichat -l empty-session -d 'Ensure the session is empty'
 complete -c aichat -l save-session -d 'Ensure the new conversation is saved to the session'
 complete -c aichat -s a -l agent -x  -a "(aichat --list-agents)" -d 'Start a agent' -r
 complete -c aichat -l agent-variable -d 'Set agent variables'
+complete -c aichat -l install-agent -d 'Install an agent from a git URL, a tarball or a directory' -r -F
+complete -c aichat -l update-agent -x  -a "(aichat --list-agents)" -d 'Update an installed agent from its source' -r
+complete -c aichat -l remove-agent -x  -a "(aichat --list-agents)" -d 'Remove an agent' -r
 complete -c aichat -l rag -x  -a"(aichat --list-rags)" -d 'Start a RAG' -r
 complete -c aichat -l rebuild-rag -d 'Rebuild the RAG to sync document changes'
+complete -c aichat -l eval-rag -d 'Evaluate RAG retrieval against a JSONL question set'
 complete -c aichat -l macro -x  -a"(aichat --list-macros)" -d 'Execute a macro' -r
+complete -c aichat -l replay -d 'Re-run a recorded trace with its tool outputs stubbed in' -r -F
 complete -c aichat -l serve -d 'Serve the LLM API and WebAPP'
+complete -c aichat -l mcp -d 'Serve roles, RAGs, agents and macros over MCP stdio'
 complete -c aichat -s e -l execute -d 'Execute commands in natural language'
+complete -c aichat -l plan -d 'Let the model propose a multi-step plan in execute mode'
+complete -c aichat -l explain-last -d 'Explain the last command recorded by the shell integration'
+complete -c aichat -l fix-last -d 'Fix the last command recorded by the shell integration'
 complete -c aichat -s c -l code -d 'Output code only'
 complete -c aichat -s f -l file -d 'Include files, directories, or URLs' -r -F
 complete -c aichat -s S -l no-stream -d 'Turn off stream mode'
@@ -20,6 +29,10 @@ complete -c aichat -l sync-models -d 'Sync models updates'
 complete -c aichat -l list-models -d 'List all available chat models'
 complete -c aichat -l list-roles -d 'List all roles'
 complete -c aichat -l list-sessions -d 'List all sessions'
+complete -c aichat -l search-history -d 'Search the messages of all sessions' -x
+complete -c aichat -l export-session -x -a "(aichat --list-sessions)" -d 'Export a session to stdout'
+complete -c aichat -l format -x -a "md html json jsonl" -d 'Export format: md, html, json or jsonl'
+complete -c aichat -l import-session -d 'Import sessions from a JSON/JSONL file or a ChatGPT export' -r -F
 complete -c aichat -l list-agents -d 'List all agents'
 complete -c aichat -l list-rags -d 'List all RAGs'
 complete -c aichat -l list-macros -d 'List all macros'
@@ -32,12 +32,21 @@ Register-ArgumentCompleter -Native -CommandName 'aichat' -ScriptBlock {
             [CompletionResult]::new('-a', '-a', [CompletionResultType]::ParameterName, 'Start a agent')
             [CompletionResult]::new('--agent', '--agent', [CompletionResultType]::ParameterName, 'Start a agent')
             [CompletionResult]::new('--agent-variable', '--agent-variable', [CompletionResultType]::ParameterName, 'Set agent variables')
+            [CompletionResult]::new('--install-agent', '--install-agent', [CompletionResultType]::ParameterName, 'Install an agent from a git URL, a tarball or a directory')
+            [CompletionResult]::new('--update-agent', '--update-agent', [CompletionResultType]::ParameterName, 'Update an installed agent from its source')
+            [CompletionResult]::new('--remove-agent', '--remove-agent', [CompletionResultType]::ParameterName, 'Remove an agent')
             [CompletionResult]::new('--rag', '--rag', [CompletionResultType]::ParameterName, 'Start a RAG')
             [CompletionResult]::new('--rebuild-rag', '--rebuild-rag', [CompletionResultType]::ParameterName, 'Rebuild the RAG to sync document changes')
+            [CompletionResult]::new('--eval-rag', '--eval-rag', [CompletionResultType]::ParameterName, 'Evaluate RAG retrieval against a JSONL question set')
             [CompletionResult]::new('--macro', '--macro', [CompletionResultType]::ParameterName, 'Execute a macro')
+            [CompletionResult]::new('--replay', '--replay', [CompletionResultType]::ParameterName, 'Re-run a recorded trace with its tool outputs stubbed in')
             [CompletionResult]::new('--serve', '--serve', [CompletionResultType]::ParameterName, 'Serve the LLM API and WebAPP')
+            [CompletionResult]::new('--mcp', '--mcp', [CompletionResultType]::ParameterName, 'Serve roles, RAGs, agents and macros over MCP stdio')
             [CompletionResult]::new('-e', '-e', [CompletionResultType]::ParameterName, 'Execute commands in natural language')
             [CompletionResult]::new('--execute', '--execute', [CompletionResultType]::ParameterName, 'Execute commands in natural language')
+            [CompletionResult]::new('--plan', '--plan', [CompletionResultType]::ParameterName, 'Let the model propose a multi-step plan in execute mode')
+            [CompletionResult]::new('--explain-last', '--explain-last', [CompletionResultType]::ParameterName, 'Explain the last command recorded by the shell integration')
+            [CompletionResult]::new('--fix-last', '--fix-last', [CompletionResultType]::ParameterName, 'Fix the last command recorded by the shell integration')
             [CompletionResult]::new('-c', '-c', [CompletionResultType]::ParameterName, 'Output code only')
             [CompletionResult]::new('--code', '--code', [CompletionResultType]::ParameterName, 'Output code only')
             [CompletionResult]::new('-f', '-f', [CompletionResultType]::ParameterName, 'Include files, directories, or URLs')
@@ -50,6 +59,10 @@ Register-ArgumentCompleter -Native -CommandName 'aichat' -ScriptBlock {
             [CompletionResult]::new('--list-models', '--list-models', [CompletionResultType]::ParameterName, 'List all available chat models')
             [CompletionResult]::new('--list-roles', '--list-roles', [CompletionResultType]::ParameterName, 'List all roles')
             [CompletionResult]::new('--list-sessions', '--list-sessions', [CompletionResultType]::ParameterName, 'List all sessions')
+            [CompletionResult]::new('--search-history', '--search-history', [CompletionResultType]::ParameterName, 'Search the messages of all sessions')
+            [CompletionResult]::new('--export-session', '--export-session', [CompletionResultType]::ParameterName, 'Export a session to stdout')
+            [CompletionResult]::new('--format', '--format', [CompletionResultType]::ParameterName, 'Export format: md, html, json or jsonl')
+            [CompletionResult]::new('--import-session', '--import-session', [CompletionResultType]::ParameterName, 'Import sessions from a JSON/JSONL file or a ChatGPT export')
             [CompletionResult]::new('--list-agents', '--list-agents', [CompletionResultType]::ParameterName, 'List all agents')
             [CompletionResult]::new('--list-rags', '--list-rags', [CompletionResultType]::ParameterName, 'List all RAGs')
             [CompletionResult]::new('--list-macros', '--list-macros', [CompletionResultType]::ParameterName, 'List all macros')
@@ -76,15 +89,17 @@ Register-ArgumentCompleter -Native -CommandName 'aichat' -ScriptBlock {
             $completions = Get-AichatValues "--list-models"
         } elseif ($flag -ceq "-r" -or $flag -eq "--role") {
             $completions = Get-AichatValues "--list-roles"
-        } elseif ($flag -ceq "-s" -or $flag -eq "--session") {
+        } elseif ($flag -ceq "-s" -or $flag -eq "--session" -or $flag -eq "--export-session") {
             $completions = Get-AichatValues "--list-sessions"
-        } elseif ($flag -ceq "-a" -or $flag -eq "--agent") {
+        } elseif ($flag -ceq "-a" -or $flag -eq "--agent" -or $flag -eq "--update-agent" -or $flag -eq "--remove-agent") {
             $completions = Get-AichatValues "--list-agents"
         } elseif ($flag -eq "--rag") {
             $completions = Get-AichatValues "--list-rags"
         } elseif ($flag -eq "--macro") {
             $completions = Get-AichatValues "--list-macros"
-        } elseif ($flag -ceq "-f" -or $flag -eq "--file") {
+        } elseif ($flag -eq "--format") {
+            $completions = @("md", "html", "json", "jsonl") | ForEach-Object { [CompletionResult]::new($_) }
+        } elseif ($flag -ceq "-f" -or $flag -eq "--file" -or $flag -eq "--replay" -or $flag -eq "--import-session") {
             $completions = @()
         }
     }
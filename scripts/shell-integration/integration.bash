@@ -1,7 +1,64 @@
+# Keep the record in a private directory, other users can't read it or plant a symlink there
+if [[ -n "$XDG_RUNTIME_DIR" && -d "$XDG_RUNTIME_DIR" ]]; then
+    _aichat_dir=$XDG_RUNTIME_DIR
+else
+    _aichat_dir="${TMPDIR:-/tmp}/aichat-$UID"
+    mkdir -m 700 "$_aichat_dir" 2>/dev/null
+    if [[ -L "$_aichat_dir" || ! -d "$_aichat_dir" || ! -O "$_aichat_dir" ]]; then
+        echo "aichat: '$_aichat_dir' is not a directory owned by you, shell integration disabled" >&2
+        return 1
+    fi
+    chmod 700 "$_aichat_dir"
+fi
+export AICHAT_LAST_COMMAND_FILE="$_aichat_dir/aichat-last-command-$$"
+
 _aichat_bash() {
     if [[ -n "$READLINE_LINE" ]]; then
         READLINE_LINE=$(aichat -e "$READLINE_LINE")
         READLINE_POINT=${#READLINE_LINE}
+    else
+        aichat --explain-last
+    fi
+}
+bind -x '"\ee": _aichat_bash'
+
+# Record the last command and its exit code for `aichat --explain-last` and `aichat --fix-last`.
+# Set AICHAT_CAPTURE_OUTPUT=1 before sourcing this script to also capture its output; this takes
+# the DEBUG trap, and the command then writes to a pipe instead of the terminal, which full-screen
+# programs do not like.
+_aichat_preexec() {
+    [[ -n "$_aichat_armed" ]] || return
+    unset _aichat_armed
+    [[ "$BASH_COMMAND" == _aichat_record* || "$BASH_COMMAND" =~ ^aichat\ --(explain|fix)-last ]] && return
+    rm -f "$AICHAT_LAST_COMMAND_FILE.output"
+    if [[ "$AICHAT_CAPTURE_OUTPUT" == 1 ]]; then
+        exec {_aichat_stdout}>&1 {_aichat_stderr}>&2
+        exec > >(tee "$AICHAT_LAST_COMMAND_FILE.output") 2>&1
+    fi
+}
+
+_aichat_record() {
+    local code=$?
+    if [[ -n "$_aichat_stdout" ]]; then
+        exec 1>&$_aichat_stdout 2>&$_aichat_stderr {_aichat_stdout}>&- {_aichat_stderr}>&-
+        unset _aichat_stdout _aichat_stderr
+    fi
+    local entry
+    entry=$(HISTTIMEFORMAT= builtin history 1)
+    if [[ -n "$entry" && "$entry" != "$_aichat_last_entry" ]]; then
+        _aichat_last_entry=$entry
+        local cmd
+        cmd=$(sed -e 's/^ *[0-9]*\*\? *//' <<<"$entry")
+        cmd=${cmd//$'\n'/ }
+        if [[ ! "$cmd" =~ ^aichat\ --(explain|fix)-last ]]; then
+            printf '%s\n%s\n' "$code" "$cmd" >"$AICHAT_LAST_COMMAND_FILE"
+        fi
     fi
+    return $code
 }
-bind -x '"\ee": _aichat_bash'
\ No newline at end of file
+PROMPT_COMMAND="_aichat_record${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+if [[ "$AICHAT_CAPTURE_OUTPUT" == 1 ]]; then
+    # Arm the trap last, so only the next command line is captured
+    PROMPT_COMMAND+=";_aichat_armed=1"
+    trap '_aichat_preexec' DEBUG
+fi
@@ -1,9 +1,37 @@
+# Keep the record in a private directory, other users can't read it or plant a symlink there
+if set -q XDG_RUNTIME_DIR; and test -d "$XDG_RUNTIME_DIR"
+    set -g _aichat_dir $XDG_RUNTIME_DIR
+else
+    set -g _aichat_dir /tmp
+    set -q TMPDIR; and set _aichat_dir $TMPDIR
+    set _aichat_dir "$_aichat_dir/aichat-"(id -u)
+    mkdir -m 700 "$_aichat_dir" 2>/dev/null
+    if test -L "$_aichat_dir"; or not test -d "$_aichat_dir"; or not test -O "$_aichat_dir"
+        echo "aichat: '$_aichat_dir' is not a directory owned by you, shell integration disabled" >&2
+        return 1
+    end
+    chmod 700 "$_aichat_dir"
+end
+set -gx AICHAT_LAST_COMMAND_FILE "$_aichat_dir/aichat-last-command-$fish_pid"
+
 function _aichat_fish
     set -l _old (commandline)
-    if test -n $_old
+    if test -n "$_old"
         echo -n "⌛"
         commandline -f repaint
         commandline (aichat -e $_old)
+    else
+        echo
+        aichat --explain-last
+        commandline -f repaint
     end
 end
-bind \ee _aichat_fish
\ No newline at end of file
+bind \ee _aichat_fish
+
+# Record the last command and its exit code for `aichat --explain-last` and `aichat --fix-last`
+function _aichat_record --on-event fish_postexec
+    set -l code $status
+    test -n "$argv[1]"; or return
+    string match -qr '^aichat --(explain|fix)-last' -- $argv[1]; and return
+    printf '%s\n%s\n' $code (string join ' ' -- (string split \n -- $argv[1])) >$AICHAT_LAST_COMMAND_FILE
+end
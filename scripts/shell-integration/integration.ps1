@@ -1,3 +1,19 @@
+# Keep the record in a private directory, other users can't read it or plant a symlink there
+$_aichat_dir = $env:XDG_RUNTIME_DIR
+if (-not $_aichat_dir -or -not (Test-Path $_aichat_dir)) {
+    if ($IsLinux -or $IsMacOS) {
+        $_aichat_dir = Join-Path ([System.IO.Path]::GetTempPath()) "aichat-$(id -u)"
+        sh -c '[ -d "$1" ] || mkdir -m 700 "$1"; [ -d "$1" ] && [ ! -L "$1" ] && [ -O "$1" ] && chmod 700 "$1"' sh $_aichat_dir
+        if ($LASTEXITCODE -ne 0) {
+            throw "aichat: '$_aichat_dir' is not a directory owned by you, shell integration disabled"
+        }
+    } else {
+        $_aichat_dir = Join-Path ([System.IO.Path]::GetTempPath()) "aichat-$env:USERNAME"
+        New-Item -ItemType Directory -Path $_aichat_dir -Force | Out-Null
+    }
+}
+$env:AICHAT_LAST_COMMAND_FILE = Join-Path $_aichat_dir "aichat-last-command-$PID"
+
 Set-PSReadLineKeyHandler -Chord "alt+e" -ScriptBlock {
     $_old = $null
     [Microsoft.PowerShell.PSConsoleReadline]::GetBufferState([ref]$_old, [ref]$null)
@@ -6,5 +22,24 @@ Set-PSReadLineKeyHandler -Chord "alt+e" -ScriptBlock {
         $_new = (aichat -e $_old)
         [Microsoft.PowerShell.PSConsoleReadLine]::DeleteLine()
         [Microsoft.PowerShell.PSConsoleReadline]::Insert($_new)
+    } else {
+        [Console]::WriteLine()
+        aichat --explain-last | Out-Host
+        [Microsoft.PowerShell.PSConsoleReadLine]::InvokePrompt()
+    }
+}
+
+# Record the last command, its exit code and error for `aichat --explain-last` and `aichat --fix-last`
+$global:_aichat_prompt = $function:prompt
+function global:prompt {
+    $_success = $?
+    $_entry = Get-History -Count 1
+    if ($_entry -and $_entry.Id -ne $global:_aichat_last_id -and $_entry.CommandLine -notmatch '^aichat --(explain|fix)-last') {
+        $global:_aichat_last_id = $_entry.Id
+        $_code = if ($_success) { 0 } elseif ($LASTEXITCODE) { $LASTEXITCODE } else { 1 }
+        $_output = if (-not $_success -and $Error.Count -gt 0) { $Error[0] | Out-String } else { "" }
+        $_command = $_entry.CommandLine -replace "`r?`n", " "
+        Set-Content -Path $env:AICHAT_LAST_COMMAND_FILE -Value "$_code`n$_command`n$_output" -NoNewline
     }
-}
\ No newline at end of file
+    & $global:_aichat_prompt
+}
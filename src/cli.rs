@@ -30,21 +30,48 @@ pub struct Cli {
     /// Set agent variables
     #[clap(long, value_names = ["NAME", "VALUE"], num_args = 2)]
     pub agent_variable: Vec<String>,
+    /// Install an agent from a git URL, a tarball or a directory
+    #[clap(long, value_name = "SOURCE")]
+    pub install_agent: Option<String>,
+    /// Update an installed agent from its source
+    #[clap(long, value_name = "AGENT")]
+    pub update_agent: Option<String>,
+    /// Remove an agent
+    #[clap(long, value_name = "AGENT")]
+    pub remove_agent: Option<String>,
     /// Start a RAG
     #[clap(long)]
     pub rag: Option<String>,
     /// Rebuild the RAG to sync document changes
     #[clap(long)]
     pub rebuild_rag: bool,
+    /// Evaluate RAG retrieval against a JSONL question set
+    #[clap(long, value_names = ["NAME", "QUESTIONS"], num_args = 2)]
+    pub eval_rag: Option<Vec<String>>,
     /// Execute a macro
     #[clap(long = "macro", value_name = "MACRO")]
     pub macro_name: Option<String>,
+    /// Re-run a recorded trace with its tool outputs stubbed in
+    #[clap(long, value_name = "FILE")]
+    pub replay: Option<String>,
     /// Serve the LLM API and WebAPP
     #[clap(long, value_name = "ADDRESS")]
     pub serve: Option<Option<String>>,
+    /// Serve roles, RAGs, agents and macros over MCP stdio
+    #[clap(long)]
+    pub mcp: bool,
     /// Execute commands in natural language
     #[clap(short = 'e', long)]
     pub execute: bool,
+    /// Let the model propose a multi-step plan in execute mode
+    #[clap(long, requires = "execute")]
+    pub plan: bool,
+    /// Explain the last command recorded by the shell integration
+    #[clap(long, conflicts_with_all = ["execute", "fix_last"])]
+    pub explain_last: bool,
+    /// Fix the last command recorded by the shell integration
+    #[clap(long, conflicts_with = "execute")]
+    pub fix_last: bool,
     /// Output code only
     #[clap(short = 'c', long)]
     pub code: bool,
@@ -72,6 +99,18 @@ pub struct Cli {
     /// List all sessions
     #[clap(long)]
     pub list_sessions: bool,
+    /// Search the messages of all sessions
+    #[clap(long, value_name = "QUERY")]
+    pub search_history: Option<String>,
+    /// Export a session to stdout
+    #[clap(long, value_name = "NAME")]
+    pub export_session: Option<String>,
+    /// Export format: md, html, json or jsonl
+    #[clap(long, value_name = "FORMAT", requires = "export_session")]
+    pub format: Option<String>,
+    /// Import sessions from a JSON/JSONL file or a ChatGPT export
+    #[clap(long, value_name = "FILE")]
+    pub import_session: Option<String>,
     /// List all agents
     #[clap(long)]
     pub list_agents: bool,
@@ -1,7 +1,7 @@
 use super::*;
 
 use crate::{
-    config::{Config, GlobalConfig, Input},
+    config::{Config, GlobalConfig, Input, TraceEvent},
     function::{eval_tool_calls, FunctionDeclaration, ToolCall, ToolResult},
     render::render_stream,
     utils::*,
@@ -17,7 +17,7 @@ use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::sync::LazyLock;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::unbounded_channel;
 
 const MODELS_YAML: &str = include_str!("../../models.yaml");
@@ -410,10 +410,11 @@ pub async fn call_chat_completions(
     client: &dyn Client,
     abort_signal: AbortSignal,
 ) -> Result<(String, Vec<ToolResult>)> {
+    let start = Instant::now();
     let ret = abortable_run_with_spinner(
         client.chat_completions(input.clone()),
         "Generating",
-        abort_signal,
+        abort_signal.clone(),
     )
     .await;
 
@@ -424,6 +425,9 @@ pub async fn call_chat_completions(
                 tool_calls,
                 ..
             } = ret;
+            client.global_config().read().record_trace(|| {
+                TraceEvent::request(input, &text, &tool_calls, None, start.elapsed())
+            });
             if !text.is_empty() {
                 if extract_code {
                     text = extract_code_block(&strip_think_tag(&text)).to_string();
@@ -432,9 +436,17 @@ pub async fn call_chat_completions(
                     client.global_config().read().print_markdown(&text)?;
                 }
             }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+            Ok((
+                text,
+                eval_tool_calls(client.global_config(), tool_calls, abort_signal).await?,
+            ))
+        }
+        Err(err) => {
+            client.global_config().read().record_trace(|| {
+                TraceEvent::request(input, "", &[], Some(format!("{err:#}")), start.elapsed())
+            });
+            Err(err)
         }
-        Err(err) => Err(err),
     }
 }
 
@@ -443,6 +455,7 @@ pub async fn call_chat_completions_streaming(
     client: &dyn Client,
     abort_signal: AbortSignal,
 ) -> Result<(String, Vec<ToolResult>)> {
+    let start = Instant::now();
     let (tx, rx) = unbounded_channel();
     let mut handler = SseHandler::new(tx, abort_signal.clone());
 
@@ -458,12 +471,19 @@ pub async fn call_chat_completions_streaming(
     render_ret?;
 
     let (text, tool_calls) = handler.take();
+    client.global_config().read().record_trace(|| {
+        let error = send_ret.as_ref().err().map(|err| format!("{err:#}"));
+        TraceEvent::request(input, &text, &tool_calls, error, start.elapsed())
+    });
     match send_ret {
         Ok(_) => {
             if !text.is_empty() && !text.ends_with('\n') {
                 println!();
             }
-            Ok((text, eval_tool_calls(client.global_config(), tool_calls)?))
+            Ok((
+                text,
+                eval_tool_calls(client.global_config(), tool_calls, abort_signal).await?,
+            ))
         }
         Err(err) => {
             if !text.is_empty() {
@@ -10,6 +10,7 @@ pub use crate::function::ToolCall;
 pub use common::*;
 pub use message::*;
 pub use model::*;
+pub use openai::openai_build_chat_completions_body;
 pub use stream::*;
 
 register_client!(
@@ -2,11 +2,14 @@ use super::*;
 
 use crate::{
     client::Model,
-    function::{run_llm_function, Functions},
+    function::{run_llm_function, sub_agent_declarations, Functions, ToolPermission},
 };
 
 use anyhow::{Context, Result};
-use inquire::{validator::Validation, Text};
+use inquire::{
+    required, validator::Validation, Confirm, Password, PasswordDisplayMode, Select, Text,
+};
+use path_absolutize::Absolutize;
 use std::{fs::read_to_string, path::Path};
 
 use serde::{Deserialize, Serialize};
@@ -49,11 +52,12 @@ impl Agent {
             AgentConfig::new(&config.read())
         };
         let mut definition = AgentDefinition::load(&definition_file_path)?;
-        let functions = if functions_file_path.exists() {
+        let mut functions = if functions_file_path.exists() {
             Functions::init(&functions_file_path)?
         } else {
             Functions::default()
         };
+        functions.extend(sub_agent_declarations(&definition.agents));
         definition.replace_tools_placeholder(&functions);
 
         agent_config.load_envs(&definition.name);
@@ -118,7 +122,9 @@ impl Agent {
         })
     }
 
+    /// Secrets are read from and written to the secret store instead of being kept with the other values
     pub fn init_agent_variables(
+        agent_name: &str,
         agent_variables: &[AgentVariable],
         variables: &AgentVariables,
         no_interaction: bool,
@@ -131,41 +137,46 @@ impl Agent {
         let mut unset_variables = vec![];
         for agent_variable in agent_variables {
             let key = agent_variable.name.clone();
-            match variables.get(&key) {
-                Some(value) => {
-                    output.insert(key, value.clone());
+            let secret_name = agent_secret_name(agent_name, &key);
+            if let Some(value) = variables.get(&key) {
+                let value = agent_variable
+                    .validate(value)
+                    .with_context(|| "Invalid agent variable")?;
+                if agent_variable.is_secret() {
+                    save_secret(&secret_name, &value)?;
                 }
-                None => {
-                    if let Some(value) = agent_variable.default.clone() {
-                        output.insert(key, value);
-                        continue;
-                    }
-                    if no_interaction {
-                        continue;
-                    }
-                    if *IS_STDOUT_TERMINAL {
-                        if !printed {
-                            println!("⚙ Init agent variables...");
-                            printed = true;
-                        }
-                        let value = Text::new(&format!(
-                            "{} ({}):",
-                            agent_variable.name, agent_variable.description
-                        ))
-                        .with_validator(|input: &str| {
-                            if input.trim().is_empty() {
-                                Ok(Validation::Invalid("This field is required".into()))
-                            } else {
-                                Ok(Validation::Valid)
-                            }
-                        })
-                        .prompt()?;
-                        output.insert(key, value);
-                    } else {
-                        unset_variables.push(agent_variable)
-                    }
+                output.insert(key, value);
+                continue;
+            }
+            if agent_variable.is_secret() {
+                if let Some(value) = load_secret(&secret_name)? {
+                    output.insert(key, value);
+                    continue;
                 }
             }
+            if let Some(value) = agent_variable.default.as_ref() {
+                let value = agent_variable
+                    .validate(value)
+                    .with_context(|| "Invalid agent variable default")?;
+                output.insert(key, value);
+                continue;
+            }
+            if no_interaction {
+                continue;
+            }
+            if *IS_STDOUT_TERMINAL {
+                if !printed {
+                    println!("⚙ Init agent variables...");
+                    printed = true;
+                }
+                let value = agent_variable.prompt()?;
+                if agent_variable.is_secret() {
+                    save_secret(&secret_name, &value)?;
+                }
+                output.insert(key, value);
+            } else {
+                unset_variables.push(agent_variable)
+            }
         }
         if !unset_variables.is_empty() {
             bail!(
@@ -183,7 +194,14 @@ impl Agent {
     pub fn export(&self) -> Result<String> {
         let mut value = json!({});
         value["name"] = json!(self.name());
-        let variables = self.variables();
+        let mut variables = self.variables().clone();
+        for variable in self.defined_variables() {
+            if variable.is_secret() {
+                if let Some(value) = variables.get_mut(&variable.name) {
+                    *value = "******".into();
+                }
+            }
+        }
         if !variables.is_empty() {
             value["variables"] = serde_json::to_value(variables)?;
         }
@@ -219,6 +237,14 @@ impl Agent {
         &self.functions
     }
 
+    pub fn tool_permissions(&self) -> &IndexMap<String, ToolPermission> {
+        &self.config.tool_permissions
+    }
+
+    pub fn tool_sandbox(&self) -> Option<bool> {
+        self.config.tool_sandbox
+    }
+
     pub fn rag(&self) -> Option<Arc<Rag>> {
         self.rag.clone()
     }
@@ -235,7 +261,10 @@ impl Agent {
             .or_else(|| self.config.instructions.clone())
             .unwrap_or_else(|| self.definition.instructions.clone());
         for (k, v) in self.variables() {
-            output = output.replace(&format!("{{{{{k}}}}}"), v)
+            // Instructions end up in the session and in requests, so secrets never go there
+            if !self.is_secret_variable(k) {
+                output = output.replace(&format!("{{{{{k}}}}}"), v)
+            }
         }
         interpolate_variables(&mut output);
         output
@@ -284,6 +313,22 @@ impl Agent {
         &self.definition.variables
     }
 
+    /// The variables that may be saved to a session file
+    pub fn persistent_variables(&self) -> AgentVariables {
+        self.variables()
+            .iter()
+            .filter(|(k, _)| !self.is_secret_variable(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn is_secret_variable(&self, name: &str) -> bool {
+        self.definition
+            .variables
+            .iter()
+            .any(|v| v.name == name && v.is_secret())
+    }
+
     pub fn exit_session(&mut self) {
         self.session_variables = None;
         self.session_dynamic_instructions = None;
@@ -382,6 +427,10 @@ pub struct AgentConfig {
     pub instructions: Option<String>,
     #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
     pub variables: AgentVariables,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub tool_permissions: IndexMap<String, ToolPermission>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_sandbox: Option<bool>,
 }
 
 impl AgentConfig {
@@ -427,6 +476,14 @@ impl AgentConfig {
                 self.variables = v;
             }
         }
+        if let Ok(v) = env::var(with_prefix("tool_permissions")) {
+            if let Ok(v) = serde_json::from_str(&v) {
+                self.tool_permissions = v;
+            }
+        }
+        if let Some(Some(v)) = read_env_bool(&with_prefix("tool_sandbox")) {
+            self.tool_sandbox = Some(v);
+        }
     }
 }
 
@@ -447,6 +504,12 @@ pub struct AgentDefinition {
     pub conversation_starters: Vec<String>,
     #[serde(default)]
     pub documents: Vec<String>,
+    /// Other agents this agent can delegate to, exposed as tools
+    #[serde(default)]
+    pub agents: Vec<String>,
+    /// Commands that must be on PATH for the tools to work
+    #[serde(default)]
+    pub dependencies: Vec<String>,
 }
 
 impl AgentDefinition {
@@ -512,12 +575,105 @@ impl AgentDefinition {
 pub struct AgentVariable {
     pub name: String,
     pub description: String,
+    #[serde(rename = "type", default)]
+    pub kind: AgentVariableKind,
+    /// The allowed values of an `enum` variable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default: Option<String>,
     #[serde(skip_deserializing, default)]
     pub value: String,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentVariableKind {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Enum,
+    Path,
+    Secret,
+}
+
+impl AgentVariable {
+    pub fn is_secret(&self) -> bool {
+        self.kind == AgentVariableKind::Secret
+    }
+
+    /// Check the value against the variable type and return it in normalized form
+    pub fn validate(&self, value: &str) -> Result<String> {
+        let name = &self.name;
+        let value = match self.kind {
+            AgentVariableKind::String => value.to_string(),
+            AgentVariableKind::Int => match value.trim().parse::<i64>() {
+                Ok(v) => v.to_string(),
+                Err(_) => bail!("'{name}' must be an integer, got '{value}'"),
+            },
+            AgentVariableKind::Bool => match value.trim().to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => "true".into(),
+                "false" | "no" | "off" | "0" => "false".into(),
+                _ => bail!("'{name}' must be true or false, got '{value}'"),
+            },
+            AgentVariableKind::Enum => {
+                if !self.options.iter().any(|v| v == value) {
+                    bail!(
+                        "'{name}' must be one of {}, got '{value}'",
+                        self.options.join(", ")
+                    );
+                }
+                value.to_string()
+            }
+            AgentVariableKind::Path => {
+                let path = Path::new(value);
+                if value.trim().is_empty() || !path.exists() {
+                    bail!("'{name}' must be an existing path, got '{value}'");
+                }
+                path.absolutize()
+                    .map(|v| v.display().to_string())
+                    .unwrap_or_else(|_| value.to_string())
+            }
+            AgentVariableKind::Secret => {
+                if value.is_empty() {
+                    bail!("'{name}' must not be empty");
+                }
+                value.to_string()
+            }
+        };
+        Ok(value)
+    }
+
+    fn prompt(&self) -> Result<String> {
+        let message = format!("{} ({}):", self.name, self.description);
+        let value = match self.kind {
+            AgentVariableKind::Bool => Confirm::new(&message).prompt()?.to_string(),
+            AgentVariableKind::Enum => Select::new(&message, self.options.clone()).prompt()?,
+            AgentVariableKind::Secret => Password::new(&message)
+                .without_confirmation()
+                .with_display_mode(PasswordDisplayMode::Masked)
+                .with_validator(required!("This field is required"))
+                .prompt()?,
+            _ => {
+                let variable = self.clone();
+                Text::new(&message)
+                    .with_validator(move |input: &str| {
+                        if input.trim().is_empty() {
+                            return Ok(Validation::Invalid("This field is required".into()));
+                        }
+                        match variable.validate(input) {
+                            Ok(_) => Ok(Validation::Valid),
+                            Err(err) => Ok(Validation::Invalid(err.to_string().into())),
+                        }
+                    })
+                    .prompt()?
+            }
+        };
+        self.validate(&value)
+    }
+}
+
 pub fn list_agents() -> Vec<String> {
     let agents_file = Config::functions_dir().join("agents.txt");
     let contents = match read_to_string(agents_file) {
@@ -549,11 +705,45 @@ pub fn complete_agent_variables(agent_name: &str) -> Vec<(String, Option<String>
         .variables
         .iter()
         .map(|v| {
-            let description = match &v.default {
-                Some(default) => format!("{} [default: {default}]", v.description),
-                None => v.description.clone(),
-            };
+            let mut description = v.description.clone();
+            match v.kind {
+                AgentVariableKind::String => {}
+                AgentVariableKind::Enum => {
+                    description.push_str(&format!(" [{}]", v.options.join("|")))
+                }
+                kind => description.push_str(&format!(" [{}]", format!("{kind:?}").to_lowercase())),
+            }
+            if let Some(default) = v.default.as_ref().filter(|_| !v.is_secret()) {
+                description.push_str(&format!(" [default: {default}]"));
+            }
             (format!("{}=", v.name), Some(description))
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_variable_validate() {
+        let variable = |kind, options: &[&str]| AgentVariable {
+            name: "foo".into(),
+            kind,
+            options: options.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
+        };
+        let int = variable(AgentVariableKind::Int, &[]);
+        assert_eq!(int.validate(" 42 ").unwrap(), "42");
+        assert!(int.validate("4.2").is_err());
+        let bool = variable(AgentVariableKind::Bool, &[]);
+        assert_eq!(bool.validate("Yes").unwrap(), "true");
+        assert_eq!(bool.validate("0").unwrap(), "false");
+        assert!(bool.validate("maybe").is_err());
+        let enum_ = variable(AgentVariableKind::Enum, &["a", "b"]);
+        assert_eq!(enum_.validate("b").unwrap(), "b");
+        assert!(enum_.validate("c").is_err());
+        let secret = variable(AgentVariableKind::Secret, &[]);
+        assert!(secret.validate("").is_err());
+    }
+}
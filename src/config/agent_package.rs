@@ -0,0 +1,505 @@
+use super::*;
+
+use crate::function::Functions;
+
+use anyhow::{Context, Result};
+use std::{
+    fs::{self, read_to_string},
+    path::Path,
+};
+
+const INSTALLED_AGENTS_FILE_NAME: &str = "installed.yaml";
+const ARCHIVE_EXTENSIONS: [&str; 6] = [".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.bz2", ".zip"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledAgent {
+    #[serde(default)]
+    pub version: String,
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    pub installed_at: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AgentSource {
+    Git(String),
+    Archive(String),
+    Dir(PathBuf),
+}
+
+impl AgentSource {
+    fn parse(source: &str) -> Self {
+        let lower = source.to_lowercase();
+        let is_archive = ARCHIVE_EXTENSIONS.iter().any(|v| lower.ends_with(v));
+        let path = Path::new(source);
+        if path.is_dir() {
+            Self::Dir(path.to_path_buf())
+        } else if is_archive {
+            Self::Archive(source.to_string())
+        } else {
+            Self::Git(source.to_string())
+        }
+    }
+}
+
+/// Install an agent from a git URL, a tarball or a local directory
+pub async fn install_agent(source: &str) -> Result<()> {
+    let (name, version) = install_agent_from(source, None).await?;
+    println!("✓ Installed agent '{name}' {version}");
+    Ok(())
+}
+
+/// Reinstall an agent from the source it was installed from
+pub async fn update_agent(name: &str) -> Result<()> {
+    let installed = load_installed_agents()?;
+    let Some(previous) = installed.get(name) else {
+        bail!("Agent '{name}' was not installed with --install-agent");
+    };
+    let (_, version) = install_agent_from(&previous.source, Some(name)).await?;
+    if previous.version == version {
+        println!("✓ Reinstalled agent '{name}' {version}");
+    } else {
+        println!("✓ Updated agent '{name}' {} → {version}", previous.version);
+    }
+    Ok(())
+}
+
+pub fn remove_agent(name: &str) -> Result<()> {
+    validate_agent_name(name)?;
+    let mut installed = load_installed_agents()?;
+    if !installed.contains_key(name) && !list_agents().iter().any(|v| v == name) {
+        bail!("Unknown agent `{name}`");
+    }
+    let functions_dir = Config::agent_functions_dir(name);
+    if functions_dir.exists() {
+        fs::remove_dir_all(&functions_dir)
+            .with_context(|| format!("Failed to remove agent at '{}'", functions_dir.display()))?;
+    }
+    update_agents_list(name, false)?;
+    if installed.shift_remove(name).is_some() {
+        save_installed_agents(&installed)?;
+    }
+    let data_dir = Config::agent_data_dir(name);
+    if data_dir.exists() {
+        let ans = *IS_STDOUT_TERMINAL
+            && Confirm::new(&format!(
+                "Also delete the agent data (config, sessions, RAG) at '{}'?",
+                data_dir.display()
+            ))
+            .with_default(false)
+            .prompt()?;
+        if ans {
+            fs::remove_dir_all(&data_dir)?;
+        }
+    }
+    println!("✓ Removed agent '{name}'");
+    Ok(())
+}
+
+async fn install_agent_from(source: &str, expected_name: Option<&str>) -> Result<(String, String)> {
+    let work_dir = temp_file("-agent-", "");
+    fs::create_dir_all(&work_dir)?;
+    let ret = install_agent_inner(source, expected_name, &work_dir).await;
+    let _ = fs::remove_dir_all(&work_dir);
+    ret
+}
+
+async fn install_agent_inner(
+    source: &str,
+    expected_name: Option<&str>,
+    work_dir: &Path,
+) -> Result<(String, String)> {
+    let (package_dir, revision) = match AgentSource::parse(source) {
+        AgentSource::Dir(path) => (path, None),
+        AgentSource::Archive(location) => {
+            let archive_path = if is_url(&location) {
+                let file_name = location.rsplit('/').next().unwrap_or("agent.tar.gz");
+                let path = work_dir.join(file_name);
+                download(&location, &path).await?;
+                path
+            } else {
+                PathBuf::from(&location)
+            };
+            let extract_dir = work_dir.join("package");
+            fs::create_dir_all(&extract_dir)?;
+            extract_archive(&archive_path, &extract_dir)?;
+            (extract_dir, None)
+        }
+        AgentSource::Git(url) => {
+            let clone_dir = work_dir.join("package");
+            let clone_path = clone_dir.display().to_string();
+            let (success, _, stderr) = run_command_with_output(
+                "git",
+                &[
+                    "clone",
+                    "--depth",
+                    "1",
+                    "--",
+                    url.as_str(),
+                    clone_path.as_str(),
+                ],
+                None,
+            )
+            .with_context(|| "Failed to run git, is it installed?")?;
+            if !success {
+                bail!("Failed to clone '{url}': {}", stderr.trim());
+            }
+            let revision = run_command_with_output(
+                "git",
+                &["-C", clone_path.as_str(), "rev-parse", "HEAD"],
+                None,
+            )
+            .ok()
+            .filter(|(success, _, _)| *success)
+            .map(|(_, stdout, _)| stdout.trim().to_string());
+            (clone_dir, revision)
+        }
+    };
+    let package_dir = find_package_root(&package_dir)?;
+    let definition = validate_agent_package(&package_dir)?;
+    let name = definition.name.clone();
+    if let Some(expected_name) = expected_name {
+        if expected_name != name {
+            bail!("The source now provides agent '{name}' instead of '{expected_name}'");
+        }
+    }
+
+    let functions_dir = Config::agent_functions_dir(&name);
+    if functions_dir.exists() && expected_name.is_none() {
+        bail!("Agent '{name}' already exists, use --update-agent to update it");
+    }
+    replace_dir(&package_dir, &functions_dir)
+        .with_context(|| format!("Failed to install agent at '{}'", functions_dir.display()))?;
+    update_agents_list(&name, true)?;
+
+    let mut installed = load_installed_agents()?;
+    installed.insert(
+        name.clone(),
+        InstalledAgent {
+            version: definition.version.clone(),
+            source: normalize_source(source),
+            revision,
+            installed_at: now(),
+        },
+    );
+    save_installed_agents(&installed)?;
+    Ok((name, definition.version))
+}
+
+/// Check the index, the tool declarations, the executables they need and the declared dependencies
+fn validate_agent_package(dir: &Path) -> Result<AgentDefinition> {
+    let definition = AgentDefinition::load(&dir.join("index.yaml"))?;
+    let name = &definition.name;
+    validate_agent_name(name)?;
+    if definition.instructions.trim().is_empty() && !definition.dynamic_instructions {
+        bail!("Agent '{name}' has no instructions");
+    }
+
+    let mut missing = vec![];
+    let functions = Functions::init(&dir.join("functions.json"))?;
+    let bin_dir = dir.join("bin");
+    for declaration in functions.declarations() {
+        let found = if declaration.agent {
+            has_executable(&bin_dir, name) || has_executable(&Config::functions_bin_dir(), name)
+        } else {
+            has_executable(&Config::functions_bin_dir(), &declaration.name)
+                || which::which(&declaration.name).is_ok()
+        };
+        if !found {
+            missing.push(format!("tool '{}'", declaration.name));
+        }
+    }
+    for dependency in &definition.dependencies {
+        if which::which(dependency).is_err() {
+            missing.push(format!("dependency '{dependency}'"));
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "Agent '{name}' cannot run here, missing {}",
+            missing.join(", ")
+        );
+    }
+    let unknown_agents: Vec<_> = definition
+        .agents
+        .iter()
+        .filter(|v| !Config::agent_functions_dir(v).join("index.yaml").exists())
+        .cloned()
+        .collect();
+    if !unknown_agents.is_empty() {
+        warn!(
+            "Agent '{name}' delegates to agents that are not installed: {}",
+            unknown_agents.join(", ")
+        );
+    }
+    Ok(definition)
+}
+
+/// Agent names become directory names, so they can't contain path separators or dots
+fn validate_agent_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid agent name '{name}', only letters, digits, '-' and '_' are allowed");
+    }
+    Ok(())
+}
+
+/// Archives often wrap the package in a single top-level directory
+fn find_package_root(dir: &Path) -> Result<PathBuf> {
+    if dir.join("index.yaml").exists() {
+        return Ok(dir.to_path_buf());
+    }
+    let entries: Vec<_> = fs::read_dir(dir)?
+        .flatten()
+        .filter(|v| v.file_type().is_ok_and(|v| v.is_dir()))
+        .map(|v| v.path())
+        .filter(|v| v.join("index.yaml").exists())
+        .collect();
+    match entries.as_slice() {
+        [path] => Ok(path.clone()),
+        [] => bail!("No agent index.yaml found in '{}'", dir.display()),
+        _ => bail!("Multiple agents found in '{}'", dir.display()),
+    }
+}
+
+fn has_executable(dir: &Path, name: &str) -> bool {
+    if dir.join(name).is_file() {
+        return true;
+    }
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|v| v.path().is_file() && v.path().file_stem() == Some(name.as_ref()))
+        })
+        .unwrap_or_default()
+}
+
+async fn download(url: &str, path: &Path) -> Result<()> {
+    let res = reqwest::get(url)
+        .await
+        .with_context(|| format!("Failed to download '{url}'"))?;
+    if !res.status().is_success() {
+        bail!("Failed to download '{url}': {}", res.status());
+    }
+    let bytes = res.bytes().await?;
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn extract_archive(archive_path: &Path, dir: &Path) -> Result<()> {
+    let archive = archive_path.display().to_string();
+    let dir = dir.display().to_string();
+    let (cmd, args) = if archive.to_lowercase().ends_with(".zip") {
+        ("unzip", vec!["-q", archive.as_str(), "-d", dir.as_str()])
+    } else {
+        ("tar", vec!["-xf", archive.as_str(), "-C", dir.as_str()])
+    };
+    let (success, _, stderr) = run_command_with_output(cmd, &args, None)
+        .with_context(|| format!("Failed to run {cmd}, is it installed?"))?;
+    if !success {
+        bail!("Failed to extract '{archive}': {}", stderr.trim());
+    }
+    Ok(())
+}
+
+/// Copy `from` next to `to` first and then swap it in, so a failed copy leaves `to` untouched
+fn replace_dir(from: &Path, to: &Path) -> Result<()> {
+    let sibling = |label: &str| {
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        to.with_file_name(format!(".{name}.{label}-{}", uuid::Uuid::new_v4()))
+    };
+    let staging_dir = sibling("new");
+    if let Err(err) = copy_dir(from, &staging_dir) {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(err);
+    }
+    let backup_dir = sibling("old");
+    let has_old = to.exists();
+    if has_old {
+        if let Err(err) = fs::rename(to, &backup_dir) {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(err.into());
+        }
+    }
+    if let Err(err) = fs::rename(&staging_dir, to) {
+        if has_old {
+            let _ = fs::rename(&backup_dir, to);
+        }
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(err.into());
+    }
+    if has_old {
+        let _ = fs::remove_dir_all(&backup_dir);
+    }
+    Ok(())
+}
+
+/// Copy a package without following its symlinks, which are recreated as they are
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)?.flatten() {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        if file_name == ".git" {
+            continue;
+        }
+        let target = to.join(&file_name);
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            copy_symlink(&path, &target)?;
+        } else if file_type.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> Result<()> {
+    bail!(
+        "Symlinks are not supported in agent packages, found '{}'",
+        from.display()
+    )
+}
+
+fn normalize_source(source: &str) -> String {
+    match AgentSource::parse(source) {
+        AgentSource::Dir(path) => fs::canonicalize(&path)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        AgentSource::Archive(v) if !is_url(&v) => fs::canonicalize(&v)
+            .map(|v| v.display().to_string())
+            .unwrap_or(v),
+        AgentSource::Archive(v) | AgentSource::Git(v) => v,
+    }
+}
+
+fn update_agents_list(name: &str, add: bool) -> Result<()> {
+    let agents_file = Config::functions_dir().join("agents.txt");
+    let mut agents = list_agents();
+    let exists = agents.iter().any(|v| v == name);
+    match (add, exists) {
+        (true, false) => agents.push(name.to_string()),
+        (false, true) => agents.retain(|v| v != name),
+        _ => return Ok(()),
+    }
+    ensure_parent_exists(&agents_file)?;
+    let mut contents = agents.join("\n");
+    contents.push('\n');
+    fs::write(&agents_file, contents)
+        .with_context(|| format!("Failed to write to '{}'", agents_file.display()))
+}
+
+pub fn load_installed_agents() -> Result<IndexMap<String, InstalledAgent>> {
+    let path = Config::agents_functions_dir().join(INSTALLED_AGENTS_FILE_NAME);
+    if !path.exists() {
+        return Ok(IndexMap::new());
+    }
+    let contents =
+        read_to_string(&path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    serde_yaml::from_str(&contents).with_context(|| format!("Failed to load '{}'", path.display()))
+}
+
+fn save_installed_agents(installed: &IndexMap<String, InstalledAgent>) -> Result<()> {
+    let path = Config::agents_functions_dir().join(INSTALLED_AGENTS_FILE_NAME);
+    ensure_parent_exists(&path)?;
+    let contents = serde_yaml::to_string(installed)?;
+    fs::write(&path, contents).with_context(|| format!("Failed to write to '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_agent_source() {
+        assert_eq!(
+            AgentSource::parse("https://github.com/acme/agents.git"),
+            AgentSource::Git("https://github.com/acme/agents.git".into())
+        );
+        assert_eq!(
+            AgentSource::parse("git@github.com:acme/agents.git"),
+            AgentSource::Git("git@github.com:acme/agents.git".into())
+        );
+        assert_eq!(
+            AgentSource::parse("https://example.com/todo-1.0.0.tar.gz"),
+            AgentSource::Archive("https://example.com/todo-1.0.0.tar.gz".into())
+        );
+        assert_eq!(
+            AgentSource::parse("./todo.TGZ"),
+            AgentSource::Archive("./todo.TGZ".into())
+        );
+    }
+
+    #[test]
+    fn test_replace_dir() {
+        let root = temp_file("-replace-", "");
+        let (from, to) = (root.join("from"), root.join("agents").join("todo"));
+        fs::create_dir_all(from.join("bin")).unwrap();
+        fs::write(from.join("index.yaml"), "name: todo").unwrap();
+        fs::write(from.join("bin/todo"), "new").unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("stale.txt"), "old").unwrap();
+
+        replace_dir(&from, &to).unwrap();
+        assert_eq!(fs::read_to_string(to.join("bin/todo")).unwrap(), "new");
+        assert!(!to.join("stale.txt").exists());
+        let names: Vec<_> = fs::read_dir(root.join("agents"))
+            .unwrap()
+            .flatten()
+            .map(|v| v.file_name())
+            .collect();
+        assert_eq!(names, ["todo"]);
+
+        assert!(replace_dir(&root.join("missing"), &to).is_err());
+        assert_eq!(
+            fs::read_to_string(to.join("index.yaml")).unwrap(),
+            "name: todo"
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_keeps_symlinks() {
+        let root = temp_file("-copy-", "");
+        let (from, to) = (root.join("from"), root.join("to"));
+        fs::create_dir_all(from.join("bin")).unwrap();
+        fs::write(from.join("index.yaml"), "name: todo").unwrap();
+        std::os::unix::fs::symlink("..", from.join("bin/loop")).unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", from.join("secret")).unwrap();
+
+        copy_dir(&from, &to).unwrap();
+        assert_eq!(fs::read_link(to.join("bin/loop")).unwrap(), Path::new(".."));
+        assert_eq!(
+            fs::read_link(to.join("secret")).unwrap(),
+            Path::new("/etc/hostname")
+        );
+        assert!(to.join("index.yaml").is_file());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_validate_agent_name() {
+        assert!(validate_agent_name("todo-v2_x").is_ok());
+        for name in ["", "..", ".", "../x", "a/b", "a b"] {
+            assert!(validate_agent_name(name).is_err(), "{name}");
+        }
+        assert!(remove_agent("..").is_err());
+        assert!(remove_agent("").is_err());
+    }
+}
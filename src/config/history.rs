@@ -0,0 +1,290 @@
+use super::*;
+
+use anyhow::{Context, Result};
+use bm25::{Language, SearchEngineBuilder};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+const SEARCH_HISTORY_LIMIT: usize = 20;
+const SNIPPET_BEFORE: usize = 40;
+const SNIPPET_AFTER: usize = 80;
+
+#[derive(Debug, Clone)]
+pub struct HistoryMatch {
+    /// `None` for chats from the messages file
+    pub session: Option<String>,
+    /// The agent owning the session
+    pub agent: Option<String>,
+    /// `None` when the session has a single branch
+    pub branch: Option<String>,
+    pub date: String,
+    pub turn: usize,
+    pub text: String,
+    pub snippet: String,
+}
+
+impl HistoryMatch {
+    pub fn title(&self) -> String {
+        match &self.session {
+            Some(name) => {
+                let mut title = match &self.agent {
+                    Some(agent) => format!("{agent}:{name}"),
+                    None => name.clone(),
+                };
+                if let Some(branch) = &self.branch {
+                    title.push_str(&format!("@{branch}"));
+                }
+                format!("{title} #{} ({})", self.turn, self.date)
+            }
+            None => format!("{MESSAGES_FILE_NAME} ({})", self.date),
+        }
+    }
+}
+
+/// Search the turns of every saved session and the chats of the messages file
+///
+/// `sessions_dirs` pairs each sessions dir with the agent owning it.
+pub fn search_history(
+    sessions_dirs: &[(Option<String>, PathBuf)],
+    messages_file: &Path,
+    query: &str,
+    bm25: bool,
+) -> Result<Vec<HistoryMatch>> {
+    let terms: Vec<String> = query.split_whitespace().map(|v| v.to_lowercase()).collect();
+    if terms.is_empty() {
+        bail!("Empty query");
+    }
+    let mut documents = vec![];
+    for (agent, sessions_dir) in sessions_dirs {
+        documents.extend(load_session_documents(agent.as_deref(), sessions_dir));
+    }
+    documents.extend(load_messages_documents(messages_file)?);
+    let matches: Vec<HistoryMatch> = if bm25 {
+        let engine = SearchEngineBuilder::<usize>::with_documents(
+            Language::English,
+            documents
+                .iter()
+                .enumerate()
+                .map(|(i, v)| bm25::Document::new(i, &v.text)),
+        )
+        .build();
+        engine
+            .search(query, SEARCH_HISTORY_LIMIT)
+            .into_iter()
+            .map(|v| documents[v.document.id].clone())
+            .collect()
+    } else {
+        let mut matches: Vec<_> = documents
+            .into_iter()
+            .filter(|v| {
+                let text = v.text.to_lowercase();
+                terms.iter().all(|term| text.contains(term.as_str()))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.date.cmp(&a.date).then(a.turn.cmp(&b.turn)));
+        matches.truncate(SEARCH_HISTORY_LIMIT);
+        matches
+    };
+    Ok(matches
+        .into_iter()
+        .map(|mut v| {
+            v.snippet = make_snippet(&v.text, &terms);
+            v
+        })
+        .collect())
+}
+
+fn load_session_documents(agent: Option<&str>, sessions_dir: &Path) -> Vec<HistoryMatch> {
+    let mut names = list_file_names(sessions_dir, ".yaml");
+    names.extend(
+        list_file_names(sessions_dir.join("_"), ".yaml")
+            .into_iter()
+            .map(|v| format!("_/{v}")),
+    );
+    let mut documents = vec![];
+    for name in names {
+        let path = sessions_dir.join(format!("{name}.yaml"));
+        let session = read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|v| Ok(serde_yaml::from_str::<Session>(&v)?));
+        let session = match session {
+            Ok(v) => v,
+            Err(err) => {
+                debug!("Skip session '{}': {err}", path.display());
+                continue;
+            }
+        };
+        let date = std::fs::metadata(&path)
+            .and_then(|v| v.modified())
+            .map(|v| {
+                chrono::DateTime::<chrono::Local>::from(v)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        // Forked branches repeat the turns shared with their parent
+        let mut seen = HashSet::new();
+        let forked = session.list_branches().len() > 1;
+        for (branch, turns) in session.list_branch_turns() {
+            for (index, (question, answer)) in turns.into_iter().enumerate() {
+                let text = format!("{question}\n\n{}", answer.unwrap_or_default());
+                if !seen.insert((index, text.clone())) {
+                    continue;
+                }
+                documents.push(HistoryMatch {
+                    session: Some(name.clone()),
+                    agent: agent.map(|v| v.to_string()),
+                    branch: forked.then(|| branch.clone()),
+                    date: date.clone(),
+                    turn: index + 1,
+                    text,
+                    snippet: String::new(),
+                });
+            }
+        }
+    }
+    documents
+}
+
+/// Split the messages file into its `# CHAT: <summary> [<time>]` entries
+fn load_messages_documents(path: &Path) -> Result<Vec<HistoryMatch>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let contents = read_to_string(path)
+        .with_context(|| format!("Failed to read messages at '{}'", path.display()))?;
+    let mut documents: Vec<HistoryMatch> = vec![];
+    for line in contents.lines() {
+        if let Some(header) = line.strip_prefix("# CHAT: ") {
+            let date = header
+                .rsplit_once('[')
+                .and_then(|(_, v)| v.split_once(']'))
+                .map(|(v, _)| v.replacen('T', " ", 1).chars().take(16).collect())
+                .unwrap_or_default();
+            documents.push(HistoryMatch {
+                session: None,
+                agent: None,
+                branch: None,
+                date,
+                turn: 1,
+                text: String::new(),
+                snippet: String::new(),
+            });
+        } else if let Some(document) = documents.last_mut() {
+            if line != "--------" {
+                document.text.push_str(line);
+                document.text.push('\n');
+            }
+        }
+    }
+    for document in documents.iter_mut() {
+        document.text = document.text.trim().to_string();
+    }
+    Ok(documents)
+}
+
+/// A single line around the first matched term
+fn make_snippet(text: &str, terms: &[String]) -> String {
+    let chars: Vec<char> = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .collect();
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let position = terms
+        .iter()
+        .filter_map(|term| {
+            let term: Vec<char> = term.chars().collect();
+            lower.windows(term.len()).position(|v| v == term.as_slice())
+        })
+        .min()
+        .unwrap_or_default();
+    let start = position.saturating_sub(SNIPPET_BEFORE);
+    let end = (position + SNIPPET_AFTER).min(chars.len());
+    let mut snippet: String = chars[start..end].iter().collect();
+    if start > 0 {
+        snippet = format!("…{snippet}");
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_history() {
+        let dir = temp_file("-history-", "");
+        let sessions_dir = dir.join("sessions");
+        let agent_sessions_dir = dir.join("agents").join("coder").join("sessions");
+        std::fs::create_dir_all(&sessions_dir).unwrap();
+        std::fs::create_dir_all(&agent_sessions_dir).unwrap();
+        std::fs::write(
+            sessions_dir.join("work.yaml"),
+            r#"model: stub:chat
+messages:
+- role: user
+  content: explain the sql join
+- role: assistant
+  content: it combines rows
+branch: main
+branches:
+  main: {}
+  retry:
+    parent: main
+    fork_at: 1
+    messages:
+    - role: user
+      content: explain the sql join
+    - role: assistant
+      content: it combines rows
+    - role: user
+      content: now the sql index
+    - role: assistant
+      content: it speeds up lookups
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            agent_sessions_dir.join("review.yaml"),
+            r#"model: stub:chat
+messages:
+- role: user
+  content: review the sql migration
+- role: assistant
+  content: looks fine
+"#,
+        )
+        .unwrap();
+        let sessions_dirs = [
+            (None, sessions_dir),
+            (Some("coder".to_string()), agent_sessions_dir),
+        ];
+        let mut titles: Vec<String> =
+            search_history(&sessions_dirs, &dir.join("messages.md"), "sql", false)
+                .unwrap()
+                .iter()
+                .map(|v| v.title().split(" (").next().unwrap().to_string())
+                .collect();
+        titles.sort();
+        assert_eq!(titles, ["coder:review #1", "work@main #1", "work@retry #2"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_make_snippet() {
+        let text = format!("{}\nfix the SQL join\n{}", "a ".repeat(40), "b ".repeat(60));
+        let snippet = make_snippet(&text, &["sql".into()]);
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("fix the SQL join"));
+        assert_eq!(make_snippet("short text", &["none".into()]), "short text");
+    }
+}
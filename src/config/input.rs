@@ -5,15 +5,18 @@ use crate::client::{
     MessageContentPart, MessageContentToolCalls, MessageRole, Model,
 };
 use crate::function::ToolResult;
-use crate::utils::{base64_encode, is_loader_protocol, sha256, AbortSignal};
+use crate::mcp::{is_mcp_path, McpClients};
+use crate::utils::{
+    is_image, is_loader_protocol, load_image, read_media_to_data_url, sha256, AbortSignal,
+};
 
 use anyhow::{bail, Context, Result};
 use indexmap::IndexSet;
-use std::{collections::HashMap, fs::File, io::Read};
+use std::collections::HashMap;
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
 const SUMMARY_MAX_WIDTH: usize = 80;
+const MEMORY_PROMPT: &str = "Facts remembered from previous conversations with the user:";
 
 #[derive(Debug, Clone)]
 pub struct Input {
@@ -29,6 +32,9 @@ pub struct Input {
     tool_calls: Option<MessageContentToolCalls>,
     role: Role,
     rag_name: Option<String>,
+    mentioned_rag: Option<String>,
+    memories: Vec<String>,
+    skip_memories: bool,
     with_session: bool,
     with_agent: bool,
 }
@@ -49,6 +55,9 @@ impl Input {
             tool_calls: None,
             role,
             rag_name: None,
+            mentioned_rag: None,
+            memories: vec![],
+            skip_memories: false,
             with_session,
             with_agent,
         }
@@ -61,15 +70,30 @@ impl Input {
         role: Option<Role>,
     ) -> Result<Self> {
         let loaders = config.read().document_loaders.clone();
-        let (raw_paths, local_paths, remote_urls, external_cmds, protocol_paths, with_last_reply) =
-            resolve_paths(&loaders, paths)?;
+        let (mention_paths, mentioned_rag) = parse_mentions(raw_text);
+        let raw_paths = resolve_paths(&loaders, paths.clone())?.0;
+        let (
+            _,
+            local_paths,
+            remote_urls,
+            external_cmds,
+            protocol_paths,
+            mcp_paths,
+            with_last_reply,
+        ) = resolve_paths(&loaders, [paths, mention_paths].concat())?;
         let mut last_reply = None;
+        if !mcp_paths.is_empty() {
+            Config::load_mcp(config).await;
+        }
+        let mcp = config.read().mcp.clone();
         let (documents, medias, data_urls) = load_documents(
             &loaders,
+            &mcp,
             local_paths,
             remote_urls,
             external_cmds,
             protocol_paths,
+            mcp_paths,
         )
         .await
         .context("Failed to load files")?;
@@ -116,11 +140,31 @@ impl Input {
             tool_calls: Default::default(),
             role,
             rag_name: None,
+            mentioned_rag,
+            memories: vec![],
+            skip_memories: false,
             with_session,
             with_agent,
         })
     }
 
+    /// Like `from_str`, but loads the `@path`, `@url` and `@rag:name` mentions in the text
+    pub async fn from_text(
+        config: &GlobalConfig,
+        text: &str,
+        role: Option<Role>,
+        abort_signal: AbortSignal,
+    ) -> Result<Self> {
+        let (paths, mentioned_rag) = parse_mentions(text);
+        if paths.is_empty() {
+            let mut input = Self::from_str(config, text, role);
+            input.mentioned_rag = mentioned_rag;
+            Ok(input)
+        } else {
+            Self::from_files_with_spinner(config, text, vec![], role, abort_signal).await
+        }
+    }
+
     pub async fn from_files_with_spinner(
         config: &GlobalConfig,
         raw_text: &str,
@@ -192,19 +236,57 @@ impl Input {
         self.tool_calls = None;
     }
 
+    /// Leave memories out of inputs that don't come from the user, like replays and sub-agents
+    pub fn set_skip_memories(&mut self) {
+        self.skip_memories = true;
+    }
+
     pub async fn use_embeddings(&mut self, abort_signal: AbortSignal) -> Result<()> {
+        if self.config.read().need_mcp(&self.role) {
+            Config::load_mcp(&self.config).await;
+        }
         if self.text.is_empty() {
             return Ok(());
         }
-        let rag = self.config.read().rag.clone();
+        if !self.skip_memories && !self.role.is_internal() {
+            match search_memories(&self.config, &self.text).await {
+                Ok(memories) => self.memories = memories,
+                Err(err) => warn!("Failed to search memories: {err}"),
+            }
+        }
+        let rag = match &self.mentioned_rag {
+            Some(name) => {
+                let rag_path = self.config.read().rag_file(name);
+                if !rag_path.exists() {
+                    bail!("Unknown RAG '{name}'")
+                }
+                Some(Arc::new(Rag::load(&self.config, name, &rag_path)?))
+            }
+            None => self.config.read().rag.clone(),
+        };
         if let Some(rag) = rag {
-            let result = Config::search_rag(&self.config, &rag, &self.text, abort_signal).await?;
+            let (result, images) =
+                Config::search_rag(&self.config, &rag, &self.text, abort_signal).await?;
             self.patched_text = Some(result);
             self.rag_name = Some(rag.name().to_string());
+            if self.role.model().data().supports_vision {
+                let loaders = self.config.read().document_loaders.clone();
+                for image in images {
+                    match load_image(&loaders, &image).await {
+                        Ok(data_url) => self.add_media(data_url, image),
+                        Err(err) => warn!("Failed to attach retrieved image '{image}': {err}"),
+                    }
+                }
+            }
         }
         Ok(())
     }
 
+    pub fn add_media(&mut self, data_url: String, path: String) {
+        self.data_urls.insert(sha256(&data_url), path);
+        self.medias.push(data_url);
+    }
+
     pub fn rag_name(&self) -> Option<&str> {
         self.rag_name.as_deref()
     }
@@ -255,6 +337,19 @@ impl Input {
         } else {
             self.role().build_messages(self)
         };
+        if !self.memories.is_empty() {
+            let memories: Vec<String> = self.memories.iter().map(|v| format!("- {v}")).collect();
+            let memories = format!("{MEMORY_PROMPT}\n{}", memories.join("\n"));
+            match messages.first_mut() {
+                Some(message) if message.role.is_system() => message
+                    .content
+                    .merge_prompt(|v| format!("{v}\n\n{memories}")),
+                _ => messages.insert(
+                    0,
+                    Message::new(MessageRole::System, MessageContent::Text(memories)),
+                ),
+            }
+        }
         if let Some(tool_calls) = &self.tool_calls {
             messages.push(Message::new(
                 MessageRole::Assistant,
@@ -385,12 +480,38 @@ fn resolve_role(config: &Config, role: Option<Role>) -> (Role, bool, bool) {
     }
 }
 
+/// Collects the `@path`, `@url` and `@rag:name` mentions in the text.
+/// Local paths only count when they exist or are globs, so `@someone` stays plain text.
+pub fn parse_mentions(text: &str) -> (Vec<String>, Option<String>) {
+    let mut paths = IndexSet::new();
+    let mut rag_name = None;
+    for word in text.split_whitespace() {
+        let Some(value) = word.strip_prefix('@') else {
+            continue;
+        };
+        let trimmed = value.trim_end_matches([',', '.', ';', ':', '!', '?', ')', '"', '\'']);
+        if let Some(name) = trimmed.strip_prefix("rag:") {
+            if !name.is_empty() {
+                rag_name = Some(name.to_string());
+            }
+        } else if is_url(trimmed) {
+            paths.insert(trimmed.to_string());
+        } else if let Some(path) = [value, trimmed].into_iter().find(|v| {
+            !v.is_empty() && (v.contains('*') || Path::new(&resolve_home_dir(v)).exists())
+        }) {
+            paths.insert(path.to_string());
+        }
+    }
+    (paths.into_iter().collect(), rag_name)
+}
+
 type ResolvePathsOutput = (
     Vec<String>,
     Vec<String>,
     Vec<String>,
     Vec<String>,
     Vec<String>,
+    Vec<String>,
     bool,
 );
 
@@ -403,6 +524,7 @@ fn resolve_paths(
     let mut remote_urls = IndexSet::new();
     let mut external_cmds = IndexSet::new();
     let mut protocol_paths = IndexSet::new();
+    let mut mcp_paths = IndexSet::new();
     let mut with_last_reply = false;
     for path in paths {
         if path == "%%" {
@@ -417,6 +539,9 @@ fn resolve_paths(
             }
             remote_urls.insert(path.clone());
             raw_paths.insert(path);
+        } else if is_mcp_path(&path) {
+            mcp_paths.insert(path.clone());
+            raw_paths.insert(path);
         } else if is_loader_protocol(loaders, &path) {
             protocol_paths.insert(path.clone());
             raw_paths.insert(path);
@@ -434,16 +559,19 @@ fn resolve_paths(
         remote_urls.into_iter().collect(),
         external_cmds.into_iter().collect(),
         protocol_paths.into_iter().collect(),
+        mcp_paths.into_iter().collect(),
         with_last_reply,
     ))
 }
 
 async fn load_documents(
     loaders: &HashMap<String, String>,
+    mcp: &McpClients,
     local_paths: Vec<String>,
     remote_urls: Vec<String>,
     external_cmds: Vec<String>,
     protocol_paths: Vec<String>,
+    mcp_paths: Vec<String>,
 ) -> Result<(
     Vec<(&'static str, String, String)>,
     Vec<String>,
@@ -499,6 +627,17 @@ async fn load_documents(
         );
     }
 
+    for mcp_path in mcp_paths {
+        let (texts, images) = mcp.read_resource(&mcp_path).await?;
+        if !texts.is_empty() {
+            files.push(("FROM", mcp_path.clone(), texts.join("\n")));
+        }
+        for data_url in images {
+            data_urls.insert(sha256(&data_url), mcp_path.clone());
+            medias.push(data_url);
+        }
+    }
+
     Ok((files, medias, data_urls))
 }
 
@@ -514,27 +653,19 @@ pub fn resolve_data_url(data_urls: &HashMap<String, String>, data_url: String) -
     }
 }
 
-fn is_image(path: &str) -> bool {
-    get_patch_extension(path)
-        .map(|v| IMAGE_EXTS.contains(&v.as_str()))
-        .unwrap_or_default()
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn read_media_to_data_url(image_path: &str) -> Result<String> {
-    let extension = get_patch_extension(image_path).unwrap_or_default();
-    let mime_type = match extension.as_str() {
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "webp" => "image/webp",
-        "gif" => "image/gif",
-        _ => bail!("Unexpected media type"),
-    };
-    let mut file = File::open(image_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-
-    let encoded_image = base64_encode(buffer);
-    let data_url = format!("data:{mime_type};base64,{encoded_image}");
-
-    Ok(data_url)
+    #[test]
+    fn test_parse_mentions() {
+        let (paths, rag_name) = parse_mentions(
+            "review @Cargo.toml, read @https://example.com/a.md and ask @rag:docs @someone",
+        );
+        assert_eq!(paths, vec!["Cargo.toml", "https://example.com/a.md"]);
+        assert_eq!(rag_name.as_deref(), Some("docs"));
+        let (paths, rag_name) = parse_mentions("mail me at me@example.com");
+        assert!(paths.is_empty());
+        assert!(rag_name.is_none());
+    }
 }
@@ -0,0 +1,212 @@
+use super::*;
+
+use crate::rag::{Rag, RagData, RagDocument};
+
+use anyhow::{bail, Context, Result};
+
+const MEMORY_NAME: &str = "memory";
+const MEMORY_HISTORY_LIMIT: usize = 20;
+const CREATED_AT_METADATA: &str = "created_at";
+pub const REMEMBER_SOURCE: &str = "remember";
+
+/// Store the facts with their embeddings, returning how many of them were new
+pub async fn remember(config: &GlobalConfig, texts: Vec<String>, source: &str) -> Result<usize> {
+    let mut rag = init_memory(config)?;
+    let created_at = now();
+    let documents = texts
+        .into_iter()
+        .map(|text| {
+            let mut document = RagDocument::new(text.trim());
+            document
+                .metadata
+                .insert(CREATED_AT_METADATA.into(), created_at.clone());
+            document
+        })
+        .collect();
+    let ids = rag.add_documents(source, documents).await?;
+    rag.save()?;
+    config.write().memory_rag = None;
+    Ok(ids.len())
+}
+
+/// The id, text and origin of every memory
+pub fn list_memories() -> Result<Vec<(String, String, String)>> {
+    let path = Config::memory_file();
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let err = || format!("Failed to load memories at '{}'", path.display());
+    let data: RagData =
+        serde_yaml::from_str(&read_to_string(&path).with_context(err)?).with_context(err)?;
+    let output = data
+        .list_documents()
+        .into_iter()
+        .map(|(id, source, document)| {
+            let created_at = document
+                .metadata
+                .get(CREATED_AT_METADATA)
+                .map(|v| v.chars().take(10).collect::<String>())
+                .unwrap_or_default();
+            (
+                id.to_string(),
+                document.page_content.clone(),
+                format!("{source}, {created_at}"),
+            )
+        })
+        .collect();
+    Ok(output)
+}
+
+/// Remove memories by id, or every memory with `all`
+pub fn forget_memories(config: &GlobalConfig, ids: &[&str]) -> Result<usize> {
+    let Some(mut rag) = load_memory(config)? else {
+        bail!("No memories");
+    };
+    let ids: Vec<usize> = if ids == ["all"] {
+        list_memories()?
+            .into_iter()
+            .filter_map(|(id, ..)| id.parse().ok())
+            .collect()
+    } else {
+        ids.iter()
+            .map(|v| v.parse().with_context(|| format!("Invalid id '{v}'")))
+            .collect::<Result<_>>()?
+    };
+    rag.remove_files(&ids)?;
+    rag.save()?;
+    config.write().memory_rag = None;
+    Ok(ids.len())
+}
+
+/// The memories relevant to the text, empty when memory is disabled or nothing is stored
+pub async fn search_memories(config: &GlobalConfig, text: &str) -> Result<Vec<String>> {
+    let (top_k, min_score) = {
+        let config = config.read();
+        if !config.memory {
+            return Ok(vec![]);
+        }
+        (config.memory_top_k, config.memory_min_score)
+    };
+    let cached = config.read().memory_rag.clone();
+    let rag = match cached {
+        Some(rag) => rag,
+        None => {
+            let Some(rag) = load_memory(config)? else {
+                return Ok(vec![]);
+            };
+            let rag = Arc::new(rag);
+            config.write().memory_rag = Some(rag.clone());
+            rag
+        }
+    };
+    rag.search_similar(text, top_k, min_score).await
+}
+
+/// Ask the model for durable facts in the current session, returning how many were stored
+pub async fn extract_memories(config: &GlobalConfig) -> Result<usize> {
+    let (name, history) = {
+        let config = config.read();
+        if !config.memory_extract {
+            return Ok(0);
+        }
+        match config.session.as_ref() {
+            Some(session) if session.dirty() => match session.chat_history(MEMORY_HISTORY_LIMIT) {
+                Some(history) => (session.name().to_string(), history),
+                None => return Ok(0),
+            },
+            _ => return Ok(0),
+        }
+    };
+    let known: Vec<String> = list_memories()?
+        .into_iter()
+        .map(|(_, text, _)| format!("- {text}"))
+        .collect();
+    let mut text = format!("<chat_history>\n{history}</chat_history>");
+    if !known.is_empty() {
+        text = format!("<memories>\n{}\n</memories>\n\n{text}", known.join("\n"));
+    }
+    let role = config.read().retrieve_role(EXTRACT_MEMORY_ROLE)?;
+    let output = Input::from_str(config, &text, Some(role))
+        .fetch_chat_text()
+        .await?;
+    let facts = parse_memories(&output);
+    if facts.is_empty() {
+        return Ok(0);
+    }
+    remember(config, facts, &format!("session:{name}")).await
+}
+
+fn load_memory(config: &GlobalConfig) -> Result<Option<Rag>> {
+    let path = Config::memory_file();
+    if !path.exists() {
+        return Ok(None);
+    }
+    Rag::load(config, MEMORY_NAME, &path).map(Some)
+}
+
+fn init_memory(config: &GlobalConfig) -> Result<Rag> {
+    if let Some(rag) = load_memory(config)? {
+        return Ok(rag);
+    }
+    let data = {
+        let config = config.read();
+        let model_id = match &config.rag_embedding_model {
+            Some(v) => v.clone(),
+            None => match list_models(&config, ModelType::Embedding).first() {
+                Some(v) => v.id(),
+                None => bail!("No available embedding model for memory"),
+            },
+        };
+        let model = Model::retrieve_model(&config, &model_id, ModelType::Embedding)?;
+        let chunk_size = config
+            .rag_chunk_size
+            .unwrap_or_else(|| model.default_chunk_size());
+        RagData::new(
+            model.id(),
+            chunk_size,
+            chunk_size / 20,
+            None,
+            config.memory_top_k,
+            model.max_batch_size(),
+        )
+    };
+    Rag::create(config, MEMORY_NAME, &Config::memory_file(), data)
+}
+
+/// One fact per line, list markers stripped; `NONE` means nothing worth keeping
+fn parse_memories(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| {
+            let line = line.trim();
+            let line = line
+                .strip_prefix("- ")
+                .or_else(|| line.strip_prefix("* "))
+                .unwrap_or_else(|| {
+                    line.trim_start_matches(|c: char| c.is_ascii_digit())
+                        .strip_prefix(". ")
+                        .unwrap_or(line)
+                });
+            line.trim().to_string()
+        })
+        .filter(|v| !v.is_empty() && v != "NONE")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memories() {
+        let text = "- The project uses anyhow for errors\n\n* Tests live next to the code\n2. Prefer tokio\nNONE\n";
+        assert_eq!(
+            parse_memories(text),
+            [
+                "The project uses anyhow for errors",
+                "Tests live next to the code",
+                "Prefer tokio"
+            ]
+        );
+        assert!(parse_memories("NONE").is_empty());
+    }
+}
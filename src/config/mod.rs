@@ -1,21 +1,47 @@
 mod agent;
+mod agent_package;
+mod history;
 mod input;
+mod memory;
 mod role;
+mod secret;
 mod session;
+mod session_export;
+mod template;
+mod trace;
 
-pub use self::agent::{complete_agent_variables, list_agents, Agent, AgentVariables};
-pub use self::input::Input;
+pub use self::agent::{
+    complete_agent_variables, list_agents, Agent, AgentDefinition, AgentVariables,
+};
+pub use self::agent_package::{install_agent, remove_agent, update_agent};
+use self::history::{search_history, HistoryMatch};
+pub use self::input::{parse_mentions, Input};
+pub use self::memory::{
+    extract_memories, forget_memories, list_memories, remember, search_memories, REMEMBER_SOURCE,
+};
 pub use self::role::{
-    Role, RoleLike, CODE_ROLE, CREATE_TITLE_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE,
+    Role, RoleLike, CAPTION_IMAGE_ROLE, CODE_ROLE, CREATE_TITLE_ROLE, EXPLAIN_SHELL_ROLE,
+    EXTRACT_MEMORY_ROLE, HYDE_ROLE, INPUT_PLACEHOLDER, MULTI_QUERY_ROLE, REWRITE_QUERY_ROLE,
+    SHELL_PLAN_ROLE, SHELL_ROLE,
 };
-use self::session::Session;
+use self::secret::{agent_secret_name, load_secret, save_secret};
+use self::session::CompressStrategy;
+pub use self::session::Session;
+pub use self::session_export::{export_session, import_sessions, ExportFormat};
+pub use self::template::template_input;
+use self::template::Template;
+use self::trace::{render_trace_turns, TraceRecord, TRACE_FILE_NAME};
+pub use self::trace::{replay_trace, TraceEvent, TraceReplay};
 
 use crate::client::{
     create_client_config, list_client_types, list_models, ClientConfig, MessageContentToolCalls,
     Model, ModelType, ProviderModels, OPENAI_COMPATIBLE_PROVIDERS,
 };
-use crate::function::{FunctionDeclaration, Functions, ToolResult};
-use crate::rag::Rag;
+use crate::function::{
+    native_declarations, FunctionDeclaration, Functions, ToolPermission, ToolResult, NATIVE_TOOLSET,
+};
+use crate::mcp::{is_mcp_path, McpClients, McpServerConfig};
+use crate::rag::{Rag, RagFilter, RetrievalStrategy, FILTER_KEYS};
 use crate::render::{MarkdownRender, RenderOptions};
 use crate::repl::{run_repl_command, split_args_text};
 use crate::utils::*;
@@ -52,8 +78,10 @@ const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.t
 const CONFIG_FILE_NAME: &str = "config.yaml";
 const ROLES_DIR_NAME: &str = "roles";
 const MACROS_DIR_NAME: &str = "macros";
+const TEMPLATES_DIR_NAME: &str = "templates";
 const ENV_FILE_NAME: &str = ".env";
 const MESSAGES_FILE_NAME: &str = "messages.md";
+const MEMORY_FILE_NAME: &str = "memory.yaml";
 const SESSIONS_DIR_NAME: &str = "sessions";
 const RAGS_DIR_NAME: &str = "rags";
 const FUNCTIONS_DIR_NAME: &str = "functions";
@@ -112,10 +140,21 @@ pub struct Config {
     pub editor: Option<String>,
     pub wrap: Option<String>,
     pub wrap_code: bool,
+    pub trace: bool,
 
     pub function_calling: bool,
     pub mapping_tools: IndexMap<String, String>,
     pub use_tools: Option<String>,
+    pub tool_call_concurrency: usize,
+    pub tool_call_timeout: Option<u64>,
+    pub tool_call_timeouts: IndexMap<String, u64>,
+    #[serde(default)]
+    pub tool_permissions: IndexMap<String, ToolPermission>,
+    #[serde(default)]
+    pub tool_sandbox: SandboxConfig,
+    pub native_tools_root: Option<PathBuf>,
+    pub sub_agent_max_depth: usize,
+    pub sub_agent_max_calls: usize,
 
     pub repl_prelude: Option<String>,
     pub cmd_prelude: Option<String>,
@@ -123,19 +162,32 @@ pub struct Config {
 
     pub save_session: Option<bool>,
     pub compress_threshold: usize,
+    pub compress_strategy: CompressStrategy,
+    pub compress_keep_tool_results: bool,
+    pub search_history_bm25: bool,
     pub summarize_prompt: Option<String>,
     pub summary_prompt: Option<String>,
 
     pub rag_embedding_model: Option<String>,
+    pub rag_vision_model: Option<String>,
     pub rag_reranker_model: Option<String>,
     pub rag_top_k: usize,
+    pub rag_retrieval_strategy: RetrievalStrategy,
     pub rag_chunk_size: Option<usize>,
     pub rag_chunk_overlap: Option<usize>,
     pub rag_template: Option<String>,
 
+    pub memory: bool,
+    pub memory_extract: bool,
+    pub memory_top_k: usize,
+    pub memory_min_score: f32,
+
     #[serde(default)]
     pub document_loaders: HashMap<String, String>,
 
+    #[serde(default)]
+    pub mcp_servers: IndexMap<String, McpServerConfig>,
+
     pub highlight: bool,
     pub theme: Option<String>,
     pub left_prompt: Option<String>,
@@ -160,9 +212,21 @@ pub struct Config {
     #[serde(skip)]
     pub functions: Functions,
     #[serde(skip)]
+    pub mcp: McpClients,
+    #[serde(skip)]
+    pub mcp_loaded: bool,
+    #[serde(skip)]
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<LastMessage>,
+    #[serde(skip)]
+    pub sub_agent_depth: usize,
+    #[serde(skip)]
+    pub sub_agent_calls: usize,
+    #[serde(skip)]
+    pub trace_turn: Option<(String, PathBuf)>,
+    #[serde(skip)]
+    pub trace_replay: Option<TraceReplay>,
 
     #[serde(skip)]
     pub role: Option<Role>,
@@ -171,6 +235,8 @@ pub struct Config {
     #[serde(skip)]
     pub rag: Option<Arc<Rag>>,
     #[serde(skip)]
+    pub memory_rag: Option<Arc<Rag>>,
+    #[serde(skip)]
     pub agent: Option<Agent>,
 }
 
@@ -188,10 +254,19 @@ impl Default for Config {
             editor: None,
             wrap: None,
             wrap_code: false,
+            trace: false,
 
             function_calling: true,
             mapping_tools: Default::default(),
             use_tools: None,
+            tool_call_concurrency: 4,
+            tool_call_timeout: None,
+            tool_call_timeouts: Default::default(),
+            tool_permissions: Default::default(),
+            tool_sandbox: Default::default(),
+            native_tools_root: None,
+            sub_agent_max_depth: 2,
+            sub_agent_max_calls: 10,
 
             repl_prelude: None,
             cmd_prelude: None,
@@ -199,18 +274,30 @@ impl Default for Config {
 
             save_session: None,
             compress_threshold: 4000,
+            compress_strategy: Default::default(),
+            compress_keep_tool_results: false,
+            search_history_bm25: false,
             summarize_prompt: None,
             summary_prompt: None,
 
             rag_embedding_model: None,
+            rag_vision_model: None,
             rag_reranker_model: None,
             rag_top_k: 5,
+            rag_retrieval_strategy: Default::default(),
             rag_chunk_size: None,
             rag_chunk_overlap: None,
             rag_template: None,
 
+            memory: true,
+            memory_extract: false,
+            memory_top_k: 3,
+            memory_min_score: 0.5,
+
             document_loaders: Default::default(),
 
+            mcp_servers: Default::default(),
+
             highlight: true,
             theme: None,
             left_prompt: None,
@@ -229,12 +316,19 @@ impl Default for Config {
 
             model: Default::default(),
             functions: Default::default(),
+            mcp: Default::default(),
+            mcp_loaded: false,
             working_mode: WorkingMode::Cmd,
             last_message: None,
+            sub_agent_depth: 0,
+            sub_agent_calls: 0,
+            trace_turn: None,
+            trace_replay: None,
 
             role: None,
             session: None,
             rag: None,
+            memory_rag: None,
             agent: None,
         }
     }
@@ -275,7 +369,6 @@ impl Config {
             config.load_functions()?;
 
             config.setup_model()?;
-            config.setup_document_loaders();
             config.setup_user_agent();
             Ok(())
         };
@@ -330,6 +423,17 @@ impl Config {
         Self::macros_dir().join(format!("{name}.yaml"))
     }
 
+    pub fn templates_dir() -> PathBuf {
+        match env::var(get_env_name("templates_dir")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(TEMPLATES_DIR_NAME),
+        }
+    }
+
+    pub fn template_file(name: &str) -> PathBuf {
+        Self::templates_dir().join(format!("{name}.md"))
+    }
+
     pub fn env_file() -> PathBuf {
         match env::var(get_env_name("env_file")) {
             Ok(value) => PathBuf::from(value),
@@ -348,12 +452,24 @@ impl Config {
     }
 
     pub fn sessions_dir(&self) -> PathBuf {
-        match &self.agent {
+        Self::agent_sessions_dir(self.agent.as_ref().map(|v| v.name()))
+    }
+
+    /// The sessions dir of the agent, or the global one for `None`
+    pub fn agent_sessions_dir(agent: Option<&str>) -> PathBuf {
+        match agent {
             None => match env::var(get_env_name("sessions_dir")) {
                 Ok(value) => PathBuf::from(value),
                 Err(_) => Self::local_path(SESSIONS_DIR_NAME),
             },
-            Some(agent) => Self::agent_data_dir(agent.name()).join(SESSIONS_DIR_NAME),
+            Some(agent) => Self::agent_data_dir(agent).join(SESSIONS_DIR_NAME),
+        }
+    }
+
+    pub fn memory_file() -> PathBuf {
+        match env::var(get_env_name("memory_file")) {
+            Ok(value) => PathBuf::from(value),
+            Err(_) => Self::local_path(MEMORY_FILE_NAME),
         }
     }
 
@@ -386,6 +502,16 @@ impl Config {
         }
     }
 
+    pub fn trace_file(&self) -> PathBuf {
+        match (&self.session, &self.agent) {
+            (Some(session), _) => self
+                .session_file(session.name())
+                .with_extension(TRACE_FILE_NAME),
+            (None, Some(agent)) => Self::agent_data_dir(agent.name()).join(TRACE_FILE_NAME),
+            (None, None) => Self::local_path(TRACE_FILE_NAME),
+        }
+    }
+
     pub fn rag_file(&self, name: &str) -> PathBuf {
         match &self.agent {
             Some(agent) => Self::agent_rag_file(agent.name(), name),
@@ -577,6 +703,10 @@ impl Config {
             Some(rag) => rag.get_config(),
             None => (self.rag_reranker_model.clone(), self.rag_top_k),
         };
+        let rag_retrieval_strategy = match &self.rag {
+            Some(rag) => rag.retrieval_strategy(),
+            None => self.rag_retrieval_strategy,
+        };
         let role = self.extract_role();
         let mut items = vec![
             ("model", role.model().id()),
@@ -592,15 +722,27 @@ impl Config {
             ),
             ("save_session", format_option_value(&self.save_session)),
             ("compress_threshold", self.compress_threshold.to_string()),
+            ("compress_strategy", self.compress_strategy.to_string()),
+            (
+                "compress_keep_tool_results",
+                self.compress_keep_tool_results.to_string(),
+            ),
+            ("search_history_bm25", self.search_history_bm25.to_string()),
             (
                 "rag_reranker_model",
                 format_option_value(&rag_reranker_model),
             ),
             ("rag_top_k", rag_top_k.to_string()),
+            ("rag_retrieval_strategy", rag_retrieval_strategy.to_string()),
+            ("memory", self.memory.to_string()),
+            ("memory_extract", self.memory_extract.to_string()),
+            ("memory_top_k", self.memory_top_k.to_string()),
+            ("memory_min_score", self.memory_min_score.to_string()),
             ("dry_run", self.dry_run.to_string()),
             ("function_calling", self.function_calling.to_string()),
             ("stream", self.stream.to_string()),
             ("save", self.save.to_string()),
+            ("trace", self.trace.to_string()),
             ("keybindings", self.keybindings.clone()),
             ("wrap", wrap),
             ("wrap_code", self.wrap_code.to_string()),
@@ -612,8 +754,10 @@ impl Config {
             ("sessions_dir", display_path(&self.sessions_dir())),
             ("rags_dir", display_path(&Self::rags_dir())),
             ("macros_dir", display_path(&Self::macros_dir())),
+            ("templates_dir", display_path(&Self::templates_dir())),
             ("functions_dir", display_path(&Self::functions_dir())),
             ("messages_file", display_path(&self.messages_file())),
+            ("memory_file", display_path(&Self::memory_file())),
         ];
         if let Ok((_, Some(log_path))) = Self::log_config(self.working_mode.is_serve()) {
             items.push(("log_path", display_path(&log_path)));
@@ -658,6 +802,14 @@ impl Config {
                 let value = parse_value(value)?;
                 config.write().set_compress_threshold(value);
             }
+            "compress_strategy" => {
+                let value = parse_value(value)?;
+                config.write().set_compress_strategy(value);
+            }
+            "compress_keep_tool_results" => {
+                let value = parse_value(value)?;
+                config.write().set_compress_keep_tool_results(value);
+            }
             "rag_reranker_model" => {
                 let value = parse_value(value)?;
                 Self::set_rag_reranker_model(config, value)?;
@@ -666,6 +818,18 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 Self::set_rag_top_k(config, value)?;
             }
+            "rag_retrieval_strategy" => {
+                let value = value.parse()?;
+                Self::set_rag_retrieval_strategy(config, value)?;
+            }
+            "rag_fusion_weights" => {
+                let value = parse_fusion_weights(value)?;
+                update_rag(config, |rag| rag.set_fusion_weights(value))?;
+            }
+            "rag_min_score" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                update_rag(config, |rag| rag.set_min_score(value))?;
+            }
             "dry_run" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().dry_run = value;
@@ -685,6 +849,30 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().save = value;
             }
+            "trace" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().trace = value;
+            }
+            "search_history_bm25" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().search_history_bm25 = value;
+            }
+            "memory" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().memory = value;
+            }
+            "memory_extract" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().memory_extract = value;
+            }
+            "memory_top_k" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().memory_top_k = value;
+            }
+            "memory_min_score" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                config.write().memory_min_score = value;
+            }
             "highlight" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 config.write().highlight = value;
@@ -700,6 +888,7 @@ impl Config {
             "session" => (config.read().sessions_dir(), Some(".yaml")),
             "rag" => (Self::rags_dir(), Some(".yaml")),
             "macro" => (Self::macros_dir(), Some(".yaml")),
+            "template" => (Self::templates_dir(), Some(".md")),
             "agent-data" => (Self::agents_data_dir(), None),
             _ => bail!("Unknown kind '{kind}'"),
         };
@@ -800,6 +989,22 @@ impl Config {
         }
     }
 
+    pub fn set_compress_strategy(&mut self, value: Option<CompressStrategy>) {
+        if let Some(session) = self.session.as_mut() {
+            session.set_compress_strategy(value);
+        } else {
+            self.compress_strategy = value.unwrap_or_default();
+        }
+    }
+
+    pub fn set_compress_keep_tool_results(&mut self, value: Option<bool>) {
+        if let Some(session) = self.session.as_mut() {
+            session.set_compress_keep_tool_results(value);
+        } else {
+            self.compress_keep_tool_results = value.unwrap_or_default();
+        }
+    }
+
     pub fn set_rag_reranker_model(config: &GlobalConfig, value: Option<String>) -> Result<()> {
         if let Some(id) = &value {
             Model::retrieve_model(&config.read(), id, ModelType::Reranker)?;
@@ -827,6 +1032,21 @@ impl Config {
         Ok(())
     }
 
+    pub fn set_rag_retrieval_strategy(
+        config: &GlobalConfig,
+        value: RetrievalStrategy,
+    ) -> Result<()> {
+        let has_rag = config.read().rag.is_some();
+        match has_rag {
+            true => update_rag(config, |rag| {
+                rag.set_retrieval_strategy(value)?;
+                Ok(())
+            })?,
+            false => config.write().rag_retrieval_strategy = value,
+        }
+        Ok(())
+    }
+
     pub fn set_wrap(&mut self, value: &str) -> Result<()> {
         if value == "no" {
             self.wrap = None;
@@ -916,7 +1136,9 @@ impl Config {
 
     pub fn retrieve_role(&self, name: &str) -> Result<Role> {
         let names = Self::list_roles(false);
-        let mut role = if names.contains(&name.to_string()) {
+        let mut role = if is_mcp_path(name) {
+            Role::new(name, &self.mcp.get_prompt(name)?)
+        } else if names.contains(&name.to_string()) {
             let path = Self::role_file(name);
             let content = read_to_string(&path)?;
             Role::new(name, &content)
@@ -1204,6 +1426,189 @@ impl Config {
         Ok(())
     }
 
+    pub fn session_branches(&self) -> Result<String> {
+        match self.session.as_ref() {
+            Some(session) => Ok(session.render_branches()),
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn fork_session_branch(&mut self, name: Option<&str>, turns: Option<usize>) -> Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            bail!("No session")
+        };
+        let turns = turns.unwrap_or_else(|| session.user_messages_len());
+        let name = session.fork_branch(name, turns)?;
+        println!("✓ Forked branch '{name}' at turn {turns}.");
+        self.discontinuous_last_message();
+        Ok(())
+    }
+
+    pub fn switch_session_branch(&mut self, name: &str) -> Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            bail!("No session")
+        };
+        session.switch_branch(name)?;
+        self.discontinuous_last_message();
+        Ok(())
+    }
+
+    pub fn diff_session_branches(&self, name: &str, other: Option<&str>) -> Result<String> {
+        match self.session.as_ref() {
+            Some(session) => session.diff_branches(name, other),
+            None => bail!("No session"),
+        }
+    }
+
+    /// List matches across saved sessions, then offer to open the session of one of them.
+    /// Returns whether a session was opened.
+    pub fn search_history(config: &GlobalConfig, query: &str) -> Result<bool> {
+        let matches = {
+            let config = config.read();
+            let mut agents = list_agents();
+            if let Ok(entries) = read_dir(Self::agents_data_dir()) {
+                agents.extend(
+                    entries
+                        .flatten()
+                        .filter(|v| v.path().is_dir())
+                        .map(|v| v.file_name().to_string_lossy().to_string()),
+                );
+            }
+            agents.sort_unstable();
+            agents.dedup();
+            let mut sessions_dirs = vec![(None, Self::agent_sessions_dir(None))];
+            sessions_dirs.extend(
+                agents
+                    .into_iter()
+                    .map(|v| (Some(v.clone()), Self::agent_sessions_dir(Some(&v)))),
+            );
+            search_history(
+                &sessions_dirs,
+                &config.messages_file(),
+                query,
+                config.search_history_bm25,
+            )?
+        };
+        if matches.is_empty() {
+            println!("No matches for '{query}'");
+            return Ok(false);
+        }
+        for (index, item) in matches.iter().enumerate() {
+            println!("{:>3}. {}", index + 1, item.title());
+            println!("{}", dimmed_text(&format!("     {}", item.snippet)));
+        }
+        if !*IS_STDOUT_TERMINAL {
+            return Ok(false);
+        }
+        let options: Vec<String> = matches.iter().map(|v| v.title()).collect();
+        let index = match Select::new("Open:", options).raw_prompt() {
+            Ok(option) => option.index,
+            Err(inquire::InquireError::OperationCanceled) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let item = &matches[index];
+        println!();
+        if item.session.is_none() {
+            println!("{}", item.text);
+            return Ok(false);
+        }
+        let (output, opened) = config.write().open_session_turn(item)?;
+        println!("{output}");
+        if !opened {
+            println!(
+                "{}",
+                dimmed_text("(read from the session file; the current session stays open)")
+            );
+        }
+        Ok(opened)
+    }
+
+    /// Render the turn of a session match
+    ///
+    /// The session is opened unless another session or agent is active, in
+    /// which case the turn is read from the session file instead.
+    pub fn open_session_turn(&mut self, item: &HistoryMatch) -> Result<(String, bool)> {
+        let Some(name) = item.session.as_deref() else {
+            bail!("Not a session turn");
+        };
+        let active_agent = self.agent.as_ref().map(|v| v.name());
+        let active_session = self.session.as_ref().map(|v| v.name());
+        if item.agent.as_deref() == active_agent
+            && (active_session.is_none() || active_session == Some(name))
+        {
+            if active_session.is_none() {
+                self.use_session(Some(name))?;
+            }
+            let Some(session) = self.session.as_mut() else {
+                bail!("No session");
+            };
+            if let Some(branch) = &item.branch {
+                session.switch_branch(branch)?;
+            }
+            return Ok((session.render_turn(item.turn)?, true));
+        }
+        let sessions_dir = Self::agent_sessions_dir(item.agent.as_deref());
+        let path = sessions_dir.join(format!("{name}.yaml"));
+        let mut session = Session::load(self, name, &path)?;
+        if let Some(branch) = &item.branch {
+            session.switch_branch(branch)?;
+        }
+        Ok((session.render_turn(item.turn)?, false))
+    }
+
+    pub fn session_history(&self) -> Result<String> {
+        match self.session.as_ref() {
+            Some(session) => Ok(session.render_history()),
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn rewind_session(&mut self, turn: usize) -> Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            bail!("No session")
+        };
+        session.rewind(turn)?;
+        self.discontinuous_last_message();
+        Ok(())
+    }
+
+    pub fn pin_session_turn(&mut self, turn: usize, pinned: bool) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.pin_turn(turn, pinned),
+            None => bail!("No session"),
+        }
+    }
+
+    pub fn drop_session_turn(&mut self, turn: usize) -> Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            bail!("No session")
+        };
+        session.drop_turn(turn)?;
+        self.discontinuous_last_message();
+        Ok(())
+    }
+
+    /// Edit the user message of a turn, then fork a branch right before it
+    pub fn edit_session_message(&mut self, turn: usize) -> Result<String> {
+        let text = match self.session.as_ref() {
+            Some(session) => session.user_message(turn)?,
+            None => bail!("No session"),
+        };
+        let editor = self.editor()?;
+        let path = temp_file("-message-", ".md");
+        std::fs::write(&path, &text)
+            .with_context(|| format!("Failed to write to '{}'", path.display()))?;
+        let ret = edit_file(&editor, &path).and_then(|_| Ok(read_to_string(&path)?));
+        let _ = remove_file(&path);
+        let new_text = ret.with_context(|| format!("Failed to edit message with '{editor}'"))?;
+        let new_text = new_text.trim().to_string();
+        if new_text.is_empty() || new_text == text.trim() {
+            bail!("The message was not changed");
+        }
+        self.fork_session_branch(None, Some(turn - 1))?;
+        Ok(new_text)
+    }
+
     pub fn set_save_session_this_time(&mut self) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
             session.set_save_session_this_time();
@@ -1226,8 +1631,11 @@ impl Config {
         {
             let mut config = config.write();
             let compress_threshold = config.compress_threshold;
+            let has_plan = config
+                .session_compress_plan()
+                .is_some_and(|(_, plan)| !plan.is_empty());
             if let Some(session) = config.session.as_mut() {
-                if session.need_compress(compress_threshold) {
+                if has_plan && session.need_compress(compress_threshold) {
                     session.set_compressing(true);
                     need_compress = true;
                 }
@@ -1256,21 +1664,34 @@ impl Config {
     }
 
     pub async fn compress_session(config: &GlobalConfig) -> Result<()> {
-        match config.read().session.as_ref() {
+        let (plan, transcript) = match config.read().session.as_ref() {
             Some(session) => {
                 if !session.has_user_messages() {
                     bail!("No need to compress since there are no messages in the session")
                 }
+                let (strategy, plan) = config.read().session_compress_plan().unwrap_or_default();
+                if plan.is_empty() {
+                    bail!("No turns to compress with the '{strategy}' strategy")
+                }
+                let transcript = session.compress_transcript(&plan);
+                (plan, transcript)
             }
             None => bail!("No session"),
-        }
+        };
 
         let prompt = config
             .read()
             .summarize_prompt
             .clone()
             .unwrap_or_else(|| SUMMARIZE_PROMPT.into());
-        let input = Input::from_str(config, &prompt, None);
+        let input = match transcript {
+            // Only part of the session is summarized, so the chat is sent without the session
+            Some(transcript) => {
+                let role = config.read().extract_role();
+                Input::from_str(config, &format!("{transcript}\n{prompt}"), Some(role))
+            }
+            None => Input::from_str(config, &prompt, None),
+        };
         let summary = input.fetch_chat_text().await?;
         let summary_prompt = config
             .read()
@@ -1278,12 +1699,24 @@ impl Config {
             .clone()
             .unwrap_or_else(|| SUMMARY_PROMPT.into());
         if let Some(session) = config.write().session.as_mut() {
-            session.compress(format!("{summary_prompt}{summary}"));
+            session.compress(format!("{summary_prompt}{summary}"), &plan);
         }
         config.write().discontinuous_last_message();
         Ok(())
     }
 
+    /// The turns the session compresses under the strategy in effect
+    fn session_compress_plan(&self) -> Option<(CompressStrategy, Vec<usize>)> {
+        let session = self.session.as_ref()?;
+        let strategy = session
+            .compress_strategy()
+            .unwrap_or(self.compress_strategy);
+        let keep_tool_results = session
+            .compress_keep_tool_results()
+            .unwrap_or(self.compress_keep_tool_results);
+        Some((strategy, session.compress_plan(strategy, keep_tool_results)))
+    }
+
     pub fn is_compressing_session(&self) -> bool {
         self.session
             .as_ref()
@@ -1447,14 +1880,28 @@ impl Config {
         rag: &Rag,
         text: &str,
         abort_signal: AbortSignal,
-    ) -> Result<String> {
+    ) -> Result<(String, Vec<String>)> {
         let (reranker_model, top_k) = rag.get_config();
         let (embeddings, ids) = rag
-            .search(text, top_k, reranker_model.as_deref(), abort_signal)
+            .search(
+                text,
+                top_k,
+                reranker_model.as_deref(),
+                rag.filter(),
+                abort_signal,
+            )
             .await?;
         let text = config.read().rag_template(&embeddings, text);
         rag.set_last_sources(&ids);
-        Ok(text)
+        Ok((text, rag.get_images(&ids)))
+    }
+
+    pub fn set_rag_filter(config: &GlobalConfig, filter: Option<&str>) -> Result<()> {
+        let filter = filter.map(|v| v.parse::<RagFilter>()).transpose()?;
+        update_rag(config, |rag| {
+            rag.set_filter(filter);
+            Ok(())
+        })
     }
 
     pub fn list_rags() -> Vec<String> {
@@ -1609,6 +2056,29 @@ impl Config {
         Ok(())
     }
 
+    pub fn list_templates() -> Vec<String> {
+        list_file_names(Self::templates_dir(), ".md")
+    }
+
+    pub fn has_template(name: &str) -> bool {
+        Self::list_templates().contains(&name.to_string())
+    }
+
+    pub fn new_template(&mut self, name: &str) -> Result<()> {
+        let ans = Confirm::new("Create a new template?")
+            .with_default(true)
+            .prompt()?;
+        if ans {
+            let template_path = Self::template_file(name);
+            ensure_parent_exists(&template_path)?;
+            let editor = self.editor()?;
+            edit_file(&editor, &template_path)?;
+        } else {
+            bail!("No template");
+        }
+        Ok(())
+    }
+
     pub fn apply_prelude(&mut self) -> Result<()> {
         if self.macro_flag || !self.state().is_empty() {
             return Ok(());
@@ -1616,7 +2086,7 @@ impl Config {
         let prelude = match self.working_mode {
             WorkingMode::Repl => self.repl_prelude.as_ref(),
             WorkingMode::Cmd => self.cmd_prelude.as_ref(),
-            WorkingMode::Serve => return Ok(()),
+            WorkingMode::Serve | WorkingMode::Mcp => return Ok(()),
         };
         let prelude = match prelude {
             Some(v) => {
@@ -1672,6 +2142,24 @@ impl Config {
                                     .map(|v| v.to_string())
                                     .filter(|v| declaration_names.contains(v)),
                             )
+                        } else if item == NATIVE_TOOLSET {
+                            tool_names.extend(
+                                self.functions
+                                    .declarations()
+                                    .iter()
+                                    .filter(|v| v.native)
+                                    .map(|v| v.name.clone()),
+                            )
+                        } else if self.mcp.servers().any(|v| v == item) {
+                            tool_names.extend(
+                                self.functions
+                                    .declarations()
+                                    .iter()
+                                    .filter(
+                                        |v| matches!(&v.mcp, Some((server, _)) if server == item),
+                                    )
+                                    .map(|v| v.name.clone()),
+                            )
                         } else if declaration_names.contains(item) {
                             tool_names.insert(item.to_string());
                         }
@@ -1745,7 +2233,12 @@ impl Config {
         let filter = args.last().unwrap_or(&"");
         if args.len() == 1 {
             values = match cmd {
-                ".role" => map_completion_values(Self::list_roles(true)),
+                ".role" => {
+                    let mut values = map_completion_values(Self::list_roles(true));
+                    values.extend(self.mcp.list_prompts());
+                    values
+                }
+                ".file" if is_mcp_path(filter) => self.mcp.list_resources(),
                 ".model" => list_models(self, ModelType::Chat)
                     .into_iter()
                     .map(|v| (v.id(), Some(v.description())))
@@ -1764,8 +2257,20 @@ impl Config {
                     }
                 }
                 ".rag" => map_completion_values(Self::list_rags()),
+                ".rag-filter" => {
+                    let mut values = vec!["clear"];
+                    values.extend(FILTER_KEYS);
+                    map_completion_values(values)
+                }
+                ".branch" => map_completion_values(vec!["list", "fork", "switch", "diff"]),
+                ".memory" => map_completion_values(vec!["list", "forget"]),
+                ".rewind" | ".edit-message" | ".drop" | ".pin" | ".unpin" => match &self.session {
+                    Some(session) => session.complete_turns(),
+                    None => vec![],
+                },
                 ".agent" => map_completion_values(list_agents()),
                 ".macro" => map_completion_values(Self::list_macros()),
+                ".template" => map_completion_values(Self::list_templates()),
                 ".starter" => match &self.agent {
                     Some(agent) => agent
                         .conversation_staters()
@@ -1782,13 +2287,24 @@ impl Config {
                         "use_tools",
                         "save_session",
                         "compress_threshold",
+                        "compress_strategy",
+                        "compress_keep_tool_results",
                         "rag_reranker_model",
                         "rag_top_k",
+                        "rag_retrieval_strategy",
+                        "rag_fusion_weights",
+                        "rag_min_score",
+                        "memory",
+                        "memory_extract",
+                        "memory_top_k",
+                        "memory_min_score",
                         "max_output_tokens",
                         "dry_run",
                         "function_calling",
                         "stream",
                         "save",
+                        "trace",
+                        "search_history_bm25",
                         "highlight",
                     ];
                     values.sort_unstable();
@@ -1797,9 +2313,14 @@ impl Config {
                         .map(|v| (format!("{v} "), None))
                         .collect()
                 }
-                ".delete" => {
-                    map_completion_values(vec!["role", "session", "rag", "macro", "agent-data"])
-                }
+                ".delete" => map_completion_values(vec![
+                    "role",
+                    "session",
+                    "rag",
+                    "macro",
+                    "template",
+                    "agent-data",
+                ]),
                 _ => vec![],
             };
         } else if cmd == ".set" && args.len() == 2 {
@@ -1811,6 +2332,10 @@ impl Config {
                 "dry_run" => complete_bool(self.dry_run),
                 "stream" => complete_bool(self.stream),
                 "save" => complete_bool(self.save),
+                "trace" => complete_bool(self.trace),
+                "search_history_bm25" => complete_bool(self.search_history_bm25),
+                "memory" => complete_bool(self.memory),
+                "memory_extract" => complete_bool(self.memory_extract),
                 "function_calling" => complete_bool(self.function_calling),
                 "use_tools" => {
                     let mut prefix = String::new();
@@ -1825,12 +2350,27 @@ impl Config {
                     }
                     values.extend(self.functions.declarations().iter().map(|v| v.name.clone()));
                     values.extend(self.mapping_tools.keys().map(|v| v.to_string()));
+                    values.extend(self.mcp.servers().map(|v| v.to_string()));
+                    values.push(NATIVE_TOOLSET.to_string());
                     values
                         .into_iter()
                         .filter(|v| !ignores.contains(v.as_str()))
                         .map(|v| format!("{prefix}{v}"))
                         .collect()
                 }
+                "compress_strategy" => vec![
+                    "null".to_string(),
+                    "full".to_string(),
+                    "rolling:4".to_string(),
+                    "window:4".to_string(),
+                ],
+                "compress_keep_tool_results" => {
+                    let value = match &self.session {
+                        Some(session) => session.compress_keep_tool_results(),
+                        None => Some(self.compress_keep_tool_results),
+                    };
+                    complete_option_bool(value)
+                }
                 "save_session" => {
                     let save_session = if let Some(session) = &self.session {
                         session.save_session()
@@ -1843,10 +2383,36 @@ impl Config {
                     .iter()
                     .map(|v| v.id())
                     .collect(),
+                "rag_retrieval_strategy" => RetrievalStrategy::ALL
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect(),
                 "highlight" => complete_bool(self.highlight),
                 _ => vec![],
             };
             values = candidates.into_iter().map(|v| (v, None)).collect();
+        } else if cmd == ".memory" && args[0] == "forget" {
+            values = list_memories()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(id, text, _)| (id, Some(text)))
+                .collect();
+        } else if cmd == ".branch" && matches!(args[0], "switch" | "diff") {
+            if let Some(session) = &self.session {
+                values = map_completion_values(session.list_branches());
+            }
+        } else if cmd == ".rag-filter" {
+            values = map_completion_values(FILTER_KEYS.to_vec());
+        } else if cmd == ".file" && is_mcp_path(filter) {
+            values = self.mcp.list_resources();
+        } else if cmd == ".template" {
+            if let Ok(template) = Template::load(args[0]) {
+                values = template
+                    .variables()
+                    .iter()
+                    .map(|v| (format!("{}=", v.name), v.description.clone()))
+                    .collect();
+            }
         } else if cmd == ".agent" {
             if args.len() == 2 {
                 let dir = Self::agent_data_dir(args[0]).join(SESSIONS_DIR_NAME);
@@ -2047,6 +2613,10 @@ impl Config {
     }
 
     pub fn before_chat_completion(&mut self, input: &Input) -> Result<()> {
+        if self.sub_agent_depth == 0 && input.tool_calls().is_none() {
+            self.sub_agent_calls = 0;
+            self.start_trace_turn(input);
+        }
         self.last_message = Some(LastMessage::new(input.clone(), String::new()));
         Ok(())
     }
@@ -2067,6 +2637,43 @@ impl Config {
         Ok(())
     }
 
+    pub fn trace_info(&self, count: usize) -> Result<String> {
+        render_trace_turns(&self.trace_file(), count)
+    }
+
+    /// The trace file is fixed for the whole turn, so records of sub-agents land in the same file
+    fn start_trace_turn(&mut self, input: &Input) {
+        self.trace_turn = None;
+        if !self.trace {
+            return;
+        }
+        let path = self.trace_file();
+        self.trace_turn = Some((uuid::Uuid::new_v4().to_string(), path));
+        self.record_trace(|| TraceEvent::turn(input, self.agent.as_ref()));
+    }
+
+    pub fn record_trace(&self, event: impl FnOnce() -> TraceEvent) {
+        let Some((turn, path)) = &self.trace_turn else {
+            return;
+        };
+        let record = TraceRecord {
+            turn: turn.clone(),
+            time: now(),
+            event: event(),
+        };
+        let ret = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                ensure_parent_exists(path)?;
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{line}")?;
+                Ok(())
+            });
+        if let Err(err) = ret {
+            warn!("Failed to record trace at '{}': {err}", path.display());
+        }
+    }
+
     fn discontinuous_last_message(&mut self) {
         if let Some(last_message) = self.last_message.as_mut() {
             last_message.continuous = false;
@@ -2138,6 +2745,7 @@ impl Config {
                 config_variables.extend(v.clone());
             }
             let new_variables = Agent::init_agent_variables(
+                agent.name(),
                 agent.defined_variables(),
                 &config_variables,
                 self.info_flag,
@@ -2164,6 +2772,7 @@ impl Config {
                         config_variables.extend(v.clone());
                     }
                     let new_variables = Agent::init_agent_variables(
+                        agent.name(),
                         agent.defined_variables(),
                         &config_variables,
                         self.info_flag,
@@ -2179,8 +2788,14 @@ impl Config {
             }
             session.sync_agent(agent);
         } else {
-            let variables = session.agent_variables();
-            agent.set_session_variables(variables.clone());
+            let variables = Agent::init_agent_variables(
+                agent.name(),
+                agent.defined_variables(),
+                session.agent_variables(),
+                self.info_flag,
+            )?;
+            agent.set_session_variables(variables);
+            session.sync_agent_variables(agent);
             agent.update_session_dynamic_instructions(Some(
                 session.agent_instructions().to_string(),
             ))?;
@@ -2265,6 +2880,12 @@ impl Config {
         if let Some(Some(v)) = read_env_bool(&get_env_name("save")) {
             self.save = v;
         }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("trace")) {
+            self.trace = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("search_history_bm25")) {
+            self.search_history_bm25 = v;
+        }
         if let Ok(v) = env::var(get_env_name("keybindings")) {
             if v == "vi" {
                 self.keybindings = v;
@@ -2291,6 +2912,34 @@ impl Config {
         if let Some(v) = read_env_value::<String>(&get_env_name("use_tools")) {
             self.use_tools = v;
         }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("tool_call_concurrency")) {
+            self.tool_call_concurrency = v;
+        }
+        if let Some(v) = read_env_value::<u64>(&get_env_name("tool_call_timeout")) {
+            self.tool_call_timeout = v;
+        }
+        if let Ok(v) = env::var(get_env_name("tool_call_timeouts")) {
+            if let Ok(v) = serde_json::from_str(&v) {
+                self.tool_call_timeouts = v;
+            }
+        }
+        if let Ok(v) = env::var(get_env_name("tool_permissions")) {
+            if let Ok(v) = serde_json::from_str(&v) {
+                self.tool_permissions = v;
+            }
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("tool_sandbox")) {
+            self.tool_sandbox.enabled = v;
+        }
+        if let Some(v) = read_env_value::<PathBuf>(&get_env_name("native_tools_root")) {
+            self.native_tools_root = v;
+        }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("sub_agent_max_depth")) {
+            self.sub_agent_max_depth = v;
+        }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("sub_agent_max_calls")) {
+            self.sub_agent_max_calls = v;
+        }
 
         if let Some(v) = read_env_value::<String>(&get_env_name("repl_prelude")) {
             self.repl_prelude = v;
@@ -2308,6 +2957,14 @@ impl Config {
         if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("compress_threshold")) {
             self.compress_threshold = v;
         }
+        if let Some(Some(v)) =
+            read_env_value::<CompressStrategy>(&get_env_name("compress_strategy"))
+        {
+            self.compress_strategy = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("compress_keep_tool_results")) {
+            self.compress_keep_tool_results = v;
+        }
         if let Some(v) = read_env_value::<String>(&get_env_name("summarize_prompt")) {
             self.summarize_prompt = v;
         }
@@ -2318,12 +2975,20 @@ impl Config {
         if let Some(v) = read_env_value::<String>(&get_env_name("rag_embedding_model")) {
             self.rag_embedding_model = v;
         }
+        if let Some(v) = read_env_value::<String>(&get_env_name("rag_vision_model")) {
+            self.rag_vision_model = v;
+        }
         if let Some(v) = read_env_value::<String>(&get_env_name("rag_reranker_model")) {
             self.rag_reranker_model = v;
         }
         if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("rag_top_k")) {
             self.rag_top_k = v;
         }
+        if let Some(Some(v)) =
+            read_env_value::<RetrievalStrategy>(&get_env_name("rag_retrieval_strategy"))
+        {
+            self.rag_retrieval_strategy = v;
+        }
         if let Some(v) = read_env_value::<usize>(&get_env_name("rag_chunk_size")) {
             self.rag_chunk_size = v;
         }
@@ -2334,6 +2999,19 @@ impl Config {
             self.rag_template = v;
         }
 
+        if let Some(Some(v)) = read_env_bool(&get_env_name("memory")) {
+            self.memory = v;
+        }
+        if let Some(Some(v)) = read_env_bool(&get_env_name("memory_extract")) {
+            self.memory_extract = v;
+        }
+        if let Some(Some(v)) = read_env_value::<usize>(&get_env_name("memory_top_k")) {
+            self.memory_top_k = v;
+        }
+        if let Some(Some(v)) = read_env_value::<f32>(&get_env_name("memory_min_score")) {
+            self.memory_min_score = v;
+        }
+
         if let Ok(v) = env::var(get_env_name("document_loaders")) {
             if let Ok(v) = serde_json::from_str(&v) {
                 self.document_loaders = v;
@@ -2380,8 +3058,35 @@ impl Config {
         }
     }
 
+    /// Connect the MCP servers once, the first time their tools, prompts or resources are needed
+    pub async fn load_mcp(config: &GlobalConfig) {
+        static LOADING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+        let _guard = LOADING.lock().await;
+        let servers = {
+            let config = config.read();
+            if config.mcp_loaded || config.mcp_servers.is_empty() {
+                return;
+            }
+            config.mcp_servers.clone()
+        };
+        let mcp = McpClients::init(&servers).await;
+        let mut config = config.write();
+        config.functions.extend(mcp.declarations());
+        config.mcp = mcp;
+        config.mcp_loaded = true;
+    }
+
+    /// Whether the role may call tools while the MCP servers are not connected yet
+    pub fn need_mcp(&self, role: &Role) -> bool {
+        !self.mcp_loaded
+            && !self.mcp_servers.is_empty()
+            && self.function_calling
+            && (role.use_tools().is_some() || self.agent.is_some())
+    }
+
     fn load_functions(&mut self) -> Result<()> {
         self.functions = Functions::init(&Self::functions_file())?;
+        self.functions.extend(native_declarations());
         Ok(())
     }
 
@@ -2399,15 +3104,6 @@ impl Config {
         Ok(())
     }
 
-    fn setup_document_loaders(&mut self) {
-        [("pdf", "pdftotext $1 -"), ("docx", "pandoc --to plain $1")]
-            .into_iter()
-            .for_each(|(k, v)| {
-                let (k, v) = (k.to_string(), v.to_string());
-                self.document_loaders.entry(k).or_insert(v);
-            });
-    }
-
     fn setup_user_agent(&mut self) {
         if let Some("auto") = self.user_agent.as_deref() {
             self.user_agent = Some(format!(
@@ -2443,6 +3139,7 @@ pub enum WorkingMode {
     Cmd,
     Repl,
     Serve,
+    Mcp,
 }
 
 impl WorkingMode {
@@ -2486,10 +3183,145 @@ pub async fn macro_execute(
     config.discontinuous_last_message();
     let config = Arc::new(RwLock::new(config));
     config.write().macro_flag = true;
-    for step in &macro_value.steps {
-        let command = Macro::interpolate_command(step, &variables);
-        println!(">> {}", multiline_text(&command));
-        run_repl_command(&config, abort_signal.clone(), &command).await?;
+    let mut variables = variables;
+    run_macro_steps(&config, &macro_value.steps, &mut variables, abort_signal).await
+}
+
+#[async_recursion::async_recursion]
+async fn run_macro_steps(
+    config: &GlobalConfig,
+    steps: &[MacroStep],
+    variables: &mut IndexMap<String, String>,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    for step in steps {
+        match step {
+            MacroStep::Command(command) => {
+                let command = Macro::interpolate_command(command, variables);
+                println!(">> {}", multiline_text(&command));
+                run_repl_command(config, abort_signal.clone(), &command).await?;
+            }
+            MacroStep::Block(block) => {
+                run_macro_block(config, block, variables, abort_signal.clone()).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async_recursion::async_recursion]
+async fn run_macro_block(
+    config: &GlobalConfig,
+    block: &MacroBlock,
+    variables: &mut IndexMap<String, String>,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    if let Some(condition) = &block.if_condition {
+        if !condition.eval(variables)? {
+            return Ok(());
+        }
+    }
+    if let Some(condition) = &block.unless {
+        if condition.eval(variables)? {
+            return Ok(());
+        }
+    }
+    let items: Option<Vec<String>> = match (&block.foreach, &block.foreach_file) {
+        (Some(value), _) => Some(
+            Macro::interpolate_command(value, variables)
+                .lines()
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect(),
+        ),
+        (None, Some(pattern)) => {
+            let pattern = Macro::interpolate_command(pattern, variables);
+            let mut paths: Vec<String> = expand_glob_paths(&[pattern], true)
+                .await?
+                .into_iter()
+                .collect();
+            paths.sort_unstable();
+            Some(paths)
+        }
+        (None, None) => None,
+    };
+    match items {
+        Some(items) => {
+            let name = block.item.as_deref().unwrap_or("item");
+            for item in items {
+                variables.insert(name.to_string(), item);
+                run_macro_action(config, block, variables, abort_signal.clone()).await?;
+            }
+            Ok(())
+        }
+        None => run_macro_action(config, block, variables, abort_signal).await,
+    }
+}
+
+async fn run_macro_action(
+    config: &GlobalConfig,
+    block: &MacroBlock,
+    variables: &mut IndexMap<String, String>,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let output = match (&block.command, &block.run, &block.write) {
+        (Some(command), None, None) => {
+            let command = Macro::interpolate_command(command, variables);
+            println!(">> {}", multiline_text(&command));
+            config.write().last_message = None;
+            run_repl_command(config, abort_signal.clone(), &command).await?;
+            let output = config
+                .read()
+                .last_message
+                .as_ref()
+                .map(|v| v.output.clone());
+            Some(output.unwrap_or_default())
+        }
+        (None, Some(command), None) => {
+            let command = Macro::interpolate_shell_command(command, variables);
+            println!(">> $ {}", multiline_text(&command));
+            let (success, stdout, stderr) =
+                run_command_with_output(&SHELL.cmd, &[&SHELL.arg, &command], None)?;
+            if !success {
+                bail!("Failed to run `{command}`: {}", stderr.trim());
+            }
+            if block.output.is_none() {
+                print!("{stdout}");
+            }
+            Some(stdout.trim_end().to_string())
+        }
+        (None, None, Some(path)) => {
+            let path = Macro::interpolate_command(path, variables);
+            let content =
+                Macro::interpolate_command(block.content.as_deref().unwrap_or_default(), variables);
+            println!(">> write {path}");
+            let path = Path::new(&path);
+            ensure_parent_exists(path)?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(block.append)
+                .truncate(!block.append)
+                .open(path)
+                .with_context(|| format!("Failed to write to '{}'", path.display()))?;
+            writeln!(file, "{}", content.trim_end())?;
+            None
+        }
+        (None, None, None) => None,
+        _ => bail!("A macro step can only have one of `command`, `run` or `write`"),
+    };
+    if let (Some(name), Some(output)) = (&block.output, output) {
+        let value = variables.entry(name.clone()).or_default();
+        if block.append && !value.is_empty() {
+            value.push_str("\n\n");
+            value.push_str(&output);
+        } else {
+            *value = output;
+        }
+    }
+    if !block.steps.is_empty() {
+        run_macro_steps(config, &block.steps, variables, abort_signal).await?;
     }
     Ok(())
 }
@@ -2498,7 +3330,7 @@ pub async fn macro_execute(
 pub struct Macro {
     #[serde(default)]
     pub variables: Vec<MacroVariable>,
-    pub steps: Vec<String>,
+    pub steps: Vec<MacroStep>,
 }
 
 impl Macro {
@@ -2547,6 +3379,19 @@ impl Macro {
         }
         output
     }
+
+    /// Like `interpolate_command`, but each value is quoted as a single shell argument,
+    /// since values may come from the model or from file names
+    pub fn interpolate_shell_command(
+        command: &str,
+        variables: &IndexMap<String, String>,
+    ) -> String {
+        let mut output = command.to_string();
+        for (key, value) in variables {
+            output = output.replace(&format!("{{{{{key}}}}}"), &SHELL.quote(value));
+        }
+        output
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -2557,6 +3402,70 @@ pub struct MacroVariable {
     pub default: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum MacroStep {
+    /// A REPL command
+    Command(String),
+    Block(Box<MacroBlock>),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MacroBlock {
+    /// A REPL command
+    pub command: Option<String>,
+    /// A shell command
+    pub run: Option<String>,
+    /// A file to write `content` to
+    pub write: Option<String>,
+    pub content: Option<String>,
+    /// Capture the output of `command` or `run` into this variable
+    pub output: Option<String>,
+    /// Append to the output variable or the written file instead of replacing it
+    pub append: bool,
+    #[serde(rename = "if")]
+    pub if_condition: Option<MacroCondition>,
+    pub unless: Option<MacroCondition>,
+    /// Repeat the step for each non-empty line of the value
+    pub foreach: Option<String>,
+    /// Repeat the step for each file matching the path or glob
+    pub foreach_file: Option<String>,
+    /// The variable holding the current item, defaults to `item`
+    #[serde(rename = "as")]
+    pub item: Option<String>,
+    pub steps: Vec<MacroStep>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum MacroCondition {
+    /// True unless the value is empty, `false` or `0`
+    Value(String),
+    Match {
+        value: String,
+        matches: String,
+    },
+}
+
+impl MacroCondition {
+    pub fn eval(&self, variables: &IndexMap<String, String>) -> Result<bool> {
+        match self {
+            MacroCondition::Value(value) => {
+                let value = Macro::interpolate_command(value, variables);
+                let value = value.trim();
+                Ok(!(value.is_empty() || value == "false" || value == "0"))
+            }
+            MacroCondition::Match { value, matches } => {
+                let value = Macro::interpolate_command(value, variables);
+                let re = fancy_regex::Regex::new(matches)
+                    .with_context(|| format!("Invalid regex '{matches}'"))?;
+                Ok(re.is_match(&value)?)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelsOverride {
     pub version: String,
@@ -2733,6 +3642,14 @@ where
     Ok(())
 }
 
+fn parse_fusion_weights(value: &str) -> Result<[f32; 2]> {
+    let err = || anyhow!("Invalid value, expected <vector_weight>,<keyword_weight>");
+    let (vector_weight, keyword_weight) = value.split_once(',').ok_or_else(err)?;
+    let vector_weight = vector_weight.trim().parse().map_err(|_| err())?;
+    let keyword_weight = keyword_weight.trim().parse().map_err(|_| err())?;
+    Ok([vector_weight, keyword_weight])
+}
+
 fn format_option_value<T>(value: &Option<T>) -> String
 where
     T: std::fmt::Display,
@@ -2742,3 +3659,119 @@ where
         None => "null".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_parse_fusion_weights() {
+        assert_eq!(parse_fusion_weights("1.125,1").unwrap(), [1.125, 1.0]);
+        assert_eq!(parse_fusion_weights(" 0.5 , 2 ").unwrap(), [0.5, 2.0]);
+        assert!(parse_fusion_weights("1.0").is_err());
+        assert!(parse_fusion_weights("1.0,abc").is_err());
+        assert!(parse_fusion_weights("1.0,2.0,3.0").is_err());
+    }
+
+    /// A config whose chat model echoes the prompt back instead of calling an api
+    fn dry_run_config() -> GlobalConfig {
+        let clients = "- type: openai-compatible\n  name: stub\n  api_base: http://127.0.0.1:9";
+        let config = Config {
+            clients: serde_yaml::from_str(clients).unwrap(),
+            model: Model::new("stub", "chat"),
+            dry_run: true,
+            stream: false,
+            ..Default::default()
+        };
+        Arc::new(RwLock::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_run_macro_steps() {
+        let dir = temp_file("-macro-", "");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        let steps = r#"
+- foreach: "{{names}}"
+  as: name
+  command: review {{name}}
+  output: reviews
+  append: true
+- if: "{{reviews}}"
+  run: printf '%s' {{title}}
+  output: echoed
+- unless:
+    value: "{{echoed}}"
+    matches: "^it's"
+  write: "{{dir}}/skipped.md"
+  content: skipped
+- if: "0"
+  write: "{{dir}}/skipped.md"
+  content: skipped
+- foreach_file: "{{dir}}/*.txt"
+  write: "{{dir}}/files.md"
+  content: "- {{item}}"
+  append: true
+"#;
+        let macro_value: Macro = serde_yaml::from_str(&format!("steps: {steps}")).unwrap();
+        let dir_value = dir.display().to_string();
+        let mut variables: IndexMap<String, String> = [
+            ("names", "a.rs\n\nb.rs"),
+            ("title", "it's $(echo pwned) `id`"),
+            ("dir", &dir_value),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let config = dry_run_config();
+        run_macro_steps(
+            &config,
+            &macro_value.steps,
+            &mut variables,
+            create_abort_signal(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(variables["name"], "b.rs");
+        assert_eq!(variables["reviews"], "review a.rs\n\nreview b.rs");
+        if cfg!(unix) {
+            assert_eq!(variables["echoed"], "it's $(echo pwned) `id`");
+        }
+        assert!(!dir.join("skipped.md").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("files.md")).unwrap(),
+            format!(
+                "- {}\n- {}\n",
+                dir.join("a.txt").display(),
+                dir.join("b.txt").display()
+            )
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_macro_condition() {
+        let variables: IndexMap<String, String> = [("yes", "ok"), ("no", "false"), ("zero", "0")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let condition = |v: &str| serde_yaml::from_str::<MacroCondition>(v).unwrap();
+        assert!(condition("'{{yes}}'").eval(&variables).unwrap());
+        assert!(!condition("'{{no}}'").eval(&variables).unwrap());
+        assert!(!condition("'{{zero}}'").eval(&variables).unwrap());
+        assert!(!condition("'  '").eval(&variables).unwrap());
+        assert!(condition("{value: '{{yes}}', matches: '^o'}")
+            .eval(&variables)
+            .unwrap());
+        assert!(!condition("{value: '{{no}}', matches: '^o'}")
+            .eval(&variables)
+            .unwrap());
+        assert!(condition("{value: x, matches: '('}")
+            .eval(&variables)
+            .is_err());
+    }
+}
@@ -10,9 +10,15 @@ use serde_json::Value;
 use std::sync::LazyLock;
 
 pub const SHELL_ROLE: &str = "%shell%";
+pub const SHELL_PLAN_ROLE: &str = "%shell-plan%";
 pub const EXPLAIN_SHELL_ROLE: &str = "%explain-shell%";
 pub const CODE_ROLE: &str = "%code%";
 pub const CREATE_TITLE_ROLE: &str = "%create-title%";
+pub const REWRITE_QUERY_ROLE: &str = "%rewrite-query%";
+pub const MULTI_QUERY_ROLE: &str = "%multi-query%";
+pub const HYDE_ROLE: &str = "%hyde%";
+pub const CAPTION_IMAGE_ROLE: &str = "%caption-image%";
+pub const EXTRACT_MEMORY_ROLE: &str = "%extract-memory%";
 
 pub const INPUT_PLACEHOLDER: &str = "__INPUT__";
 
@@ -195,6 +201,11 @@ impl Role {
         &self.name
     }
 
+    /// Built-in helper roles like `%shell%` that aichat uses internally
+    pub fn is_internal(&self) -> bool {
+        self.name.starts_with('%')
+    }
+
     pub fn model_id(&self) -> Option<&str> {
         self.model_id.as_deref()
     }
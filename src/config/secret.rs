@@ -0,0 +1,198 @@
+use super::*;
+
+use anyhow::{Context, Result};
+use std::{fs, io::Write, process::Stdio};
+
+const SECRETS_FILE_NAME: &str = "secrets.enc";
+const SECRETS_KEY_FILE_NAME: &str = "secrets.key";
+const KEYRING_SERVICE: &str = env!("CARGO_CRATE_NAME");
+
+/// Look a secret up in the OS keyring, then in the encrypted secrets file
+pub fn load_secret(name: &str) -> Result<Option<String>> {
+    if let Some(value) = keyring_load(name) {
+        return Ok(Some(value));
+    }
+    Ok(load_secrets_file()?.shift_remove(name))
+}
+
+/// Store a secret in the OS keyring when one is available, otherwise in the encrypted secrets file
+pub fn save_secret(name: &str, value: &str) -> Result<()> {
+    if keyring_save(name, value) {
+        return Ok(());
+    }
+    let mut secrets = load_secrets_file()?;
+    if secrets.get(name).map(|v| v.as_str()) == Some(value) {
+        return Ok(());
+    }
+    secrets.insert(name.to_string(), value.to_string());
+    save_secrets_file(&secrets)
+}
+
+pub fn agent_secret_name(agent_name: &str, variable_name: &str) -> String {
+    format!("agent/{agent_name}/{variable_name}")
+}
+
+fn keyring_load(name: &str) -> Option<String> {
+    let (cmd, args): (&str, Vec<&str>) = if cfg!(target_os = "macos") {
+        (
+            "security",
+            vec![
+                "find-generic-password",
+                "-s",
+                KEYRING_SERVICE,
+                "-a",
+                name,
+                "-w",
+            ],
+        )
+    } else if cfg!(target_os = "linux") {
+        (
+            "secret-tool",
+            vec!["lookup", "service", KEYRING_SERVICE, "account", name],
+        )
+    } else {
+        return None;
+    };
+    which::which(cmd).ok()?;
+    let (success, stdout, _) = run_command_with_output(cmd, &args, None).ok()?;
+    let value = stdout.trim_end_matches(['\r', '\n']);
+    (success && !value.is_empty()).then(|| value.to_string())
+}
+
+fn keyring_save(name: &str, value: &str) -> bool {
+    let ret = if cfg!(target_os = "macos") {
+        which::which("security").is_ok() && security_store(name, value).unwrap_or_default()
+    } else if cfg!(target_os = "linux") {
+        which::which("secret-tool").is_ok() && secret_tool_store(name, value).unwrap_or_default()
+    } else {
+        false
+    };
+    if !ret {
+        debug!("No usable keyring, storing secret '{name}' in the secrets file");
+    }
+    ret
+}
+
+/// `security -i` reads the command from stdin, keeping the secret out of the process list.
+/// It exits with success even if the command fails, so the stored value is read back.
+fn security_store(name: &str, value: &str) -> Result<bool> {
+    let quote = |v: &str| format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""));
+    let command = format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        quote(KEYRING_SERVICE),
+        quote(name),
+        quote(value)
+    );
+    Ok(run_with_stdin("security", &["-i"], &command)?
+        && keyring_load(name).as_deref() == Some(value))
+}
+
+/// `secret-tool` reads the secret from stdin, keeping it out of the process list
+fn secret_tool_store(name: &str, value: &str) -> Result<bool> {
+    let label = format!("{KEYRING_SERVICE} {name}");
+    run_with_stdin(
+        "secret-tool",
+        &[
+            "store",
+            "--label",
+            &label,
+            "service",
+            KEYRING_SERVICE,
+            "account",
+            name,
+        ],
+        value,
+    )
+}
+
+fn run_with_stdin(cmd: &str, args: &[&str], input: &str) -> Result<bool> {
+    let mut child = std::process::Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    Ok(child.wait()?.success())
+}
+
+fn load_secrets_file() -> Result<IndexMap<String, String>> {
+    let path = Config::local_path(SECRETS_FILE_NAME);
+    if !path.exists() {
+        return Ok(IndexMap::new());
+    }
+    let key = load_secrets_key()?;
+    let data = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read secrets at '{}'", path.display()))?;
+    let data = base64_decode(data.trim())
+        .ok()
+        .and_then(|v| aes_gcm_decrypt(&key, &v).ok())
+        .ok_or_else(|| anyhow!("Failed to decrypt secrets at '{}'", path.display()))?;
+    let secrets = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to load secrets at '{}'", path.display()))?;
+    Ok(secrets)
+}
+
+fn save_secrets_file(secrets: &IndexMap<String, String>) -> Result<()> {
+    let path = Config::local_path(SECRETS_FILE_NAME);
+    let key = load_secrets_key()?;
+    let data = aes_gcm_encrypt(&key, &serde_json::to_vec(secrets)?)?;
+    write_private_file(&path, &base64_encode(data))
+}
+
+fn load_secrets_key() -> Result<Vec<u8>> {
+    let path = Config::local_path(SECRETS_KEY_FILE_NAME);
+    if path.exists() {
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read secrets key at '{}'", path.display()))?;
+        return base64_decode(data.trim())
+            .with_context(|| format!("Invalid secrets key at '{}'", path.display()));
+    }
+    let key = generate_aes_key();
+    write_private_file(&path, &base64_encode(&key))?;
+    Ok(key)
+}
+
+fn write_private_file(path: &Path, contents: &str) -> Result<()> {
+    ensure_parent_exists(path)?;
+    let err = || format!("Failed to write to '{}'", path.display());
+    let mut options = fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).with_context(err)?;
+    // The mode only applies on creation, an existing file is restricted before writing
+    #[cfg(unix)]
+    {
+        use std::os::unix::prelude::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_bytes()).with_context(err)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private_file() {
+        use std::os::unix::prelude::PermissionsExt;
+
+        let path = temp_file("-secret-", ".key");
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        write_private_file(&path, "first").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_private_file(&path, "second").unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        let _ = fs::remove_file(&path);
+    }
+}
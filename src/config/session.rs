@@ -1,21 +1,27 @@
 use super::input::*;
 use super::*;
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{Message, MessageContent, MessageContentPart, MessageRole};
 use crate::render::MarkdownRender;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fancy_regex::Regex;
 use inquire::{validator::Validation, Confirm, Text};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{read_to_string, write};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::LazyLock;
 
 static RE_AUTONAME_PREFIX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d{8}T\d{6}-").unwrap());
 
+const DEFAULT_BRANCH_NAME: &str = "main";
+
+/// A question and its answer, if any
+type Turn = (String, Option<String>);
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Session {
     #[serde(rename(serialize = "model", deserialize = "model"))]
@@ -30,6 +36,10 @@ pub struct Session {
     save_session: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     compress_threshold: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compress_strategy: Option<CompressStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compress_keep_tool_results: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     role_name: Option<String>,
@@ -41,8 +51,21 @@ pub struct Session {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     compressed_messages: Vec<Message>,
     messages: Vec<Message>,
+    /// The number of leading turns of `messages` that compression kept verbatim while
+    /// folding later turns; their originals sit in order in `compressed_messages`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    kept_turns: usize,
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     data_urls: HashMap<String, String>,
+    /// Hashes of the user messages whose turns are never compressed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pinned: Vec<String>,
+
+    /// The active branch, whose messages are the ones above
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    branch: String,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    branches: IndexMap<String, SessionBranch>,
 
     #[serde(skip)]
     model: Model,
@@ -143,6 +166,50 @@ impl Session {
         self.messages.iter().filter(|v| v.role.is_user()).count()
     }
 
+    pub fn chat_history(&self, limit: usize) -> Option<String> {
+        let messages: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|v| v.role.is_user() || v.role.is_assistant())
+            .collect();
+        if messages.is_empty() {
+            return None;
+        }
+        let start = messages.len().saturating_sub(limit);
+        let output = messages[start..]
+            .iter()
+            .map(|v| {
+                let role = if v.role.is_user() {
+                    "USER"
+                } else {
+                    "ASSISTANT"
+                };
+                format!("{role}: {}\n", v.content.to_text())
+            })
+            .collect();
+        Some(output)
+    }
+
+    /// Every message of the session in order, with the compressed turns in place of the summary
+    pub fn transcript(&self) -> Vec<Message> {
+        if self.compressed_messages.is_empty() {
+            return self.messages.clone();
+        }
+        let mut messages = self.compressed_messages.clone();
+        messages.extend(self.active_messages().iter().cloned());
+        messages
+    }
+
+    pub fn resolve_url(&self, url: &str) -> String {
+        resolve_data_url(&self.data_urls, url.to_string())
+    }
+
+    pub fn import_messages(&mut self, messages: Vec<Message>) {
+        self.messages = messages;
+        self.dirty = true;
+        self.update_tokens();
+    }
+
     pub fn export(&self) -> Result<String> {
         let mut data = json!({
             "path": self.path,
@@ -168,6 +235,10 @@ impl Session {
         if percent != 0.0 {
             data["total/max"] = format!("{percent}%").into();
         }
+        if !self.branches.is_empty() {
+            data["branch"] = self.branch().into();
+            data["branches"] = json!(self.branches.keys().collect::<Vec<_>>());
+        }
         data["messages"] = json!(self.messages);
 
         let output = serde_yaml::to_string(&data)
@@ -211,10 +282,25 @@ impl Session {
             items.push(("compress_threshold", compress_threshold.to_string()));
         }
 
+        if let Some(compress_strategy) = self.compress_strategy {
+            items.push(("compress_strategy", compress_strategy.to_string()));
+        }
+
+        if let Some(compress_keep_tool_results) = self.compress_keep_tool_results {
+            items.push((
+                "compress_keep_tool_results",
+                compress_keep_tool_results.to_string(),
+            ));
+        }
+
         if let Some(max_input_tokens) = self.model().max_input_tokens() {
             items.push(("max_input_tokens", max_input_tokens.to_string()));
         }
 
+        if !self.branches.is_empty() {
+            items.push(("branch", self.branch().to_string()));
+        }
+
         let mut lines: Vec<String> = items
             .iter()
             .map(|(name, value)| format!("{name:<20}{value}"))
@@ -287,10 +373,19 @@ impl Session {
     pub fn sync_agent(&mut self, agent: &Agent) {
         self.role_name = None;
         self.role_prompt = agent.interpolated_instructions();
-        self.agent_variables = agent.variables().clone();
+        self.agent_variables = agent.persistent_variables();
         self.agent_instructions = self.role_prompt.clone();
     }
 
+    /// Drop secret values that sessions saved before agent variables were typed
+    pub fn sync_agent_variables(&mut self, agent: &Agent) {
+        let variables = agent.persistent_variables();
+        if variables != self.agent_variables {
+            self.agent_variables = variables;
+            self.dirty = true;
+        }
+    }
+
     pub fn agent_variables(&self) -> &AgentVariables {
         &self.agent_variables
     }
@@ -317,6 +412,28 @@ impl Session {
         }
     }
 
+    pub fn compress_strategy(&self) -> Option<CompressStrategy> {
+        self.compress_strategy
+    }
+
+    pub fn set_compress_strategy(&mut self, value: Option<CompressStrategy>) {
+        if self.compress_strategy != value {
+            self.compress_strategy = value;
+            self.dirty = true;
+        }
+    }
+
+    pub fn compress_keep_tool_results(&self) -> Option<bool> {
+        self.compress_keep_tool_results
+    }
+
+    pub fn set_compress_keep_tool_results(&mut self, value: Option<bool>) {
+        if self.compress_keep_tool_results != value {
+            self.compress_keep_tool_results = value;
+            self.dirty = true;
+        }
+    }
+
     pub fn need_compress(&self, global_compress_threshold: usize) -> bool {
         if self.compressing {
             return false;
@@ -336,7 +453,54 @@ impl Session {
         self.compressing = compressing;
     }
 
-    pub fn compress(&mut self, mut prompt: String) {
+    /// The indexes of the active turns that compression folds into the summary.
+    /// Pinned turns, and tool call turns when asked, always stay verbatim.
+    pub fn compress_plan(&self, strategy: CompressStrategy, keep_tool_results: bool) -> Vec<usize> {
+        let (_, turns) = split_turns(&self.messages);
+        let candidates = turns.iter().enumerate().filter(|(_, turn)| {
+            let pinned = turn
+                .first()
+                .is_some_and(|v| self.pinned.contains(&message_hash(v)));
+            let has_tool_results = turn.iter().any(|v| v.role == MessageRole::Tool);
+            !(pinned || (keep_tool_results && has_tool_results))
+        });
+        match strategy {
+            CompressStrategy::Full => candidates.map(|(i, _)| i).collect(),
+            CompressStrategy::Rolling(n) => candidates.take(n).map(|(i, _)| i).collect(),
+            CompressStrategy::Window(k) => {
+                let end = turns.len().saturating_sub(k);
+                candidates.map(|(i, _)| i).filter(|i| *i < end).collect()
+            }
+        }
+    }
+
+    /// The chat of the planned turns, or `None` when the plan covers the whole session
+    pub fn compress_transcript(&self, plan: &[usize]) -> Option<String> {
+        let (_, turns) = split_turns(&self.messages);
+        if plan.len() == turns.len() {
+            return None;
+        }
+        let messages: Vec<Message> = plan
+            .iter()
+            .flat_map(|i| turns[*i].iter().cloned())
+            .collect();
+        let output = list_turns(&messages)
+            .into_iter()
+            .map(|(question, answer)| {
+                format!(
+                    "USER: {question}\nASSISTANT: {}\n",
+                    answer.unwrap_or_default()
+                )
+            })
+            .collect();
+        Some(output)
+    }
+
+    /// Move the planned turns into `compressed_messages` and put the summary in front of the rest
+    ///
+    /// Turns kept verbatim ahead of a compressed one are also copied into `compressed_messages`,
+    /// so the history keeps its order; `kept_turns` counts those copies.
+    pub fn compress(&mut self, mut prompt: String, plan: &[usize]) {
         if let Some(system_prompt) = self.messages.first().and_then(|v| {
             if MessageRole::System == v.role {
                 let content = v.content.to_text();
@@ -348,11 +512,30 @@ impl Session {
         }) {
             prompt = format!("{system_prompt}\n\n{prompt}",);
         }
-        self.compressed_messages.append(&mut self.messages);
+        let kept_turns = self.kept_turns;
+        let last_planned = plan.iter().max().copied();
+        // Every turn before this one that stays verbatim has its original in `compressed_messages`
+        let copied_until = last_planned
+            .map(|v| v + 1)
+            .unwrap_or_default()
+            .max(kept_turns);
+        self.kept_turns = (0..copied_until).filter(|v| !plan.contains(v)).count();
+        let messages = std::mem::take(&mut self.messages);
+        let (head, turns) = split_turns(&messages);
+        self.compressed_messages.extend_from_slice(head);
         self.messages.push(Message::new(
             MessageRole::System,
             MessageContent::Text(prompt),
         ));
+        for (index, turn) in turns.into_iter().enumerate() {
+            // Copies kept by an earlier compression are in `compressed_messages` already
+            if index >= kept_turns && last_planned.is_some_and(|v| index <= v) {
+                self.compressed_messages.extend_from_slice(turn);
+            }
+            if !plan.contains(&index) {
+                self.messages.extend_from_slice(turn);
+            }
+        }
         self.dirty = true;
         self.update_tokens();
     }
@@ -474,6 +657,12 @@ impl Session {
                 }
             }
         } else if input.regenerate() {
+            // Keep the previous answer on the current branch and continue on a sibling
+            let turns = self.total_turns();
+            self.fork_branch(None, turns)?;
+            if let Some(branch) = self.branches.get_mut(self.branch.as_str()) {
+                branch.fork_at = turns.saturating_sub(1);
+            }
             if let Some(message) = self.messages.last_mut() {
                 if let MessageContent::Text(text) = &mut message.content {
                     *text = output.to_string();
@@ -511,12 +700,365 @@ impl Session {
     pub fn clear_messages(&mut self) {
         self.messages.clear();
         self.compressed_messages.clear();
+        self.kept_turns = 0;
         self.data_urls.clear();
+        self.branch.clear();
+        self.branches.clear();
         self.autoname = None;
         self.dirty = true;
         self.update_tokens();
     }
 
+    pub fn branch(&self) -> &str {
+        if self.branch.is_empty() {
+            DEFAULT_BRANCH_NAME
+        } else {
+            &self.branch
+        }
+    }
+
+    pub fn list_branches(&self) -> Vec<String> {
+        if self.branches.is_empty() {
+            return vec![DEFAULT_BRANCH_NAME.to_string()];
+        }
+        self.branches.keys().cloned().collect()
+    }
+
+    /// Start a new branch holding the first `turns` turns of the active one and switch to it
+    pub fn fork_branch(&mut self, name: Option<&str>, turns: usize) -> Result<String> {
+        let total = self.total_turns();
+        if turns > total {
+            bail!("The branch '{}' only has {total} turns", self.branch());
+        }
+        let name = match name {
+            Some(name) => {
+                let name = name.trim();
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    bail!("Invalid branch name '{name}'");
+                }
+                if self.branches.contains_key(name) || name == self.branch() {
+                    bail!("Branch '{name}' already exists");
+                }
+                name.to_string()
+            }
+            None => (1..)
+                .map(|i| format!("branch-{i}"))
+                .find(|v| !self.branches.contains_key(v) && v != self.branch())
+                .unwrap_or_default(),
+        };
+        let (compressed_messages, messages, kept_turns) = self.truncated_messages(turns);
+        let parent = self.branch().to_string();
+        self.stash_branch();
+        self.branches.insert(
+            name.clone(),
+            SessionBranch {
+                parent: Some(parent),
+                fork_at: turns,
+                ..Default::default()
+            },
+        );
+        self.branch = name.clone();
+        self.messages = messages;
+        self.compressed_messages = compressed_messages;
+        self.kept_turns = kept_turns;
+        self.dirty = true;
+        self.update_tokens();
+        Ok(name)
+    }
+
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        if name == self.branch() {
+            return Ok(());
+        }
+        if !self.branches.contains_key(name) {
+            bail!("Unknown branch '{name}'");
+        }
+        self.stash_branch();
+        if let Some(branch) = self.branches.get_mut(name) {
+            self.messages = std::mem::take(&mut branch.messages);
+            self.compressed_messages = std::mem::take(&mut branch.compressed_messages);
+            self.kept_turns = std::mem::take(&mut branch.kept_turns);
+        }
+        self.branch = name.to_string();
+        self.dirty = true;
+        self.update_tokens();
+        Ok(())
+    }
+
+    pub fn render_branches(&self) -> String {
+        if self.branches.is_empty() {
+            return format!(
+                "* {DEFAULT_BRANCH_NAME} ({} turns)",
+                self.user_messages_len()
+            );
+        }
+        let mut lines = vec![];
+        self.render_branch_tree(None, 0, &mut lines);
+        lines.join("\n")
+    }
+
+    /// Show the turns each branch has after the point where they diverge
+    pub fn diff_branches(&self, name: &str, other: Option<&str>) -> Result<String> {
+        let (name, other) = match other {
+            Some(other) => (name, other),
+            None => (self.branch(), name),
+        };
+        let messages = self.branch_messages(name)?;
+        let other_messages = self.branch_messages(other)?;
+        let common = messages
+            .iter()
+            .zip(other_messages.iter())
+            .take_while(|(a, b)| json!(a) == json!(b))
+            .count();
+        let shared_turns = messages[..common]
+            .iter()
+            .filter(|v| v.role.is_user())
+            .count();
+        let mut lines = vec![format!(
+            "{shared_turns} turns shared by '{name}' and '{other}'"
+        )];
+        for (branch, messages) in [(name, messages), (other, other_messages)] {
+            lines.push(String::new());
+            lines.push(format!("@@ {branch}"));
+            let rest = &messages[common..];
+            if rest.is_empty() {
+                lines.push("(no more messages)".into());
+            }
+            for message in rest {
+                let text = message.content.to_text();
+                match message.role {
+                    MessageRole::User => lines.push(format!(">> {text}")),
+                    MessageRole::Assistant => lines.push(text),
+                    _ => {}
+                }
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Turns are counted across compressed and active messages, starting at 1
+    pub fn total_turns(&self) -> usize {
+        count_turns(&self.compressed_messages) + count_turns(self.active_messages())
+    }
+
+    pub fn render_history(&self) -> String {
+        let compressed_turns = count_turns(&self.compressed_messages);
+        let mut lines = vec![];
+        for (index, (question, answer)) in self.list_turns().into_iter().enumerate() {
+            let mut line = format!("{:>3}. {}", index + 1, truncate_prompt(&question));
+            if self.pinned.contains(&sha256(&question)) {
+                line.push_str(&dimmed_text(" (pinned)"));
+            } else if index < compressed_turns {
+                line.push_str(&dimmed_text(" (compressed)"));
+            }
+            lines.push(line);
+            if let Some(answer) = answer {
+                lines.push(dimmed_text(&format!("     {}", truncate_prompt(&answer))));
+            }
+        }
+        if lines.is_empty() {
+            return "No messages".into();
+        }
+        lines.join("\n")
+    }
+
+    pub fn complete_turns(&self) -> Vec<(String, Option<String>)> {
+        self.list_turns()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (question, _))| ((i + 1).to_string(), Some(truncate_prompt(&question))))
+            .collect()
+    }
+
+    /// The question and the last answer of each turn
+    pub fn list_turns(&self) -> Vec<(String, Option<String>)> {
+        let mut turns = list_turns(&self.compressed_messages);
+        turns.extend(list_turns(self.active_messages()));
+        turns
+    }
+
+    /// The turns of every branch, the active one first
+    pub fn list_branch_turns(&self) -> Vec<(String, Vec<Turn>)> {
+        let mut output = vec![(self.branch().to_string(), self.list_turns())];
+        for (name, branch) in &self.branches {
+            if name != self.branch() {
+                let messages = &branch.messages;
+                let mut turns = list_turns(&branch.compressed_messages);
+                turns.extend(list_turns(
+                    &messages[turn_start_index(messages, branch.kept_turns)..],
+                ));
+                output.push((name.clone(), turns));
+            }
+        }
+        output
+    }
+
+    pub fn render_turn(&self, turn: usize) -> Result<String> {
+        self.guard_turn(turn)?;
+        let (question, answer) = self.list_turns().swap_remove(turn - 1);
+        Ok(format!(">> {question}\n{}", answer.unwrap_or_default()))
+    }
+
+    pub fn pin_turn(&mut self, turn: usize, pinned: bool) -> Result<()> {
+        self.guard_turn(turn)?;
+        let hash = self
+            .compressed_messages
+            .iter()
+            .chain(self.active_messages())
+            .filter(|v| v.role.is_user())
+            .nth(turn - 1)
+            .map(message_hash)
+            .unwrap_or_default();
+        if pinned && !self.pinned.contains(&hash) {
+            self.pinned.push(hash);
+        } else if !pinned {
+            self.pinned.retain(|v| v != &hash);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn user_message(&self, turn: usize) -> Result<String> {
+        self.guard_turn(turn)?;
+        let message = self
+            .compressed_messages
+            .iter()
+            .chain(self.active_messages())
+            .filter(|v| v.role.is_user())
+            .nth(turn - 1);
+        Ok(message.map(|v| v.content.to_text()).unwrap_or_default())
+    }
+
+    /// Keep the first `turn` turns, undoing compressions that happened after them
+    pub fn rewind(&mut self, turn: usize) -> Result<()> {
+        if turn != 0 {
+            self.guard_turn(turn)?;
+        }
+        (self.compressed_messages, self.messages, self.kept_turns) = self.truncated_messages(turn);
+        self.prune_data_urls();
+        self.dirty = true;
+        self.update_tokens();
+        Ok(())
+    }
+
+    pub fn drop_turn(&mut self, turn: usize) -> Result<()> {
+        self.guard_turn(turn)?;
+        let compressed_turns = count_turns(&self.compressed_messages);
+        if turn <= compressed_turns {
+            bail!("Turn {turn} is part of the compressed summary, use `.rewind` instead");
+        }
+        let start = turn_start_index(
+            &self.messages,
+            turn - compressed_turns - 1 + self.kept_turns,
+        );
+        let end = turn_start_index(&self.messages, turn - compressed_turns + self.kept_turns);
+        self.messages.drain(start..end);
+        self.prune_data_urls();
+        self.dirty = true;
+        self.update_tokens();
+        Ok(())
+    }
+
+    fn guard_turn(&self, turn: usize) -> Result<()> {
+        let total = self.total_turns();
+        if turn == 0 || turn > total {
+            bail!("Invalid turn {turn}, the session has {total} turns");
+        }
+        Ok(())
+    }
+
+    /// The compressed messages, active messages and kept turns of the first `turns` turns
+    fn truncated_messages(&self, turns: usize) -> (Vec<Message>, Vec<Message>, usize) {
+        let compressed_turns = count_turns(&self.compressed_messages);
+        if turns >= compressed_turns {
+            let index =
+                turn_start_index(&self.messages, turns - compressed_turns + self.kept_turns);
+            return (
+                self.compressed_messages.clone(),
+                self.messages[..index].to_vec(),
+                self.kept_turns,
+            );
+        }
+        // Every compression but the first moves a summary into `compressed_messages`;
+        // the turns after the last one of them become the active messages again
+        let index = turn_start_index(&self.compressed_messages, turns);
+        let split = self.compressed_messages[..index]
+            .iter()
+            .rposition(|v| v.role == MessageRole::System)
+            .filter(|v| *v > 0)
+            .unwrap_or_default();
+        (
+            self.compressed_messages[..split].to_vec(),
+            self.compressed_messages[split..index].to_vec(),
+            0,
+        )
+    }
+
+    /// The active messages after the copies of kept turns
+    fn active_messages(&self) -> &[Message] {
+        &self.messages[turn_start_index(&self.messages, self.kept_turns)..]
+    }
+
+    fn prune_data_urls(&mut self) {
+        let mut hashes = HashSet::new();
+        let branches = self.branches.values();
+        let messages = self
+            .compressed_messages
+            .iter()
+            .chain(self.messages.iter())
+            .chain(branches.flat_map(|v| v.compressed_messages.iter().chain(v.messages.iter())));
+        for message in messages {
+            if let MessageContent::Array(parts) = &message.content {
+                for part in parts {
+                    if let MessageContentPart::ImageUrl { image_url } = part {
+                        hashes.insert(sha256(&image_url.url));
+                    }
+                }
+            }
+        }
+        self.data_urls.retain(|k, _| hashes.contains(k));
+    }
+
+    fn stash_branch(&mut self) {
+        let branch = self.branches.entry(self.branch().to_string()).or_default();
+        branch.messages = std::mem::take(&mut self.messages);
+        branch.compressed_messages = std::mem::take(&mut self.compressed_messages);
+        branch.kept_turns = std::mem::take(&mut self.kept_turns);
+    }
+
+    fn branch_messages(&self, name: &str) -> Result<&[Message]> {
+        if name == self.branch() {
+            return Ok(&self.messages);
+        }
+        match self.branches.get(name) {
+            Some(branch) => Ok(&branch.messages),
+            None => bail!("Unknown branch '{name}'"),
+        }
+    }
+
+    fn render_branch_tree(&self, parent: Option<&str>, depth: usize, lines: &mut Vec<String>) {
+        for (name, branch) in &self.branches {
+            if branch.parent.as_deref() != parent {
+                continue;
+            }
+            let marker = if name == self.branch() { "*" } else { " " };
+            let turns = self
+                .branch_messages(name)
+                .map(|v| v.iter().filter(|v| v.role.is_user()).count())
+                .unwrap_or_default();
+            let mut line = format!("{marker} {}{name} ({turns} turns", "  ".repeat(depth));
+            if let Some(parent) = &branch.parent {
+                line.push_str(&format!(
+                    ", forked from {parent} at turn {}",
+                    branch.fork_at
+                ));
+            }
+            line.push(')');
+            lines.push(line);
+            self.render_branch_tree(Some(name), depth + 1, lines);
+        }
+    }
+
     pub fn echo_messages(&self, input: &Input) -> String {
         let messages = self.build_messages(input);
         serde_yaml::to_string(&messages).unwrap_or_else(|_| "Unable to echo message".into())
@@ -612,6 +1154,137 @@ impl RoleLike for Session {
     }
 }
 
+/// A branch other than the active one; the active branch only keeps its place in the tree here
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct SessionBranch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// The number of turns shared with the parent branch
+    #[serde(default)]
+    fork_at: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    compressed_messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    kept_turns: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CompressStrategy {
+    /// Summarize the whole session
+    #[default]
+    Full,
+    /// Summarize only the oldest N turns
+    Rolling(usize),
+    /// Keep the last K turns verbatim and summarize the rest
+    Window(usize),
+}
+
+impl std::fmt::Display for CompressStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressStrategy::Full => write!(f, "full"),
+            CompressStrategy::Rolling(n) => write!(f, "rolling:{n}"),
+            CompressStrategy::Window(k) => write!(f, "window:{k}"),
+        }
+    }
+}
+
+impl FromStr for CompressStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err =
+            || anyhow!("Invalid compress strategy '{s}', expected full, rolling:<n> or window:<k>");
+        let strategy = match s.split_once(':') {
+            None if s == "full" => CompressStrategy::Full,
+            Some((kind, value)) => {
+                let value: usize = value.parse().map_err(|_| err())?;
+                match kind {
+                    "rolling" if value > 0 => CompressStrategy::Rolling(value),
+                    "window" => CompressStrategy::Window(value),
+                    _ => return Err(err()),
+                }
+            }
+            _ => return Err(err()),
+        };
+        Ok(strategy)
+    }
+}
+
+impl TryFrom<String> for CompressStrategy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CompressStrategy> for String {
+    fn from(value: CompressStrategy) -> Self {
+        value.to_string()
+    }
+}
+
+fn message_hash(message: &Message) -> String {
+    sha256(&message.content.to_text())
+}
+
+/// Split the messages into the leading system messages and the turns that follow
+fn split_turns(messages: &[Message]) -> (&[Message], Vec<&[Message]>) {
+    let starts: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| v.role.is_user())
+        .map(|(i, _)| i)
+        .collect();
+    let head = &messages[..starts.first().copied().unwrap_or(messages.len())];
+    let turns = starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| &messages[*start..starts.get(i + 1).copied().unwrap_or(messages.len())])
+        .collect();
+    (head, turns)
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn count_turns(messages: &[Message]) -> usize {
+    messages.iter().filter(|v| v.role.is_user()).count()
+}
+
+/// Pair each user message with the last assistant answer that follows it
+fn list_turns(messages: &[Message]) -> Vec<(String, Option<String>)> {
+    let mut turns: Vec<(String, Option<String>)> = vec![];
+    for message in messages {
+        match message.role {
+            MessageRole::User => turns.push((message.content.to_text(), None)),
+            MessageRole::Assistant => {
+                if let Some(turn) = turns.last_mut() {
+                    turn.1 = Some(message.content.to_text());
+                }
+            }
+            _ => {}
+        }
+    }
+    turns
+}
+
+/// The index of the message that starts turn `turn`, counting turns by user messages
+fn turn_start_index(messages: &[Message], turn: usize) -> usize {
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| v.role.is_user())
+        .nth(turn)
+        .map(|(i, _)| i)
+        .unwrap_or(messages.len())
+}
+
 #[derive(Debug, Clone, Default)]
 struct AutoName {
     naming: bool,
@@ -636,3 +1309,161 @@ impl AutoName {
         !self.naming && self.chat_history.is_some() && self.name.is_none()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_message(role: MessageRole, text: &str) -> Message {
+        Message::new(role, MessageContent::Text(text.into()))
+    }
+
+    #[test]
+    fn test_session_branches() {
+        let mut session = Session {
+            messages: vec![
+                text_message(MessageRole::System, "sys"),
+                text_message(MessageRole::User, "q1"),
+                text_message(MessageRole::Assistant, "a1"),
+                text_message(MessageRole::User, "q2"),
+                text_message(MessageRole::Assistant, "a2"),
+            ],
+            ..Default::default()
+        };
+        let name = session.fork_branch(None, 1).unwrap();
+        assert_eq!(name, "branch-1");
+        assert_eq!(session.messages.len(), 3);
+        session
+            .messages
+            .push(text_message(MessageRole::User, "q2'"));
+        session.switch_branch("main").unwrap();
+        assert_eq!(session.user_messages_len(), 2);
+        let diff = session.diff_branches("branch-1", None).unwrap();
+        assert!(diff.starts_with("1 turns shared by 'main' and 'branch-1'"));
+        assert!(diff.contains(">> q2'"));
+        assert!(session.fork_branch(Some("branch-1"), 0).is_err());
+        let data = serde_yaml::to_string(&session).unwrap();
+        let session: Session = serde_yaml::from_str(&data).unwrap();
+        assert_eq!(session.list_branches(), ["main", "branch-1"]);
+    }
+
+    #[test]
+    fn test_session_rewind_and_drop() {
+        let mut session = Session {
+            compressed_messages: vec![
+                text_message(MessageRole::System, "sys"),
+                text_message(MessageRole::User, "q1"),
+                text_message(MessageRole::Assistant, "a1"),
+                text_message(MessageRole::System, "summary1"),
+                text_message(MessageRole::User, "q2"),
+                text_message(MessageRole::Assistant, "a2"),
+            ],
+            messages: vec![
+                text_message(MessageRole::System, "summary2"),
+                text_message(MessageRole::User, "q3"),
+                text_message(MessageRole::Assistant, "a3"),
+                text_message(MessageRole::User, "q4"),
+                text_message(MessageRole::Assistant, "a4"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(session.total_turns(), 4);
+        assert_eq!(session.user_message(2).unwrap(), "q2");
+        assert!(session.drop_turn(2).is_err());
+        session.drop_turn(3).unwrap();
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.user_message(3).unwrap(), "q4");
+        session.rewind(1).unwrap();
+        assert_eq!(session.compressed_messages.len(), 3);
+        assert_eq!(session.messages[0].content.to_text(), "summary1");
+        assert_eq!(session.total_turns(), 1);
+        assert!(session.rewind(2).is_err());
+    }
+
+    #[test]
+    fn test_session_compress_plan() {
+        let mut session = Session {
+            messages: vec![text_message(MessageRole::System, "sys")],
+            ..Default::default()
+        };
+        for i in 1..=4 {
+            session
+                .messages
+                .push(text_message(MessageRole::User, &format!("q{i}")));
+            session
+                .messages
+                .push(text_message(MessageRole::Assistant, &format!("a{i}")));
+        }
+        session.pin_turn(2, true).unwrap();
+        assert_eq!(
+            session.compress_plan(CompressStrategy::Full, false),
+            [0, 2, 3]
+        );
+        assert_eq!(
+            session.compress_plan(CompressStrategy::Rolling(2), false),
+            [0, 2]
+        );
+        let plan = session.compress_plan(CompressStrategy::Window(2), false);
+        assert_eq!(plan, [0]);
+        assert!(session
+            .compress_transcript(&plan)
+            .unwrap()
+            .starts_with("USER: q1\nASSISTANT: a1"));
+        session.compress("summary".into(), &plan);
+        assert_eq!(session.compressed_messages.len(), 3);
+        assert_eq!(session.messages[0].content.to_text(), "sys\n\nsummary");
+        assert_eq!(session.user_message(2).unwrap(), "q2");
+        assert_eq!(session.total_turns(), 4);
+        assert_eq!(
+            "window:3".parse::<CompressStrategy>().unwrap(),
+            CompressStrategy::Window(3)
+        );
+        assert!("rolling:0".parse::<CompressStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_session_compress_keeps_order() {
+        let mut session = Session::default();
+        let add_turn = |session: &mut Session, i: usize| {
+            session
+                .messages
+                .push(text_message(MessageRole::User, &format!("q{i}")));
+            session
+                .messages
+                .push(text_message(MessageRole::Assistant, &format!("a{i}")));
+        };
+        for i in 1..=4 {
+            add_turn(&mut session, i);
+        }
+        let questions = |session: &Session| -> Vec<String> {
+            session.list_turns().into_iter().map(|(q, _)| q).collect()
+        };
+        session.pin_turn(2, true).unwrap();
+        let plan = session.compress_plan(CompressStrategy::Rolling(2), false);
+        assert_eq!(plan, [0, 2]);
+        session.compress("summary1".into(), &plan);
+        assert_eq!(questions(&session), ["q1", "q2", "q3", "q4"]);
+        assert_eq!(session.messages.len(), 5);
+        assert_eq!(session.user_message(4).unwrap(), "q4");
+        assert!(session.drop_turn(2).is_err());
+
+        add_turn(&mut session, 5);
+        let plan = session.compress_plan(CompressStrategy::Full, false);
+        assert_eq!(plan, [1, 2]);
+        session.compress("summary2".into(), &plan);
+        assert_eq!(questions(&session), ["q1", "q2", "q3", "q4", "q5"]);
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.transcript().len(), 11);
+
+        // A repeat of a compressed turn is a new turn, not a kept copy
+        add_turn(&mut session, 1);
+        assert_eq!(session.total_turns(), 6);
+        assert_eq!(session.user_message(6).unwrap(), "q1");
+        let data = serde_yaml::to_string(&session).unwrap();
+        let mut session: Session = serde_yaml::from_str(&data).unwrap();
+        assert_eq!(session.total_turns(), 6);
+        session.drop_turn(6).unwrap();
+        assert_eq!(session.total_turns(), 5);
+        assert_eq!(session.messages.len(), 3);
+    }
+}
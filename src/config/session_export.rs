@@ -0,0 +1,555 @@
+use super::*;
+
+use crate::client::{
+    openai_build_chat_completions_body, ChatCompletionsData, Message, MessageContent,
+    MessageContentPart, MessageContentToolCalls, MessageRole, ToolCall,
+};
+use crate::function::ToolResult;
+use crate::render::HtmlRender;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::str::FromStr;
+use syntect::highlighting::Theme;
+use zip::ZipArchive;
+
+const HTML_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>__TITLE__</title>
+<style>
+body { max-width: 50rem; margin: 2rem auto; padding: 0 1rem; font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Helvetica, Arial, sans-serif; line-height: 1.6; color: #24292f; }
+h2 { border-bottom: 1px solid #d0d7de; padding-bottom: 0.3rem; }
+pre { padding: 0.75rem 1rem; border-radius: 6px; overflow-x: auto; }
+code { font-family: ui-monospace, SFMono-Regular, Menlo, Consolas, monospace; font-size: 0.9em; }
+img { max-width: 100%; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #d0d7de; padding: 0.25rem 0.5rem; }
+details { margin: 0.5rem 0; }
+</style>
+</head>
+<body>
+__BODY__
+</body>
+</html>
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    Json,
+    Jsonl,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            "json" => Ok(ExportFormat::Json),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            _ => bail!("Invalid export format '{s}', expected md, html, json or jsonl"),
+        }
+    }
+}
+
+pub fn export_session(config: &Config, name: &str, format: ExportFormat) -> Result<String> {
+    let path = config.session_file(name);
+    if !path.exists() {
+        bail!("Unknown session '{name}'");
+    }
+    let session = Session::load(config, name, &path)?;
+    let output = match format {
+        ExportFormat::Markdown => render_markdown(&session, name),
+        ExportFormat::Html => {
+            let theme: Theme = decode_bin(LIGHT_THEME).context("Invalid builtin light theme")?;
+            let body = render_html(&session, name, theme)?;
+            HTML_TEMPLATE
+                .replace("__TITLE__", &escape_html(name))
+                .replace("__BODY__", body.trim_end())
+        }
+        ExportFormat::Json => {
+            let data = json!({
+                "name": name,
+                "model": session.model().id(),
+                "messages": session.transcript(),
+            });
+            format!("{}\n", serde_json::to_string_pretty(&data)?)
+        }
+        ExportFormat::Jsonl => {
+            let body = openai_build_chat_completions_body(
+                ChatCompletionsData {
+                    messages: session.transcript(),
+                    temperature: None,
+                    top_p: None,
+                    functions: None,
+                    stream: false,
+                },
+                session.model(),
+            );
+            format!("{}\n", json!({ "messages": body["messages"] }))
+        }
+    };
+    Ok(output)
+}
+
+/// Import every conversation of a JSON/JSONL file or a ChatGPT export, returning the saved session names
+pub fn import_sessions(config: &Config, path: &Path) -> Result<Vec<String>> {
+    let stem = path
+        .file_stem()
+        .map(|v| v.to_string_lossy().to_string())
+        .unwrap_or_else(|| "imported".into());
+    let conversations = read_conversations(path)
+        .with_context(|| format!("Failed to import sessions from '{}'", path.display()))?;
+    let multiple = conversations.len() > 1;
+    let mut names: HashSet<String> = config.list_sessions().into_iter().collect();
+    let mut imported = vec![];
+    for (index, conversation) in conversations.into_iter().enumerate() {
+        if conversation.messages.is_empty() {
+            continue;
+        }
+        let base = conversation
+            .name
+            .as_deref()
+            .map(sanitize_session_name)
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| {
+                if multiple {
+                    format!("{stem}-{}", index + 1)
+                } else {
+                    stem.clone()
+                }
+            });
+        let mut name = base.clone();
+        let mut suffix = 2;
+        while names.contains(&name) {
+            name = format!("{base}-{suffix}");
+            suffix += 1;
+        }
+        let mut session = Session::new(config, &name);
+        if let Some(model) = conversation
+            .model
+            .and_then(|v| Model::retrieve_model(config, &v, ModelType::Chat).ok())
+        {
+            session.set_model(model);
+        }
+        session.import_messages(conversation.messages);
+        session.save(&name, &config.session_file(&name), false)?;
+        names.insert(name.clone());
+        imported.push(name);
+    }
+    if imported.is_empty() {
+        bail!("No conversations found in '{}'", path.display());
+    }
+    Ok(imported)
+}
+
+#[derive(Debug, Default)]
+struct Conversation {
+    name: Option<String>,
+    model: Option<String>,
+    messages: Vec<Message>,
+}
+
+fn read_conversations(path: &Path) -> Result<Vec<Conversation>> {
+    let is_zip = path
+        .extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("zip"));
+    if is_zip {
+        let data = std::fs::read(path)?;
+        let mut archive = ZipArchive::new(Cursor::new(data)).context("Invalid zip archive")?;
+        let mut file = archive
+            .by_name("conversations.json")
+            .context("Missing 'conversations.json', not a ChatGPT export")?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        return parse_json(&serde_json::from_str(&contents)?);
+    }
+    let contents = read_to_string(path)?;
+    if path
+        .extension()
+        .is_some_and(|v| v.eq_ignore_ascii_case("jsonl"))
+    {
+        let mut conversations = vec![];
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = serde_json::from_str(line)
+                .with_context(|| format!("Invalid JSON at line {}", index + 1))?;
+            conversations.extend(parse_json(&value)?);
+        }
+        return Ok(conversations);
+    }
+    parse_json(&serde_json::from_str(&contents)?)
+}
+
+/// Accept an exported session, an OpenAI `{"messages": [...]}` object, a bare messages array
+/// or ChatGPT conversations
+fn parse_json(value: &Value) -> Result<Vec<Conversation>> {
+    match value {
+        Value::Array(list) if list.iter().any(|v| v.get("mapping").is_some()) => {
+            Ok(list.iter().filter_map(parse_chatgpt_conversation).collect())
+        }
+        Value::Array(list) => Ok(vec![Conversation {
+            messages: parse_messages(list)?,
+            ..Default::default()
+        }]),
+        Value::Object(_) if value.get("mapping").is_some() => {
+            Ok(parse_chatgpt_conversation(value).into_iter().collect())
+        }
+        Value::Object(_) => {
+            let messages = value["messages"]
+                .as_array()
+                .ok_or_else(|| anyhow!("Missing 'messages'"))?;
+            Ok(vec![Conversation {
+                name: value["name"].as_str().map(|v| v.to_string()),
+                model: value["model"].as_str().map(|v| v.to_string()),
+                messages: parse_messages(messages)?,
+            }])
+        }
+        _ => bail!("Unsupported JSON, expected an object or an array"),
+    }
+}
+
+/// Parse aichat messages as well as OpenAI chat messages with `tool_calls`
+fn parse_messages(list: &[Value]) -> Result<Vec<Message>> {
+    let mut messages = vec![];
+    let mut index = 0;
+    while index < list.len() {
+        let value = &list[index];
+        index += 1;
+        if let Some(tool_calls) = value["tool_calls"].as_array() {
+            let mut tool_results = vec![];
+            for tool_call in tool_calls {
+                let id = tool_call["id"].as_str().map(|v| v.to_string());
+                let output = list[index..]
+                    .iter()
+                    .take_while(|v| v["role"] == "tool")
+                    .find(|v| v["tool_call_id"].as_str() == id.as_deref())
+                    .map(|v| parse_json_text(&v["content"]))
+                    .unwrap_or(Value::Null);
+                let call = ToolCall::new(
+                    tool_call["function"]["name"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    parse_json_text(&tool_call["function"]["arguments"]),
+                    id,
+                );
+                tool_results.push(ToolResult::new(call, output));
+            }
+            while index < list.len() && list[index]["role"] == "tool" {
+                index += 1;
+            }
+            let text = value["content"].as_str().unwrap_or_default().to_string();
+            messages.push(Message::new(
+                MessageRole::Assistant,
+                MessageContent::ToolCalls(MessageContentToolCalls::new(tool_results, text)),
+            ));
+            continue;
+        }
+        let mut value = value.clone();
+        if value["role"] == "developer" {
+            value["role"] = "system".into();
+        }
+        if value["content"].is_null() {
+            continue;
+        }
+        let message: Message = serde_json::from_value(value)
+            .with_context(|| format!("Invalid message at index {}", index - 1))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Follow a ChatGPT conversation from its current node back to the root
+fn parse_chatgpt_conversation(value: &Value) -> Option<Conversation> {
+    let mapping = value["mapping"].as_object()?;
+    let mut node_id = value["current_node"].as_str();
+    let mut messages = vec![];
+    for _ in 0..mapping.len() {
+        let Some(node) = node_id.and_then(|v| mapping.get(v)) else {
+            break;
+        };
+        if let Some(message) = parse_chatgpt_message(&node["message"]) {
+            messages.push(message);
+        }
+        node_id = node["parent"].as_str();
+    }
+    messages.reverse();
+    Some(Conversation {
+        name: value["title"].as_str().map(|v| v.to_string()),
+        model: None,
+        messages,
+    })
+}
+
+fn parse_chatgpt_message(value: &Value) -> Option<Message> {
+    let role = match value["author"]["role"].as_str()? {
+        "system" => MessageRole::System,
+        "user" => MessageRole::User,
+        "assistant" => MessageRole::Assistant,
+        _ => return None,
+    };
+    let text = value["content"]["parts"]
+        .as_array()?
+        .iter()
+        .filter_map(|v| v.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(Message::new(role, MessageContent::Text(text)))
+}
+
+enum ExportBlock {
+    Markdown(String),
+    ToolCall(ToolResult),
+}
+
+/// The titled sections of the transcript, skipping empty messages
+fn export_sections(session: &Session) -> Vec<(&'static str, Vec<ExportBlock>)> {
+    let mut sections = vec![];
+    for message in session.transcript() {
+        let title = match message.role {
+            MessageRole::System => "System",
+            MessageRole::User => "User",
+            MessageRole::Assistant => "Assistant",
+            MessageRole::Tool => "Tool",
+        };
+        let mut blocks = vec![];
+        match message.content {
+            MessageContent::Text(text) => blocks.push(ExportBlock::Markdown(text)),
+            MessageContent::Array(list) => {
+                for item in list {
+                    blocks.push(ExportBlock::Markdown(match item {
+                        MessageContentPart::Text { text } => text,
+                        MessageContentPart::ImageUrl { image_url } => {
+                            format!("![image]({})", session.resolve_url(&image_url.url))
+                        }
+                    }))
+                }
+            }
+            MessageContent::ToolCalls(MessageContentToolCalls {
+                tool_results, text, ..
+            }) => {
+                blocks.push(ExportBlock::Markdown(text));
+                blocks.extend(tool_results.into_iter().map(ExportBlock::ToolCall));
+            }
+        }
+        blocks.retain(|v| !matches!(v, ExportBlock::Markdown(text) if text.trim().is_empty()));
+        if !blocks.is_empty() {
+            sections.push((title, blocks));
+        }
+    }
+    sections
+}
+
+fn export_header(session: &Session, name: &str) -> String {
+    let mut output = format!("# {name}\n\n- Model: `{}`\n", session.model().id());
+    if let Some(role) = session.role_name() {
+        output.push_str(&format!("- Role: `{role}`\n"));
+    }
+    output
+}
+
+fn render_markdown(session: &Session, name: &str) -> String {
+    let mut output = export_header(session, name);
+    for (title, blocks) in export_sections(session) {
+        let parts: Vec<String> = blocks
+            .iter()
+            .map(|block| match block {
+                ExportBlock::Markdown(text) => text.trim().to_string(),
+                ExportBlock::ToolCall(tool_result) => format!(
+                    "<details>\n<summary>Call <code>{}</code></summary>\n\n{}\n\n{}\n\n</details>",
+                    escape_html(&tool_result.call.name),
+                    json_block(&tool_result.call.arguments),
+                    json_block(&tool_result.output),
+                ),
+            })
+            .collect();
+        output.push_str(&format!("\n## {title}\n\n{}\n", parts.join("\n\n")));
+    }
+    output
+}
+
+/// Render the chat content as escaped markdown and wrap the tool calls in `<details>`
+fn render_html(session: &Session, name: &str, theme: Theme) -> Result<String> {
+    let render = HtmlRender::init(theme)?;
+    let mut output = render.render(&export_header(session, name))?;
+    for (title, blocks) in export_sections(session) {
+        output.push_str(&format!("<h2>{title}</h2>\n"));
+        for block in blocks {
+            match block {
+                ExportBlock::Markdown(text) => output.push_str(&render.render(&text)?),
+                ExportBlock::ToolCall(tool_result) => output.push_str(&format!(
+                    "<details>\n<summary>Call <code>{}</code></summary>\n{}{}</details>\n",
+                    escape_html(&tool_result.call.name),
+                    render.render(&json_block(&tool_result.call.arguments))?,
+                    render.render(&json_block(&tool_result.output))?,
+                )),
+            }
+        }
+    }
+    Ok(output)
+}
+
+fn json_block(value: &Value) -> String {
+    let text = serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string());
+    format!("```json\n{text}\n```")
+}
+
+/// Tool arguments and outputs travel as JSON strings in the OpenAI format
+fn parse_json_text(value: &Value) -> Value {
+    match value.as_str() {
+        Some(text) => serde_json::from_str(text).unwrap_or_else(|_| text.into()),
+        None => value.clone(),
+    }
+}
+
+fn sanitize_session_name(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    name.split('-')
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let chatgpt = json!([{
+            "title": "Rust lifetimes?",
+            "current_node": "c",
+            "mapping": {
+                "a": { "message": null, "parent": null },
+                "b": { "message": { "author": { "role": "user" }, "content": { "parts": ["hi"] } }, "parent": "a" },
+                "x": { "message": { "author": { "role": "assistant" }, "content": { "parts": ["old"] } }, "parent": "b" },
+                "c": { "message": { "author": { "role": "assistant" }, "content": { "parts": ["hello"] } }, "parent": "b" }
+            }
+        }]);
+        let conversations = parse_json(&chatgpt).unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(
+            sanitize_session_name(conversations[0].name.as_deref().unwrap()),
+            "Rust-lifetimes"
+        );
+        let texts: Vec<String> = conversations[0]
+            .messages
+            .iter()
+            .map(|v| v.content.to_text())
+            .collect();
+        assert_eq!(texts, ["hi", "hello"]);
+
+        let openai = json!({ "messages": [
+            { "role": "user", "content": "weather?" },
+            { "role": "assistant", "content": null, "tool_calls": [
+                { "id": "call_1", "type": "function", "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" } }
+            ] },
+            { "role": "tool", "tool_call_id": "call_1", "content": "{\"temp\":20}" },
+            { "role": "assistant", "content": "20°C" }
+        ] });
+        let messages = parse_json(&openai).unwrap().remove(0).messages;
+        assert_eq!(messages.len(), 3);
+        let MessageContent::ToolCalls(tool_calls) = &messages[1].content else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(
+            tool_calls.tool_results[0].call.arguments,
+            json!({"city": "Paris"})
+        );
+        assert_eq!(tool_calls.tool_results[0].output, json!({"temp": 20}));
+    }
+
+    fn export_fixture() -> Session {
+        let call = ToolCall::new("get_weather".into(), json!({"city": "Paris"}), None);
+        let mut session = Session::default();
+        session.import_messages(vec![
+            Message::new(
+                MessageRole::User,
+                MessageContent::Text("<script>alert(1)</script> weather?".into()),
+            ),
+            Message::new(
+                MessageRole::Assistant,
+                MessageContent::ToolCalls(MessageContentToolCalls::new(
+                    vec![ToolResult::new(call, json!({"temp": 20}))],
+                    String::new(),
+                )),
+            ),
+            Message::new(
+                MessageRole::Assistant,
+                MessageContent::Text(
+                    "See [x](javascript:alert(1)), ![y](JavaScript:alert(2)) and \
+                    [docs](https://example.com).\n\n```rust\nfn main() {}\n```"
+                        .into(),
+                ),
+            ),
+        ]);
+        session
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let output = render_markdown(&export_fixture(), "demo");
+        assert!(output.starts_with("# demo\n"));
+        assert!(output.contains("## User\n\n<script>alert(1)</script> weather?"));
+        assert!(output.contains("<summary>Call <code>get_weather</code></summary>"));
+        assert!(output.contains("```json\n{\n  \"temp\": 20\n}\n```"));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let theme: Theme = decode_bin(LIGHT_THEME).unwrap();
+        let output = render_html(&export_fixture(), "demo", theme).unwrap();
+        assert!(!output.contains("<script>"));
+        assert!(output.contains("&lt;script&gt;alert(1)&lt;/script&gt; weather?"));
+        assert!(output.contains("<details>\n<summary>Call <code>get_weather</code></summary>"));
+        assert_eq!(output.matches("<pre style=").count(), 3);
+        assert!(!output.to_lowercase().contains("javascript:"));
+        assert!(output.contains(r##"<a href="#">x</a>"##));
+        assert!(output.contains(r##"<img src="#" alt="y" />"##));
+        assert!(output.contains(r#"<a href="https://example.com">docs</a>"#));
+    }
+
+    #[test]
+    fn test_export_json_round_trip() {
+        let session = export_fixture();
+        let data = json!({ "name": "demo", "messages": session.transcript() });
+        let conversation = parse_json(&data).unwrap().remove(0);
+        assert_eq!(conversation.name.as_deref(), Some("demo"));
+        assert_eq!(
+            serde_json::to_value(&conversation.messages).unwrap(),
+            serde_json::to_value(session.transcript()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use super::*;
+
+use anyhow::{Context, Result};
+use fancy_regex::Regex;
+use inquire::{autocompletion::Replacement, Autocomplete, CustomUserError};
+use std::sync::LazyLock;
+
+static RE_FRONT_MATTER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)^---\s*\n(.*?)\n---\s*\n?(.*)$").unwrap());
+static RE_PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{([?!]?\w+(?: (?:[^{}]|\{\w+\})*)?)\}\}").unwrap());
+
+/// A named prompt with `{{var}}` placeholders
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Template {
+    prompt: String,
+    variables: Vec<TemplateVariable>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct TemplateVariable {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub default: Option<String>,
+    /// Suggested values offered by completion
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TemplateMetadata {
+    #[serde(default)]
+    variables: Vec<TemplateVariable>,
+}
+
+impl Template {
+    pub fn load(name: &str) -> Result<Self> {
+        let path = Config::template_file(name);
+        let err = || format!("Failed to load template '{name}' at '{}'", path.display());
+        let content = read_to_string(&path).with_context(err)?;
+        Self::new(&content).with_context(err)
+    }
+
+    pub fn new(content: &str) -> Result<Self> {
+        let (metadata, prompt) = match RE_FRONT_MATTER.captures(content) {
+            Ok(Some(caps)) => {
+                let metadata: TemplateMetadata = serde_yaml::from_str(&caps[1])?;
+                (metadata, caps[2].to_string())
+            }
+            _ => (TemplateMetadata::default(), content.to_string()),
+        };
+        let mut prompt = prompt.trim().to_string();
+        interpolate_variables(&mut prompt);
+        let mut variables = metadata.variables;
+        for name in placeholder_names(&prompt) {
+            if !variables.iter().any(|v| v.name == name) {
+                variables.push(TemplateVariable {
+                    name,
+                    ..Default::default()
+                });
+            }
+        }
+        Ok(Self { prompt, variables })
+    }
+
+    pub fn variables(&self) -> &[TemplateVariable] {
+        &self.variables
+    }
+
+    pub fn render(&self, values: &HashMap<String, String>) -> String {
+        let variables: HashMap<&str, String> = values
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+        RE_PLACEHOLDER
+            .replace_all(&self.prompt, |caps: &fancy_regex::Captures<'_>| {
+                render_prompt(&format!("{{{}}}", &caps[1]), &variables)
+            })
+            .to_string()
+    }
+}
+
+/// Fill the template variables and build the input, attaching files when given
+///
+/// The args are `[<var>=<value>]... [<file>]... [-- <text>...]`.
+pub async fn template_input(
+    config: &GlobalConfig,
+    name: &str,
+    args: Option<&str>,
+    abort_signal: AbortSignal,
+) -> Result<Input> {
+    let template = Template::load(name)?;
+    let (words, text) = split_args_text(args.unwrap_or_default(), cfg!(windows));
+    let mut values = HashMap::new();
+    let mut files = vec![];
+    for word in words {
+        match word.split_once('=') {
+            Some((key, value)) if template.variables.iter().any(|v| v.name == key) => {
+                values.insert(key.to_string(), value.to_string());
+            }
+            _ => files.push(word),
+        }
+    }
+    for variable in template.variables() {
+        if values.contains_key(&variable.name) {
+            continue;
+        }
+        let value = if *IS_STDOUT_TERMINAL {
+            prompt_variable(variable)?
+        } else {
+            variable.default.clone().unwrap_or_default()
+        };
+        values.insert(variable.name.clone(), value);
+    }
+    let mut prompt = template.render(&values);
+    if !text.is_empty() {
+        prompt = format!("{prompt}\n{text}");
+    }
+    if files.is_empty() {
+        Ok(Input::from_str(config, &prompt, None))
+    } else {
+        Input::from_files_with_spinner(config, &prompt, files, None, abort_signal).await
+    }
+}
+
+fn prompt_variable(variable: &TemplateVariable) -> Result<String> {
+    let message = match &variable.description {
+        Some(description) => format!("{} ({description}):", variable.name),
+        None => format!("{}:", variable.name),
+    };
+    let mut prompt = Text::new(&message).with_autocomplete(OptionsCompleter {
+        options: variable.options.clone(),
+    });
+    if let Some(default) = &variable.default {
+        prompt = prompt.with_default(default);
+    }
+    Ok(prompt.prompt()?)
+}
+
+fn placeholder_names(prompt: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for caps in RE_PLACEHOLDER.captures_iter(prompt).flatten() {
+        let expr = caps[1].trim_start_matches(['?', '!']);
+        let name = expr.split(' ').next().unwrap_or_default().to_string();
+        if !name.starts_with("__") && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+#[derive(Debug, Clone)]
+struct OptionsCompleter {
+    options: Vec<String>,
+}
+
+impl Autocomplete for OptionsCompleter {
+    fn get_suggestions(&mut self, input: &str) -> Result<Vec<String>, CustomUserError> {
+        let input = input.to_lowercase();
+        Ok(self
+            .options
+            .iter()
+            .filter(|v| v.to_lowercase().contains(&input))
+            .cloned()
+            .collect())
+    }
+
+    fn get_completion(
+        &mut self,
+        _input: &str,
+        highlighted_suggestion: Option<String>,
+    ) -> Result<Replacement, CustomUserError> {
+        Ok(highlighted_suggestion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let template = Template::new(
+            r#"---
+variables:
+  - name: language
+    default: rust
+    options: [rust, go]
+---
+Review this {{language}} code{{?focus  with a focus on {focus}}}.
+Keep {{}} and {braces} as they are."#,
+        )
+        .unwrap();
+        let names: Vec<&str> = template
+            .variables()
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        assert_eq!(names, ["language", "focus"]);
+        let values = HashMap::from([
+            ("language".to_string(), "go".to_string()),
+            ("focus".to_string(), "errors".to_string()),
+        ]);
+        assert_eq!(
+            template.render(&values),
+            "Review this go code with a focus on errors.\nKeep {{}} and {braces} as they are."
+        );
+        let values = HashMap::from([("language".to_string(), "rust".to_string())]);
+        assert_eq!(
+            template.render(&values),
+            "Review this rust code.\nKeep {{}} and {braces} as they are."
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use super::*;
+
+use crate::client::call_chat_completions;
+use crate::function::ToolCall;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{path::Path, time::Duration};
+
+pub const TRACE_FILE_NAME: &str = "trace.jsonl";
+
+const MAX_REPLAY_ROUNDS: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord {
+    pub turn: String,
+    pub time: String,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEvent {
+    Turn {
+        input: String,
+        model: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        role: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        agent: Option<String>,
+    },
+    Request {
+        model: String,
+        output: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        duration_ms: u64,
+    },
+    Tool {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        arguments: Value,
+        #[serde(default)]
+        output: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stdout: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        duration_ms: u64,
+    },
+}
+
+impl TraceEvent {
+    pub fn turn(input: &Input, agent: Option<&Agent>) -> Self {
+        let role = input.role();
+        Self::Turn {
+            input: input.raw(),
+            model: role.model().id(),
+            role: (!role.is_derived()).then(|| role.name().to_string()),
+            agent: agent.map(|v| v.name().to_string()),
+        }
+    }
+
+    pub fn request(
+        input: &Input,
+        output: &str,
+        tool_calls: &[ToolCall],
+        error: Option<String>,
+        duration: Duration,
+    ) -> Self {
+        Self::Request {
+            model: input.role().model().id(),
+            output: output.to_string(),
+            tool_calls: tool_calls.to_vec(),
+            error,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+
+    pub fn tool(
+        call: &ToolCall,
+        ret: &Result<Value>,
+        stdout: Option<String>,
+        exit_code: Option<i32>,
+        duration: Duration,
+    ) -> Self {
+        let (output, error) = match ret {
+            Ok(v) => (v.clone(), None),
+            Err(err) => (Value::Null, Some(format!("{err:#}"))),
+        };
+        Self::Tool {
+            name: call.name.clone(),
+            id: call.id.clone(),
+            arguments: normalize_arguments(&call.arguments),
+            output,
+            stdout,
+            exit_code,
+            error,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+/// Recorded tool outputs, handed out in place of running the tools again
+#[derive(Debug, Clone, Default)]
+pub struct TraceReplay {
+    calls: Vec<(String, Value, Value)>,
+}
+
+impl TraceReplay {
+    /// Prefer a call with identical arguments, then fall back to the next call of the same tool
+    pub fn take(&mut self, name: &str, arguments: &Value) -> Option<Value> {
+        let arguments = normalize_arguments(arguments);
+        let index = self
+            .calls
+            .iter()
+            .position(|(n, a, _)| n == name && a == &arguments)
+            .or_else(|| self.calls.iter().position(|(n, _, _)| n == name))?;
+        Some(self.calls.remove(index).2)
+    }
+}
+
+pub fn load_trace(path: &Path) -> Result<Vec<TraceRecord>> {
+    let contents = read_to_string(path)
+        .with_context(|| format!("Failed to read trace file at '{}'", path.display()))?;
+    let mut records = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(v) => records.push(v),
+            Err(err) => warn!("Skip line {} of '{}': {err}", index + 1, path.display()),
+        }
+    }
+    Ok(records)
+}
+
+/// Group records by turn, keeping the order turns started in
+pub fn group_trace_turns(records: Vec<TraceRecord>) -> Vec<(String, Vec<TraceRecord>)> {
+    let mut turns: IndexMap<String, Vec<TraceRecord>> = IndexMap::new();
+    for record in records {
+        turns.entry(record.turn.clone()).or_default().push(record);
+    }
+    turns.into_iter().collect()
+}
+
+pub fn render_trace_turns(path: &Path, count: usize) -> Result<String> {
+    if !path.exists() {
+        bail!("No trace found at '{}'", path.display());
+    }
+    let turns = group_trace_turns(load_trace(path)?);
+    let skip = turns.len().saturating_sub(count);
+    let mut output = vec![];
+    for (_, records) in turns.into_iter().skip(skip) {
+        for record in records {
+            output.push(render_record(&record));
+        }
+        output.push(String::new());
+    }
+    Ok(output.join("\n").trim_end().to_string())
+}
+
+fn render_record(record: &TraceRecord) -> String {
+    match &record.event {
+        TraceEvent::Turn {
+            input,
+            model,
+            role,
+            agent,
+        } => {
+            let mut scope = vec![model.clone()];
+            scope.extend(agent.iter().map(|v| format!("agent:{v}")));
+            scope.extend(role.iter().map(|v| format!("role:{v}")));
+            format!(
+                "# TURN {} [{}] ({})\n{input}",
+                record.turn,
+                record.time,
+                scope.join(", ")
+            )
+        }
+        TraceEvent::Request {
+            model,
+            output,
+            tool_calls,
+            error,
+            duration_ms,
+        } => {
+            let mut lines = vec![dimmed_text(&format!(
+                "→ request {model} {}",
+                format_duration(*duration_ms)
+            ))];
+            if let Some(error) = error {
+                lines.push(error_text(&format!("  error: {error}")));
+            }
+            if !output.is_empty() {
+                lines.push(indent_text(output, 2));
+            }
+            for call in tool_calls {
+                lines.push(format!("  ⚙ {} {}", call.name, call.arguments));
+            }
+            lines.join("\n")
+        }
+        TraceEvent::Tool {
+            name,
+            arguments,
+            output,
+            stdout,
+            exit_code,
+            error,
+            duration_ms,
+            ..
+        } => {
+            let exit_code = exit_code.map(|v| format!(" exit {v}")).unwrap_or_default();
+            let mut lines = vec![dimmed_text(&format!(
+                "→ tool {name}{exit_code} {}",
+                format_duration(*duration_ms)
+            ))];
+            lines.push(format!("  arguments: {arguments}"));
+            if let Some(stdout) = stdout.as_ref().filter(|v| !v.trim().is_empty()) {
+                lines.push(format!("  stdout:\n{}", indent_text(stdout.trim_end(), 4)));
+            }
+            if let Some(error) = error {
+                lines.push(error_text(&format!("  error: {error}")));
+            } else {
+                lines.push(format!("  output: {output}"));
+            }
+            lines.join("\n")
+        }
+    }
+}
+
+/// Re-run the conversation recorded in a trace file, answering tool calls with the recorded outputs
+pub async fn replay_trace(
+    config: &GlobalConfig,
+    path: &str,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let turns = group_trace_turns(load_trace(Path::new(path))?);
+    if turns.is_empty() {
+        bail!("No turns recorded in '{path}'");
+    }
+    for (_, records) in turns {
+        let mut text = None;
+        let mut calls = vec![];
+        for record in records {
+            match record.event {
+                TraceEvent::Turn { input, .. } => text = Some(input),
+                TraceEvent::Tool {
+                    name,
+                    arguments,
+                    output,
+                    ..
+                } => calls.push((name, arguments, output)),
+                TraceEvent::Request { .. } => {}
+            }
+        }
+        let Some(text) = text else {
+            continue;
+        };
+        if *IS_STDOUT_TERMINAL {
+            println!("{}", dimmed_text(&format!(">> {text}")));
+        }
+        config.write().trace_replay = Some(TraceReplay { calls });
+        let ret = replay_turn(config, &text, abort_signal.clone()).await;
+        config.write().trace_replay = None;
+        ret?;
+    }
+    config.write().exit_session()?;
+    Ok(())
+}
+
+async fn replay_turn(config: &GlobalConfig, text: &str, abort_signal: AbortSignal) -> Result<()> {
+    let mut input = Input::from_str(config, text, None);
+    input.set_skip_memories();
+    input.use_embeddings(abort_signal.clone()).await?;
+    let client = input.create_client()?;
+    for _ in 0..MAX_REPLAY_ROUNDS {
+        config.write().before_chat_completion(&input)?;
+        let (output, tool_results) =
+            call_chat_completions(&input, true, false, client.as_ref(), abort_signal.clone())
+                .await?;
+        config
+            .write()
+            .after_chat_completion(&input, &output, &tool_results)?;
+        if tool_results.is_empty() {
+            return Ok(());
+        }
+        input = input.merge_tool_results(output, tool_results);
+    }
+    bail!("The turn did not finish within {MAX_REPLAY_ROUNDS} rounds of tool calls")
+}
+
+fn normalize_arguments(arguments: &Value) -> Value {
+    match arguments.as_str() {
+        Some(v) => serde_json::from_str(v).unwrap_or_else(|_| arguments.clone()),
+        None => arguments.clone(),
+    }
+}
+
+fn format_duration(duration_ms: u64) -> String {
+    format!("{:.1}s", duration_ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_trace_replay_take() {
+        let mut replay = TraceReplay {
+            calls: vec![
+                ("fs_cat".into(), json!({"path": "a.txt"}), json!("A")),
+                ("fs_cat".into(), json!({"path": "b.txt"}), json!("B")),
+            ],
+        };
+        assert_eq!(
+            replay.take("fs_cat", &json!(r#"{"path":"b.txt"}"#)),
+            Some(json!("B"))
+        );
+        assert_eq!(
+            replay.take("fs_cat", &json!({"path": "c.txt"})),
+            Some(json!("A"))
+        );
+        assert_eq!(replay.take("fs_cat", &json!({"path": "a.txt"})), None);
+    }
+}
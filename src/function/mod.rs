@@ -0,0 +1,780 @@
+mod native;
+mod sub_agent;
+
+pub use self::native::{native_declarations, NATIVE_TOOLSET};
+pub use self::sub_agent::sub_agent_declarations;
+
+use self::native::eval_native_tool;
+use self::sub_agent::run_sub_agent;
+
+use crate::{
+    config::{Agent, Config, GlobalConfig, TraceEvent},
+    utils::*,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{stream, StreamExt};
+use indexmap::IndexMap;
+use inquire::Confirm;
+use is_terminal::IsTerminal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+const PATH_SEP: &str = ";";
+#[cfg(not(windows))]
+const PATH_SEP: &str = ":";
+
+pub async fn eval_tool_calls(
+    config: &GlobalConfig,
+    mut calls: Vec<ToolCall>,
+    abort_signal: AbortSignal,
+) -> Result<Vec<ToolResult>> {
+    let mut output = vec![];
+    if calls.is_empty() {
+        return Ok(output);
+    }
+    calls = ToolCall::dedup(calls);
+    if calls.is_empty() {
+        bail!("The request was aborted because an infinite loop of function calls was detected.")
+    }
+    // Ask for approvals one by one before anything runs concurrently
+    let mut denials = vec![];
+    for call in &calls {
+        denials.push(call.check_permission(config)?);
+    }
+    let concurrency = {
+        let config = config.read();
+        // Sub-agents swap the current agent out, so they must not overlap with other calls
+        let has_sub_agent = calls.iter().any(|call| {
+            config
+                .agent
+                .as_ref()
+                .and_then(|agent| agent.functions().find(&call.name))
+                .map(|v| v.sub_agent.is_some())
+                .unwrap_or_default()
+        });
+        if has_sub_agent {
+            1
+        } else {
+            config.tool_call_concurrency.max(1)
+        }
+    };
+    let evals: Vec<_> = calls
+        .iter()
+        .zip(denials)
+        .map(|(call, denial)| {
+            let abort_signal = abort_signal.clone();
+            async move {
+                let start = Instant::now();
+                let mut process = ToolProcess {
+                    capture: config.read().trace_turn.is_some(),
+                    ..Default::default()
+                };
+                let ret = match denial {
+                    Some(output) => Ok(output),
+                    None => {
+                        call.eval_with_timeout(config, abort_signal, &mut process)
+                            .await
+                    }
+                };
+                config.read().record_trace(|| {
+                    TraceEvent::tool(
+                        call,
+                        &ret,
+                        process.stdout,
+                        process.exit_code,
+                        start.elapsed(),
+                    )
+                });
+                ret
+            }
+        })
+        .collect();
+    // `buffered` keeps the results in call order while running up to `concurrency` calls at once
+    let results: Vec<Result<Value>> = stream::iter(evals).buffered(concurrency).collect().await;
+    let mut is_all_null = true;
+    for (call, result) in calls.into_iter().zip(results) {
+        let mut result = result?;
+        if result.is_null() {
+            result = json!("DONE");
+        } else {
+            is_all_null = false;
+        }
+        output.push(ToolResult::new(call, result));
+    }
+    if is_all_null {
+        output = vec![];
+    }
+    Ok(output)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ToolResult {
+    pub call: ToolCall,
+    pub output: Value,
+}
+
+impl ToolResult {
+    pub fn new(call: ToolCall, output: Value) -> Self {
+        Self { call, output }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Functions {
+    declarations: Vec<FunctionDeclaration>,
+}
+
+impl Functions {
+    pub fn init(declarations_path: &Path) -> Result<Self> {
+        let declarations: Vec<FunctionDeclaration> = if declarations_path.exists() {
+            let ctx = || {
+                format!(
+                    "Failed to load functions at {}",
+                    declarations_path.display()
+                )
+            };
+            let content = fs::read_to_string(declarations_path).with_context(ctx)?;
+            serde_json::from_str(&content).with_context(ctx)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self { declarations })
+    }
+
+    pub fn find(&self, name: &str) -> Option<&FunctionDeclaration> {
+        self.declarations.iter().find(|v| v.name == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.declarations.iter().any(|v| v.name == name)
+    }
+
+    pub fn declarations(&self) -> &[FunctionDeclaration] {
+        &self.declarations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.declarations.is_empty()
+    }
+
+    pub fn extend(&mut self, declarations: Vec<FunctionDeclaration>) {
+        for declaration in declarations {
+            if self.contains(&declaration.name) {
+                warn!("Skip tool '{}', the name is taken", declaration.name);
+                continue;
+            }
+            self.declarations.push(declaration);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    pub parameters: JsonSchema,
+    #[serde(skip_serializing, default)]
+    pub agent: bool,
+    /// The MCP server and tool name this function is routed to
+    #[serde(skip)]
+    pub mcp: Option<(String, String)>,
+    /// Whether this function is handled in-process by a native tool
+    #[serde(skip)]
+    pub native: bool,
+    /// The agent this function delegates to
+    #[serde(skip)]
+    pub sub_agent: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchema {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub properties: Option<IndexMap<String, JsonSchema>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<JsonSchema>>,
+    #[serde(rename = "anyOf", skip_serializing_if = "Option::is_none")]
+    pub any_of: Option<Vec<JsonSchema>>,
+    #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub enum_value: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<Vec<String>>,
+}
+
+impl JsonSchema {
+    pub fn is_empty_properties(&self) -> bool {
+        match &self.properties {
+            Some(v) => v.is_empty(),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: Value,
+    pub id: Option<String>,
+}
+
+type CallConfig = (String, String, Vec<String>, HashMap<String, String>);
+
+/// What a tool process left behind, kept for the trace
+#[derive(Debug, Default)]
+pub struct ToolProcess {
+    pub capture: bool,
+    pub stdout: Option<String>,
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPermission {
+    #[default]
+    Allow,
+    Ask,
+    Deny,
+}
+
+impl ToolPermission {
+    /// Exact names win over `prefix*` patterns, and the longest pattern wins among patterns.
+    /// Agent rules take precedence over global ones at the same level.
+    pub fn resolve(rules: &[&IndexMap<String, ToolPermission>], name: &str) -> Self {
+        if let Some(permission) = rules.iter().find_map(|v| v.get(name)) {
+            return *permission;
+        }
+        let mut matched: Option<(usize, ToolPermission)> = None;
+        for rule in rules {
+            for (pattern, permission) in rule.iter() {
+                let Some(prefix) = pattern.strip_suffix('*') else {
+                    continue;
+                };
+                if name.starts_with(prefix)
+                    && matched.map(|(len, _)| prefix.len() > len).unwrap_or(true)
+                {
+                    matched = Some((prefix.len(), *permission));
+                }
+            }
+        }
+        matched.map(|(_, v)| v).unwrap_or_default()
+    }
+}
+
+impl ToolCall {
+    pub fn dedup(calls: Vec<Self>) -> Vec<Self> {
+        let mut new_calls = vec![];
+        let mut seen_ids = HashSet::new();
+
+        for call in calls.into_iter().rev() {
+            if let Some(id) = &call.id {
+                if !seen_ids.contains(id) {
+                    seen_ids.insert(id.clone());
+                    new_calls.push(call);
+                }
+            } else {
+                new_calls.push(call);
+            }
+        }
+
+        new_calls.reverse();
+        new_calls
+    }
+
+    pub fn new(name: String, arguments: Value, id: Option<String>) -> Self {
+        Self {
+            name,
+            arguments,
+            id,
+        }
+    }
+
+    /// Return the output to send back instead of running the call, if it is not permitted
+    fn check_permission(&self, config: &GlobalConfig) -> Result<Option<Value>> {
+        let permission = {
+            let config = config.read();
+            // Replayed calls never run, so there is nothing to approve
+            if config.trace_replay.is_some() {
+                return Ok(None);
+            }
+            let mut rules = vec![];
+            if let Some(agent) = &config.agent {
+                rules.push(agent.tool_permissions());
+            }
+            rules.push(&config.tool_permissions);
+            ToolPermission::resolve(&rules, &self.name)
+        };
+        let approved = match permission {
+            ToolPermission::Allow => true,
+            ToolPermission::Deny => false,
+            ToolPermission::Ask => {
+                if !*IS_STDOUT_TERMINAL || !std::io::stdin().is_terminal() {
+                    false
+                } else {
+                    let arguments = match self.parse_arguments(&self.name) {
+                        Ok(v) => serde_json::to_string_pretty(&v)?,
+                        Err(_) => self.arguments.to_string(),
+                    };
+                    println!("{}", dimmed_text(&format!("{} {arguments}", self.name)));
+                    Confirm::new(&format!("Allow the call '{}'?", self.name))
+                        .with_default(false)
+                        .prompt()?
+                }
+            }
+        };
+        if approved {
+            return Ok(None);
+        }
+        Ok(Some(json!({
+            "error": format!("The call '{}' was denied by the user's tool policy", self.name)
+        })))
+    }
+
+    async fn eval_with_timeout(
+        &self,
+        config: &GlobalConfig,
+        abort_signal: AbortSignal,
+        process: &mut ToolProcess,
+    ) -> Result<Value> {
+        let timeout = {
+            let config = config.read();
+            config
+                .tool_call_timeouts
+                .get(&self.name)
+                .copied()
+                .or(config.tool_call_timeout)
+                .filter(|v| *v > 0)
+        };
+        let eval = async {
+            match timeout {
+                Some(secs) => {
+                    match tokio::time::timeout(
+                        Duration::from_secs(secs),
+                        self.eval(config, abort_signal.clone(), process),
+                    )
+                    .await
+                    {
+                        Ok(ret) => ret,
+                        Err(_) => Ok(json!({
+                            "error": format!("The call '{}' timed out after {secs}s", self.name)
+                        })),
+                    }
+                }
+                None => self.eval(config, abort_signal.clone(), process).await,
+            }
+        };
+        tokio::select! {
+            ret = eval => ret,
+            _ = wait_abort_signal(&abort_signal) => bail!("Aborted."),
+            _ = tokio::signal::ctrl_c() => {
+                abort_signal.set_ctrlc();
+                bail!("Aborted.")
+            }
+        }
+    }
+
+    async fn eval(
+        &self,
+        config: &GlobalConfig,
+        abort_signal: AbortSignal,
+        process: &mut ToolProcess,
+    ) -> Result<Value> {
+        let replayed = config
+            .write()
+            .trace_replay
+            .as_mut()
+            .map(|v| v.take(&self.name, &self.arguments));
+        if let Some(output) = replayed {
+            if *IS_STDOUT_TERMINAL {
+                let message = format!("Replay {} {}", self.name, self.arguments);
+                println!("{}", dimmed_text(&message));
+            }
+            return Ok(output.unwrap_or_else(
+                || json!({ "error": format!("No recorded output for the call '{}'", self.name) }),
+            ));
+        }
+
+        if let Some(agent_name) = self.sub_agent(config) {
+            let json_data = self.parse_arguments(&self.name)?;
+            return run_sub_agent(config, &agent_name, json_data, abort_signal).await;
+        }
+
+        if let Some((server, tool)) = self.mcp_tool(config) {
+            let json_data = self.parse_arguments(&self.name)?;
+            let mcp = config.read().mcp.clone();
+            return mcp.call_tool(&server, &tool, json_data).await;
+        }
+
+        if self.native_tool(config) {
+            let json_data = self.parse_arguments(&self.name)?;
+            return eval_native_tool(config, &self.name, json_data).await;
+        }
+
+        let (call_name, cmd_name, mut cmd_args, envs) = match &config.read().agent {
+            Some(agent) => self.extract_call_config_from_agent(config, agent)?,
+            None => self.extract_call_config_from_config(config)?,
+        };
+
+        let json_data = self.parse_arguments(&call_name)?;
+
+        cmd_args.push(json_data.to_string());
+
+        let sandbox = {
+            let config = config.read();
+            let enabled = config
+                .agent
+                .as_ref()
+                .and_then(|v| v.tool_sandbox())
+                .unwrap_or(config.tool_sandbox.enabled);
+            enabled.then(|| config.tool_sandbox.clone())
+        };
+
+        let output =
+            match run_llm_function_async(cmd_name, cmd_args, envs, sandbox.as_ref(), process)
+                .await?
+            {
+                Some(contents) => serde_json::from_str(&contents)
+                    .ok()
+                    .unwrap_or_else(|| json!({"output": contents})),
+                None => Value::Null,
+            };
+
+        Ok(output)
+    }
+
+    fn parse_arguments(&self, call_name: &str) -> Result<Value> {
+        if self.arguments.is_object() {
+            Ok(self.arguments.clone())
+        } else if let Some(arguments) = self.arguments.as_str() {
+            serde_json::from_str(arguments)
+                .map_err(|_| anyhow!("The call '{call_name}' has invalid arguments: {arguments}"))
+        } else {
+            bail!(
+                "The call '{call_name}' has invalid arguments: {}",
+                self.arguments
+            );
+        }
+    }
+
+    fn mcp_tool(&self, config: &GlobalConfig) -> Option<(String, String)> {
+        let config = config.read();
+        if let Some(agent) = &config.agent {
+            if agent.functions().contains(&self.name) {
+                return None;
+            }
+        }
+        config.functions.find(&self.name)?.mcp.clone()
+    }
+
+    fn sub_agent(&self, config: &GlobalConfig) -> Option<String> {
+        let config = config.read();
+        config
+            .agent
+            .as_ref()?
+            .functions()
+            .find(&self.name)?
+            .sub_agent
+            .clone()
+    }
+
+    fn native_tool(&self, config: &GlobalConfig) -> bool {
+        let config = config.read();
+        if let Some(agent) = &config.agent {
+            if agent.functions().contains(&self.name) {
+                return false;
+            }
+        }
+        config
+            .functions
+            .find(&self.name)
+            .map(|v| v.native)
+            .unwrap_or_default()
+    }
+
+    fn extract_call_config_from_agent(
+        &self,
+        config: &GlobalConfig,
+        agent: &Agent,
+    ) -> Result<CallConfig> {
+        let function_name = self.name.clone();
+        match agent.functions().find(&function_name) {
+            Some(function) => {
+                let agent_name = agent.name().to_string();
+                if function.agent {
+                    Ok((
+                        format!("{agent_name}-{function_name}"),
+                        agent_name,
+                        vec![function_name],
+                        agent.variable_envs(),
+                    ))
+                } else {
+                    Ok((
+                        function_name.clone(),
+                        function_name,
+                        vec![],
+                        Default::default(),
+                    ))
+                }
+            }
+            None => self.extract_call_config_from_config(config),
+        }
+    }
+
+    fn extract_call_config_from_config(&self, config: &GlobalConfig) -> Result<CallConfig> {
+        let function_name = self.name.clone();
+        match config.read().functions.contains(&function_name) {
+            true => Ok((
+                function_name.clone(),
+                function_name,
+                vec![],
+                Default::default(),
+            )),
+            false => bail!("Unexpected call: {function_name} {}", self.arguments),
+        }
+    }
+}
+
+pub fn run_llm_function(
+    cmd_name: String,
+    cmd_args: Vec<String>,
+    envs: HashMap<String, String>,
+) -> Result<Option<String>> {
+    let (cmd_name, envs, temp_file) = prepare_llm_function(cmd_name, &cmd_args, envs)?;
+    let exit_code = run_command(&cmd_name, &cmd_args, Some(envs))
+        .map_err(|err| anyhow!("Unable to run {cmd_name}, {err}"))?;
+    read_llm_function_output(exit_code, &temp_file)
+}
+
+/// Like `run_llm_function`, but the process is killed once the returned future is dropped
+pub async fn run_llm_function_async(
+    cmd_name: String,
+    cmd_args: Vec<String>,
+    envs: HashMap<String, String>,
+    sandbox: Option<&SandboxConfig>,
+    process: &mut ToolProcess,
+) -> Result<Option<String>> {
+    let (cmd_name, envs, temp_file) = prepare_llm_function(cmd_name, &cmd_args, envs)?;
+    let mut command = match sandbox {
+        Some(sandbox) => {
+            // The output file must exist to be bound into the sandbox
+            fs::write(&temp_file, "")?;
+            sandbox.command(&cmd_name, &cmd_args, envs, &[temp_file.as_path()])?
+        }
+        None => {
+            let mut command = tokio::process::Command::new(&cmd_name);
+            command.args(&cmd_args).envs(envs);
+            command
+        }
+    };
+    command.kill_on_drop(true);
+    let exit_code = if process.capture {
+        let output = command
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .await
+            .map_err(|err| anyhow!("Unable to run {cmd_name}, {err}"))?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        if *IS_STDOUT_TERMINAL {
+            print!("{stdout}");
+        }
+        process.stdout = Some(stdout);
+        output.status.code().unwrap_or_default()
+    } else {
+        command
+            .status()
+            .await
+            .map_err(|err| anyhow!("Unable to run {cmd_name}, {err}"))?
+            .code()
+            .unwrap_or_default()
+    };
+    process.exit_code = Some(exit_code);
+    read_llm_function_output(exit_code, &temp_file)
+}
+
+fn prepare_llm_function(
+    cmd_name: String,
+    cmd_args: &[String],
+    mut envs: HashMap<String, String>,
+) -> Result<(String, HashMap<String, String>, PathBuf)> {
+    let prompt = format!("Call {cmd_name} {}", cmd_args.join(" "));
+
+    let mut bin_dirs: Vec<PathBuf> = vec![];
+    if cmd_args.len() > 1 {
+        let dir = Config::agent_functions_dir(&cmd_name).join("bin");
+        if dir.exists() {
+            bin_dirs.push(dir);
+        }
+    }
+    bin_dirs.push(Config::functions_bin_dir());
+    let current_path = std::env::var("PATH").context("No PATH environment variable")?;
+    let prepend_path = bin_dirs
+        .iter()
+        .map(|v| format!("{}{PATH_SEP}", v.display()))
+        .collect::<Vec<_>>()
+        .join("");
+    envs.insert("PATH".into(), format!("{prepend_path}{current_path}"));
+
+    let temp_file = temp_file("-eval-", "");
+    envs.insert("LLM_OUTPUT".into(), temp_file.display().to_string());
+
+    #[cfg(windows)]
+    let cmd_name = polyfill_cmd_name(&cmd_name, &bin_dirs);
+    if *IS_STDOUT_TERMINAL {
+        println!("{}", dimmed_text(&prompt));
+    }
+    Ok((cmd_name, envs, temp_file))
+}
+
+fn read_llm_function_output(exit_code: i32, temp_file: &Path) -> Result<Option<String>> {
+    if exit_code != 0 {
+        bail!("Tool call exit with {exit_code}");
+    }
+    let mut output = None;
+    if temp_file.exists() {
+        let contents =
+            fs::read_to_string(temp_file).context("Failed to retrieve tool call output")?;
+        if !contents.is_empty() {
+            output = Some(contents);
+        }
+    };
+    Ok(output)
+}
+
+#[cfg(windows)]
+fn polyfill_cmd_name<T: AsRef<Path>>(cmd_name: &str, bin_dir: &[T]) -> String {
+    let cmd_name = cmd_name.to_string();
+    if let Ok(exts) = std::env::var("PATHEXT") {
+        for name in exts.split(';').map(|ext| format!("{cmd_name}{ext}")) {
+            for dir in bin_dir {
+                let path = dir.as_ref().join(&name);
+                if path.exists() {
+                    return name.to_string();
+                }
+            }
+        }
+    }
+    cmd_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_tool_permission() {
+        let agent: IndexMap<String, ToolPermission> =
+            serde_yaml::from_str("fs_*: ask\nfs_cat: allow").unwrap();
+        let global: IndexMap<String, ToolPermission> =
+            serde_yaml::from_str("'*': deny\nfs_rm: deny\nweb_search: allow").unwrap();
+        let rules = [&agent, &global];
+        assert_eq!(
+            ToolPermission::resolve(&rules, "fs_cat"),
+            ToolPermission::Allow
+        );
+        assert_eq!(
+            ToolPermission::resolve(&rules, "fs_rm"),
+            ToolPermission::Deny
+        );
+        assert_eq!(
+            ToolPermission::resolve(&rules, "fs_ls"),
+            ToolPermission::Ask
+        );
+        assert_eq!(
+            ToolPermission::resolve(&rules, "web_search"),
+            ToolPermission::Allow
+        );
+        assert_eq!(
+            ToolPermission::resolve(&rules, "execute_command"),
+            ToolPermission::Deny
+        );
+        assert_eq!(ToolPermission::resolve(&[], "fs_ls"), ToolPermission::Allow);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_eval_tool_calls_concurrently() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Call the scripts by their full path rather than pointing the functions dir at them,
+        // which would leak into the other tests through the environment
+        let bin_dir = temp_file("-functions-", "");
+        fs::create_dir_all(&bin_dir).unwrap();
+        let log_file = bin_dir.join("calls.log");
+        let stub_fast = bin_dir.join("stub_fast").display().to_string();
+        let stub_slow = bin_dir.join("stub_slow").display().to_string();
+        let script = format!(
+            r#"#!/bin/sh
+secs=$(printf '%s' "$1" | sed -n 's/.*"secs":\([0-9.]*\).*/\1/p')
+echo start >> '{log}'
+sleep "$secs"
+echo end >> '{log}'
+printf '{{"secs":%s}}' "$secs" > "$LLM_OUTPUT"
+"#,
+            log = log_file.display()
+        );
+        let mut config = Config::default();
+        for name in [&stub_fast, &stub_slow] {
+            fs::write(name, &script).unwrap();
+            fs::set_permissions(name, fs::Permissions::from_mode(0o755)).unwrap();
+            config.functions.extend(vec![FunctionDeclaration {
+                name: name.clone(),
+                description: String::new(),
+                parameters: serde_json::from_value(json!({ "type": "object" })).unwrap(),
+                agent: false,
+                mcp: None,
+                native: false,
+                sub_agent: None,
+            }]);
+        }
+        config.tool_call_concurrency = 2;
+        config.tool_call_timeouts = [(stub_slow.clone(), 1)].into_iter().collect();
+        let config = std::sync::Arc::new(parking_lot::RwLock::new(config));
+
+        let calls = [0.4, 0.1, 0.3, 0.2]
+            .into_iter()
+            .map(|secs| ToolCall::new(stub_fast.clone(), json!({ "secs": secs }), None))
+            .chain([ToolCall::new(stub_slow.clone(), json!({ "secs": 3 }), None)])
+            .collect();
+        let results = eval_tool_calls(&config, calls, create_abort_signal())
+            .await
+            .unwrap();
+        let outputs: Vec<_> = results.iter().map(|v| v.output.clone()).collect();
+        assert_eq!(
+            outputs,
+            [
+                json!({ "secs": 0.4 }),
+                json!({ "secs": 0.1 }),
+                json!({ "secs": 0.3 }),
+                json!({ "secs": 0.2 }),
+                json!({ "error": format!("The call '{stub_slow}' timed out after 1s") }),
+            ]
+        );
+
+        let log = fs::read_to_string(&log_file).unwrap();
+        let (mut running, mut max_running) = (0, 0);
+        for line in log.lines() {
+            match line {
+                "start" => running += 1,
+                _ => running -= 1,
+            }
+            max_running = max_running.max(running);
+        }
+        assert_eq!(max_running, 2);
+        let _ = fs::remove_dir_all(&bin_dir);
+    }
+}
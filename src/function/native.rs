@@ -0,0 +1,497 @@
+use super::FunctionDeclaration;
+
+use crate::config::{Config, GlobalConfig};
+use crate::rag::Rag;
+use crate::utils::*;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, Utc};
+use fancy_regex::Regex;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The `use_tools` item that selects all native tools
+pub const NATIVE_TOOLSET: &str = "native";
+
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+const MAX_LIST_ENTRIES: usize = 1000;
+const MAX_GREP_MATCHES: usize = 200;
+
+pub fn native_declarations() -> Vec<FunctionDeclaration> {
+    let value = json!([
+        {
+            "name": "read_file",
+            "description": "Read the contents of a text file within the workspace.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "File path, relative to the workspace root" }
+                },
+                "required": ["path"]
+            }
+        },
+        {
+            "name": "list_directory",
+            "description": "List the entries of a directory within the workspace. Directories end with '/'.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Directory path, relative to the workspace root, defaults to '.'" }
+                }
+            }
+        },
+        {
+            "name": "grep_files",
+            "description": "Search files within the workspace for lines matching a regular expression.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "pattern": { "type": "string", "description": "The regular expression" },
+                    "path": { "type": "string", "description": "File or directory to search, defaults to '.'" }
+                },
+                "required": ["pattern"]
+            }
+        },
+        {
+            "name": "fetch_url",
+            "description": "Fetch a web page or document and return its contents as text.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": { "type": "string", "description": "The URL to fetch" }
+                },
+                "required": ["url"]
+            }
+        },
+        {
+            "name": "calculate",
+            "description": "Evaluate a math expression. Supports + - * / % ^, parentheses, pi, e and sqrt, abs, sin, cos, tan, asin, acos, atan, ln, log, exp, floor, ceil, round, min, max, pow.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "expression": { "type": "string", "description": "The expression, e.g. '2 * (3 + 4) ^ 2'" }
+                },
+                "required": ["expression"]
+            }
+        },
+        {
+            "name": "current_time",
+            "description": "Get the current date and time.",
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        },
+        {
+            "name": "search_rag",
+            "description": "Search a knowledge base (RAG) for passages relevant to the query.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "rag": { "type": "string", "description": "The name of the RAG" },
+                    "query": { "type": "string", "description": "The search query" }
+                },
+                "required": ["rag", "query"]
+            }
+        }
+    ]);
+    let mut declarations: Vec<FunctionDeclaration> = serde_json::from_value(value).unwrap();
+    for declaration in declarations.iter_mut() {
+        declaration.native = true;
+    }
+    declarations
+}
+
+pub async fn eval_native_tool(config: &GlobalConfig, name: &str, args: Value) -> Result<Value> {
+    let get_arg = |key: &str| args[key].as_str().unwrap_or_default().to_string();
+    let output = match name {
+        "read_file" => read_file(&workspace_root(config)?, &get_arg("path"))?,
+        "list_directory" => list_directory(&workspace_root(config)?, &get_arg("path"))?,
+        "grep_files" => grep_files(
+            &workspace_root(config)?,
+            &get_arg("pattern"),
+            &get_arg("path"),
+        )?,
+        "fetch_url" => {
+            let url = get_arg("url");
+            if !is_url(&url) {
+                bail!("Invalid URL '{url}'");
+            }
+            let loaders = config.read().document_loaders.clone();
+            let (contents, _) = fetch_with_loaders(&loaders, &url, false).await?;
+            contents
+        }
+        "calculate" => format_number(eval_math(&get_arg("expression"))?),
+        "current_time" => {
+            let now = Local::now();
+            format!(
+                "Local: {} ({})\nUTC: {}\nUnix timestamp: {}",
+                now.to_rfc3339(),
+                now.format("%A"),
+                Utc::now().to_rfc3339(),
+                now.timestamp()
+            )
+        }
+        "search_rag" => search_rag(config, &get_arg("rag"), &get_arg("query")).await?,
+        _ => bail!("Unknown native tool '{name}'"),
+    };
+    Ok(json!({ "output": output }))
+}
+
+fn workspace_root(config: &GlobalConfig) -> Result<PathBuf> {
+    let root = match config.read().native_tools_root.clone() {
+        Some(v) => v,
+        None => std::env::current_dir()?,
+    };
+    root.canonicalize()
+        .with_context(|| format!("Invalid workspace root '{}'", root.display()))
+}
+
+/// Resolve a path against the root, refusing anything that escapes it
+fn resolve_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let path = if path.is_empty() { "." } else { path };
+    let resolved = root
+        .join(path)
+        .canonicalize()
+        .with_context(|| format!("Path '{path}' not found"))?;
+    if !resolved.starts_with(root) {
+        bail!("Access denied, '{path}' is outside of the workspace");
+    }
+    Ok(resolved)
+}
+
+fn relative_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+fn read_file(root: &Path, path: &str) -> Result<String> {
+    let path = resolve_path(root, path)?;
+    if fs::metadata(&path)?.len() > MAX_FILE_SIZE {
+        bail!("File is larger than {MAX_FILE_SIZE} bytes");
+    }
+    let data = fs::read(&path)?;
+    String::from_utf8(data).map_err(|_| anyhow!("'{}' is not a text file", path.display()))
+}
+
+fn list_directory(root: &Path, path: &str) -> Result<String> {
+    let path = resolve_path(root, path)?;
+    let mut entries = vec![];
+    for entry in fs::read_dir(&path)?.flatten() {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().map(|v| v.is_dir()).unwrap_or_default() {
+            name.push('/');
+        }
+        entries.push(name);
+    }
+    entries.sort_unstable();
+    if entries.len() > MAX_LIST_ENTRIES {
+        let more = entries.len() - MAX_LIST_ENTRIES;
+        entries.truncate(MAX_LIST_ENTRIES);
+        entries.push(format!("... {more} more entries"));
+    }
+    Ok(entries.join("\n"))
+}
+
+fn grep_files(root: &Path, pattern: &str, path: &str) -> Result<String> {
+    let re = Regex::new(pattern).with_context(|| format!("Invalid pattern '{pattern}'"))?;
+    let path = resolve_path(root, path)?;
+    let mut pending = vec![(path.is_dir(), path)];
+    let mut matches = vec![];
+    while let Some((is_dir, path)) = pending.pop() {
+        if is_dir {
+            let Ok(entries) = fs::read_dir(&path) else {
+                continue;
+            };
+            // Symlinks are skipped, since they may point outside of the workspace
+            let mut children: Vec<_> = entries
+                .flatten()
+                .filter(|v| !v.file_name().to_string_lossy().starts_with('.'))
+                .filter_map(|v| {
+                    let file_type = v.file_type().ok()?;
+                    (!file_type.is_symlink()).then(|| (file_type.is_dir(), v.path()))
+                })
+                .collect();
+            children.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            pending.extend(children);
+            continue;
+        }
+        let too_large = fs::metadata(&path)
+            .map(|v| v.len() > MAX_FILE_SIZE)
+            .unwrap_or(true);
+        if too_large {
+            continue;
+        }
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for (index, line) in contents.lines().enumerate() {
+            if re.is_match(line).unwrap_or_default() {
+                matches.push(format!(
+                    "{}:{}: {}",
+                    relative_path(root, &path),
+                    index + 1,
+                    line.trim()
+                ));
+                if matches.len() >= MAX_GREP_MATCHES {
+                    matches.push(format!("... stopped after {MAX_GREP_MATCHES} matches"));
+                    return Ok(matches.join("\n"));
+                }
+            }
+        }
+    }
+    if matches.is_empty() {
+        return Ok("No matches found".into());
+    }
+    Ok(matches.join("\n"))
+}
+
+async fn search_rag(config: &GlobalConfig, name: &str, query: &str) -> Result<String> {
+    if !Config::list_rags().iter().any(|v| v == name) {
+        bail!("Unknown RAG '{name}'");
+    }
+    let rag_path = config.read().rag_file(name);
+    let rag = Rag::load(config, name, &rag_path)?;
+    let (reranker_model, top_k) = rag.get_config();
+    let (embeddings, _) = rag
+        .search(
+            query,
+            top_k,
+            reranker_model.as_deref(),
+            None,
+            create_abort_signal(),
+        )
+        .await?;
+    if embeddings.is_empty() {
+        return Ok("No relevant content found".into());
+    }
+    Ok(embeddings)
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        value.to_string()
+    }
+}
+
+fn eval_math(expression: &str) -> Result<f64> {
+    let mut parser = MathParser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+    let value = parser.parse_expr()?;
+    if let Some(c) = parser.peek() {
+        bail!("Unexpected '{c}' in the expression");
+    }
+    if !value.is_finite() {
+        bail!("The result is not a finite number");
+    }
+    Ok(value)
+}
+
+struct MathParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl MathParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => bail!("Expected '{expected}' in the expression"),
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<f64> {
+        let mut value = self.parse_term()?;
+        while let Some(c) = self.peek() {
+            match c {
+                '+' => {
+                    self.pos += 1;
+                    value += self.parse_term()?;
+                }
+                '-' => {
+                    self.pos += 1;
+                    value -= self.parse_term()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_term(&mut self) -> Result<f64> {
+        let mut value = self.parse_unary()?;
+        while let Some(c) = self.peek() {
+            match c {
+                '*' => {
+                    self.pos += 1;
+                    value *= self.parse_unary()?;
+                }
+                '/' => {
+                    self.pos += 1;
+                    value /= self.parse_unary()?;
+                }
+                '%' => {
+                    self.pos += 1;
+                    value %= self.parse_unary()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_unary(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.parse_unary()?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_power(),
+        }
+    }
+
+    fn parse_power(&mut self) -> Result<f64> {
+        let base = self.parse_atom()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(base.powf(self.parse_unary()?));
+        }
+        Ok(base)
+    }
+
+    fn parse_atom(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.parse_expr()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number '{number}'"))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric()) {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                if self.peek() != Some('(') {
+                    return match name.as_str() {
+                        "pi" => Ok(std::f64::consts::PI),
+                        "e" => Ok(std::f64::consts::E),
+                        _ => bail!("Unknown constant '{name}'"),
+                    };
+                }
+                self.pos += 1;
+                let mut args = vec![self.parse_expr()?];
+                while self.peek() == Some(',') {
+                    self.pos += 1;
+                    args.push(self.parse_expr()?);
+                }
+                self.expect(')')?;
+                call_math_function(&name, &args)
+            }
+            Some(c) => bail!("Unexpected '{c}' in the expression"),
+            None => bail!("Unexpected end of the expression"),
+        }
+    }
+}
+
+fn call_math_function(name: &str, args: &[f64]) -> Result<f64> {
+    let value = match (name, args) {
+        ("sqrt", [x]) => x.sqrt(),
+        ("abs", [x]) => x.abs(),
+        ("sin", [x]) => x.sin(),
+        ("cos", [x]) => x.cos(),
+        ("tan", [x]) => x.tan(),
+        ("asin", [x]) => x.asin(),
+        ("acos", [x]) => x.acos(),
+        ("atan", [x]) => x.atan(),
+        ("ln", [x]) => x.ln(),
+        ("log", [x]) => x.log10(),
+        ("log", [x, base]) => x.log(*base),
+        ("exp", [x]) => x.exp(),
+        ("floor", [x]) => x.floor(),
+        ("ceil", [x]) => x.ceil(),
+        ("round", [x]) => x.round(),
+        ("pow", [x, y]) => x.powf(*y),
+        ("min", [first, rest @ ..]) => rest.iter().fold(*first, |a, b| a.min(*b)),
+        ("max", [first, rest @ ..]) => rest.iter().fold(*first, |a, b| a.max(*b)),
+        _ => bail!("Unknown function '{name}' with {} argument(s)", args.len()),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval_math() {
+        assert_eq!(eval_math("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(eval_math("2 * (3 + 4) ^ 2").unwrap(), 98.0);
+        assert_eq!(eval_math("-2^2").unwrap(), -4.0);
+        assert_eq!(eval_math("2^-1").unwrap(), 0.5);
+        assert_eq!(eval_math("max(1, sqrt(16), 3) % 3").unwrap(), 1.0);
+        assert_eq!(format_number(eval_math("log(1000)").unwrap()), "3");
+        assert!(eval_math("1 / 0").is_err());
+        assert!(eval_math("2 +").is_err());
+        assert!(eval_math("foo(1)").is_err());
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let root = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert!(resolve_path(&root, "src").is_ok());
+        assert!(resolve_path(&root, "..").is_err());
+        assert!(resolve_path(&root, "/").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_grep_files_skips_symlinks() {
+        let outside = temp_file("-outside-", "");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "needle outside\n").unwrap();
+        let root = temp_file("-workspace-", "");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "// needle inside\n").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("src/linked")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret.txt")).unwrap();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(
+            grep_files(&root, "needle", "").unwrap(),
+            "src/main.rs:1: // needle inside"
+        );
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&outside);
+    }
+}
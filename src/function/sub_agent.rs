@@ -0,0 +1,259 @@
+use super::FunctionDeclaration;
+
+use crate::client::call_chat_completions;
+use crate::config::{
+    Agent, AgentDefinition, AgentVariables, Config, GlobalConfig, Input, Role, Session,
+};
+use crate::rag::Rag;
+use crate::utils::*;
+
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+use std::{sync::Arc, time::Instant};
+
+const MAX_SUB_AGENT_ROUNDS: usize = 16;
+
+/// Expose each sub-agent as a tool named `agent_<name>`
+pub fn sub_agent_declarations(names: &[String]) -> Vec<FunctionDeclaration> {
+    let mut output = vec![];
+    for name in names {
+        let index_path = Config::agent_functions_dir(name).join("index.yaml");
+        let definition = match AgentDefinition::load(&index_path) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Skip sub-agent '{name}': {err}");
+                continue;
+            }
+        };
+        let mut variables = Map::new();
+        for variable in &definition.variables {
+            variables.insert(
+                variable.name.clone(),
+                json!({ "type": "string", "description": variable.description }),
+            );
+        }
+        let mut properties = json!({
+            "prompt": { "type": "string", "description": "The task or question for the agent" }
+        });
+        if !variables.is_empty() {
+            properties["variables"] = json!({ "type": "object", "properties": variables });
+        }
+        let description = match definition.description.is_empty() {
+            true => format!("Delegate a task to the '{name}' agent"),
+            false => format!(
+                "Delegate a task to the '{name}' agent: {}",
+                definition.description
+            ),
+        };
+        let value = json!({
+            "name": format!("agent_{}", name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")),
+            "description": description,
+            "parameters": {
+                "type": "object",
+                "properties": properties,
+                "required": ["prompt"]
+            }
+        });
+        if let Ok(mut declaration) = serde_json::from_value::<FunctionDeclaration>(value) {
+            declaration.sub_agent = Some(name.clone());
+            output.push(declaration);
+        }
+    }
+    output
+}
+
+/// Run a nested conversation with the sub-agent and return its final answer
+pub async fn run_sub_agent(
+    config: &GlobalConfig,
+    name: &str,
+    arguments: Value,
+    abort_signal: AbortSignal,
+) -> Result<Value> {
+    let (depth, max_depth, calls, max_calls) = {
+        let config = config.read();
+        (
+            config.sub_agent_depth,
+            config.sub_agent_max_depth,
+            config.sub_agent_calls,
+            config.sub_agent_max_calls,
+        )
+    };
+    if depth >= max_depth {
+        return Ok(json!({
+            "error": format!("Cannot delegate to '{name}', the maximum agent depth of {max_depth} is reached")
+        }));
+    }
+    if calls >= max_calls {
+        return Ok(json!({
+            "error": format!("Cannot delegate to '{name}', the budget of {max_calls} agent calls is used up")
+        }));
+    }
+    let prompt = arguments["prompt"].as_str().unwrap_or_default().to_string();
+    if prompt.trim().is_empty() {
+        bail!("The call to agent '{name}' has no prompt");
+    }
+    let mut variables = AgentVariables::default();
+    if let Some(values) = arguments["variables"].as_object() {
+        for (key, value) in values {
+            let value = match value {
+                Value::String(v) => v.clone(),
+                _ => value.to_string(),
+            };
+            variables.insert(key.clone(), value);
+        }
+    }
+
+    let indent = "  ".repeat(depth);
+    if *IS_STDOUT_TERMINAL {
+        let message = format!("{indent}⤷ {name}: {}", truncate_prompt(&prompt));
+        println!("{}", dimmed_text(&message));
+    }
+    let start = Instant::now();
+
+    let mut agent = Agent::init(config, name, abort_signal.clone()).await?;
+    let mut agent_variables = agent.config_variables().clone();
+    agent_variables.extend(variables);
+    let shared_variables = Agent::init_agent_variables(
+        agent.name(),
+        agent.defined_variables(),
+        &agent_variables,
+        false,
+    )?;
+    agent.set_shared_variables(shared_variables);
+    agent.update_shared_dynamic_instructions(false)?;
+
+    // Park the outer state so the nested conversation gets a clean slate
+    let parked = ParkedState::park(config);
+    {
+        let mut config = config.write();
+        config.rag = agent.rag();
+        config.agent = Some(agent);
+        config.sub_agent_calls += 1;
+    }
+    let ret = converse(config, &prompt, abort_signal).await;
+    drop(parked);
+
+    if *IS_STDOUT_TERMINAL {
+        let status = if ret.is_ok() { "done" } else { "failed" };
+        let message = format!(
+            "{indent}⤶ {name} {status} in {:.1}s",
+            start.elapsed().as_secs_f64()
+        );
+        println!("{}", dimmed_text(&message));
+    }
+    Ok(json!({ "output": ret? }))
+}
+
+/// The outer agent, session, role and rag, put back on drop so that a timed out
+/// or aborted sub-agent never loses them
+struct ParkedState {
+    config: GlobalConfig,
+    state: (
+        Option<Agent>,
+        Option<Session>,
+        Option<Role>,
+        Option<Arc<Rag>>,
+    ),
+}
+
+impl ParkedState {
+    fn park(config: &GlobalConfig) -> Self {
+        let mut guard = config.write();
+        let state = (
+            guard.agent.take(),
+            guard.session.take(),
+            guard.role.take(),
+            guard.rag.take(),
+        );
+        guard.sub_agent_depth += 1;
+        Self {
+            config: config.clone(),
+            state,
+        }
+    }
+}
+
+impl Drop for ParkedState {
+    fn drop(&mut self) {
+        let mut config = self.config.write();
+        (config.agent, config.session, config.role, config.rag) = std::mem::take(&mut self.state);
+        config.sub_agent_depth -= 1;
+    }
+}
+
+async fn converse(
+    config: &GlobalConfig,
+    prompt: &str,
+    abort_signal: AbortSignal,
+) -> Result<String> {
+    let mut input = Input::from_str(config, prompt, None);
+    input.set_skip_memories();
+    input.use_embeddings(abort_signal.clone()).await?;
+    let client = input.create_client()?;
+    for _ in 0..MAX_SUB_AGENT_ROUNDS {
+        // Boxed since sub-agents may call sub-agents again
+        let (output, tool_results) = Box::pin(call_chat_completions(
+            &input,
+            false,
+            false,
+            client.as_ref(),
+            abort_signal.clone(),
+        ))
+        .await?;
+        if tool_results.is_empty() {
+            return Ok(output);
+        }
+        input = input.merge_tool_results(output, tool_results);
+    }
+    bail!("The agent did not finish within {MAX_SUB_AGENT_ROUNDS} rounds of tool calls")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use parking_lot::RwLock;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_max_depth() {
+        let config = Config {
+            sub_agent_depth: 2,
+            sub_agent_max_depth: 2,
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+        let ret = run_sub_agent(
+            &config,
+            "coder",
+            json!({ "prompt": "hi" }),
+            create_abort_signal(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ret,
+            json!({ "error": "Cannot delegate to 'coder', the maximum agent depth of 2 is reached" })
+        );
+        assert_eq!(config.read().sub_agent_calls, 0);
+    }
+
+    #[tokio::test]
+    async fn test_restore_parked_state_on_timeout() {
+        let config = Config {
+            role: Some(Role::new("reviewer", "Review the code")),
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+        let nested = async {
+            let _parked = ParkedState::park(&config);
+            config.write().role = Some(Role::new("nested", "Nested role"));
+            std::future::pending::<()>().await;
+        };
+        let ret = tokio::time::timeout(Duration::from_millis(10), nested).await;
+        assert!(ret.is_err());
+        let config = config.read();
+        assert_eq!(config.role.as_ref().map(|v| v.name()), Some("reviewer"));
+        assert_eq!(config.sub_agent_depth, 0);
+    }
+}
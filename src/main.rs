@@ -4,6 +4,7 @@ mod client;
 mod config;
 mod database;
 mod function;
+mod mcp;
 mod rag;
 mod render;
 mod repl;
@@ -16,12 +17,16 @@ extern crate log;
 
 use crate::cli::Cli;
 use crate::client::{
-    call_chat_completions, call_chat_completions_streaming, list_models, ModelType,
+    call_chat_completions, call_chat_completions_streaming, list_models, Client, ModelType,
 };
 use crate::config::{
-    ensure_parent_exists, list_agents, load_env_file, macro_execute, Config, GlobalConfig, Input,
-    WorkingMode, CODE_ROLE, EXPLAIN_SHELL_ROLE, SHELL_ROLE, TEMP_SESSION_NAME,
+    ensure_parent_exists, export_session, import_sessions, install_agent, list_agents,
+    load_env_file, macro_execute, remove_agent, replay_trace, update_agent, Config, ExportFormat,
+    GlobalConfig, Input, WorkingMode, CODE_ROLE, EXPLAIN_SHELL_ROLE, SHELL_PLAN_ROLE, SHELL_ROLE,
+    TEMP_SESSION_NAME,
 };
+use crate::mcp::is_mcp_path;
+use crate::rag::eval_rag;
 use crate::render::render_error;
 use crate::repl::Repl;
 use crate::utils::*;
@@ -31,16 +36,21 @@ use clap::Parser;
 use inquire::Text;
 use parking_lot::RwLock;
 use simplelog::{format_description, ConfigBuilder, LevelFilter, SimpleLogger, WriteLogger};
-use std::{env, process, sync::Arc};
+use std::{env, path::Path, process, sync::Arc};
+
+const COMMAND_COLOR: nu_ansi_term::Color = nu_ansi_term::Color::Rgb(255, 165, 0);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     load_env_file()?;
     let cli = Cli::parse();
-    let text = cli.text()?;
+    // In MCP mode, stdin carries the protocol
+    let text = if cli.mcp { None } else { cli.text()? };
     let working_mode = if cli.serve.is_some() {
         WorkingMode::Serve
-    } else if text.is_none() && cli.file.is_empty() {
+    } else if cli.mcp {
+        WorkingMode::Mcp
+    } else if text.is_none() && cli.file.is_empty() && !cli.explain_last && !cli.fix_last {
         WorkingMode::Repl
     } else {
         WorkingMode::Cmd
@@ -52,7 +62,12 @@ async fn main() -> Result<()> {
         || cli.list_agents
         || cli.list_rags
         || cli.list_macros
-        || cli.list_sessions;
+        || cli.list_sessions
+        || cli.export_session.is_some()
+        || cli.import_session.is_some()
+        || cli.install_agent.is_some()
+        || cli.update_agent.is_some()
+        || cli.remove_agent.is_some();
     setup_logger(working_mode.is_serve())?;
     let config = Arc::new(RwLock::new(Config::init(working_mode, info_flag).await?));
     if let Err(err) = run(config, cli, text).await {
@@ -64,6 +79,11 @@ async fn main() -> Result<()> {
 
 async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()> {
     let abort_signal = create_abort_signal();
+    let text = if cli.explain_last || cli.fix_last {
+        Some(LastCommand::load()?.to_prompt(text.as_deref()))
+    } else {
+        text
+    };
 
     if cli.sync_models {
         let url = config.read().sync_models_url();
@@ -81,6 +101,15 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
         println!("{roles}");
         return Ok(());
     }
+    if let Some(source) = &cli.install_agent {
+        return install_agent(source).await;
+    }
+    if let Some(name) = &cli.update_agent {
+        return update_agent(name).await;
+    }
+    if let Some(name) = &cli.remove_agent {
+        return remove_agent(name);
+    }
     if cli.list_agents {
         let agents = list_agents().join("\n");
         println!("{agents}");
@@ -122,9 +151,15 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
         if let Some(prompt) = &cli.prompt {
             config.write().use_prompt(prompt)?;
         } else if let Some(name) = &cli.role {
+            if is_mcp_path(name) {
+                Config::load_mcp(&config).await;
+            }
             config.write().use_role(name)?;
-        } else if cli.execute {
-            config.write().use_role(SHELL_ROLE)?;
+        } else if cli.execute || cli.fix_last {
+            let role = if cli.plan { SHELL_PLAN_ROLE } else { SHELL_ROLE };
+            config.write().use_role(role)?;
+        } else if cli.explain_last {
+            config.write().use_role(EXPLAIN_SHELL_ROLE)?;
         } else if cli.code {
             config.write().use_role(CODE_ROLE)?;
         }
@@ -142,6 +177,23 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
         println!("{sessions}");
         return Ok(());
     }
+    if let Some(name) = &cli.export_session {
+        let format: ExportFormat = cli.format.as_deref().unwrap_or("md").parse()?;
+        let output = export_session(&config.read(), name, format)?;
+        print!("{output}");
+        return Ok(());
+    }
+    if let Some(file) = &cli.import_session {
+        for name in import_sessions(&config.read(), Path::new(file))? {
+            println!("✓ Imported session '{name}'.");
+        }
+        return Ok(());
+    }
+    if let Some(query) = &cli.search_history {
+        if !Config::search_history(&config, query)? {
+            return Ok(());
+        }
+    }
     if let Some(model_id) = &cli.model {
         config.write().set_model(model_id)?;
     }
@@ -162,6 +214,9 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
     if let Some(addr) = cli.serve {
         return serve::run(config, addr).await;
     }
+    if cli.mcp {
+        return mcp::serve_stdio(config).await;
+    }
     let is_repl = config.read().working_mode.is_repl();
     if cli.rebuild_rag {
         Config::rebuild_rag(&config, abort_signal.clone()).await?;
@@ -169,13 +224,25 @@ async fn run(config: GlobalConfig, cli: Cli, text: Option<String>) -> Result<()>
             return Ok(());
         }
     }
+    if let Some(args) = &cli.eval_rag {
+        let output = eval_rag(&config, &args[0], &args[1], abort_signal.clone()).await?;
+        print!("{output}");
+        return Ok(());
+    }
     if let Some(name) = &cli.macro_name {
         macro_execute(&config, name, text.as_deref(), abort_signal.clone()).await?;
         return Ok(());
     }
-    if cli.execute && !is_repl {
+    if let Some(path) = &cli.replay {
+        return replay_trace(&config, path, abort_signal).await;
+    }
+    if (cli.execute || cli.fix_last) && !is_repl {
         let input = create_input(&config, text, &cli.file, abort_signal.clone()).await?;
-        shell_execute(&config, &SHELL, input, abort_signal.clone()).await?;
+        if cli.plan {
+            shell_plan_execute(&config, &SHELL, input, abort_signal.clone()).await?;
+        } else {
+            shell_execute(&config, &SHELL, input, abort_signal.clone()).await?;
+        }
         return Ok(());
     }
     config.write().apply_prelude()?;
@@ -262,16 +329,11 @@ async fn shell_execute(
         return Ok(());
     }
     if *IS_STDOUT_TERMINAL {
-        let options = ["execute", "revise", "describe", "copy", "quit"];
-        let command = color_text(eval_str.trim(), nu_ansi_term::Color::Rgb(255, 165, 0));
-        let first_letter_color = nu_ansi_term::Color::Cyan;
-        let prompt_text = options
-            .iter()
-            .map(|v| format!("{}{}", color_text(&v[0..1], first_letter_color), &v[1..]))
-            .collect::<Vec<String>>()
-            .join(&dimmed_text(" | "));
+        let command = color_text(eval_str.trim(), COMMAND_COLOR);
+        let prompt_text = options_prompt(&["execute", "revise", "describe", "copy", "quit"]);
         loop {
             println!("{command}");
+            print_command_risks(&eval_str);
             let answer_char =
                 read_single_key(&['e', 'r', 'd', 'c', 'q'], 'e', &format!("{prompt_text}: "))?;
 
@@ -291,26 +353,8 @@ async fn shell_execute(
                     return shell_execute(config, shell, input, abort_signal.clone()).await;
                 }
                 'd' => {
-                    let role = config.read().retrieve_role(EXPLAIN_SHELL_ROLE)?;
-                    let input = Input::from_str(config, &eval_str, Some(role));
-                    if input.stream() {
-                        call_chat_completions_streaming(
-                            &input,
-                            client.as_ref(),
-                            abort_signal.clone(),
-                        )
+                    describe_command(config, client.as_ref(), &eval_str, abort_signal.clone())
                         .await?;
-                    } else {
-                        call_chat_completions(
-                            &input,
-                            true,
-                            false,
-                            client.as_ref(),
-                            abort_signal.clone(),
-                        )
-                        .await?;
-                    }
-                    println!();
                     continue;
                 }
                 'c' => {
@@ -327,6 +371,170 @@ async fn shell_execute(
     Ok(())
 }
 
+#[async_recursion::async_recursion]
+async fn shell_plan_execute(
+    config: &GlobalConfig,
+    shell: &Shell,
+    mut input: Input,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let client = input.create_client()?;
+    config.write().before_chat_completion(&input)?;
+    let (eval_str, _) =
+        call_chat_completions(&input, false, true, client.as_ref(), abort_signal.clone()).await?;
+
+    config
+        .write()
+        .after_chat_completion(&input, &eval_str, &[])?;
+    let steps: Vec<String> = eval_str
+        .lines()
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && !v.starts_with('#'))
+        .map(|v| v.to_string())
+        .collect();
+    if steps.is_empty() {
+        bail!("No command generated");
+    }
+    if config.read().dry_run {
+        config.read().print_markdown(&eval_str)?;
+        return Ok(());
+    }
+    if !*IS_STDOUT_TERMINAL {
+        println!("{}", steps.join("\n"));
+        return Ok(());
+    }
+    let prompt_text = options_prompt(&["execute", "revise", "describe", "copy", "quit"]);
+    loop {
+        for (i, step) in steps.iter().enumerate() {
+            println!("{}. {}", i + 1, color_text(step, COMMAND_COLOR));
+            print_command_risks(step);
+        }
+        let answer_char =
+            read_single_key(&['e', 'r', 'd', 'c', 'q'], 'e', &format!("{prompt_text}: "))?;
+
+        match answer_char {
+            'e' => {
+                if let Some(failure) = run_shell_plan(config, shell, &steps)? {
+                    let text = format!(
+                        "{}\n\nThe plan was:\n{}\n\n{failure}",
+                        input.text(),
+                        steps.join("\n")
+                    );
+                    input.set_text(text);
+                    return shell_plan_execute(config, shell, input, abort_signal.clone()).await;
+                }
+            }
+            'r' => {
+                let revision = Text::new("Enter your revision:").prompt()?;
+                let text = format!("{}\n{revision}", input.text());
+                input.set_text(text);
+                return shell_plan_execute(config, shell, input, abort_signal.clone()).await;
+            }
+            'd' => {
+                describe_command(
+                    config,
+                    client.as_ref(),
+                    &steps.join("\n"),
+                    abort_signal.clone(),
+                )
+                .await?;
+                continue;
+            }
+            'c' => {
+                set_text(&steps.join("\n"))?;
+                println!("{}", dimmed_text("✓ Copied the commands."));
+            }
+            _ => {}
+        }
+        break;
+    }
+    Ok(())
+}
+
+/// Runs the steps one by one and returns a failure report when the user asks the model to fix it
+fn run_shell_plan(
+    config: &GlobalConfig,
+    shell: &Shell,
+    steps: &[String],
+) -> Result<Option<String>> {
+    let step_prompt = options_prompt(&["yes", "skip", "quit"]);
+    let failure_prompt = options_prompt(&["fix", "continue", "quit"]);
+    let total = steps.len();
+    for (i, step) in steps.iter().enumerate() {
+        println!(
+            "{} {}",
+            dimmed_text(&format!("[{}/{total}]", i + 1)),
+            color_text(step, COMMAND_COLOR)
+        );
+        match read_single_key(&['y', 's', 'q'], 'y', &format!("{step_prompt}: "))? {
+            's' => continue,
+            'q' => return Ok(None),
+            _ => {}
+        }
+        debug!("{} {:?}", shell.cmd, &[&shell.arg, step]);
+        let (code, output) = run_command_with_tee(shell, step)?;
+        if config.read().save_shell_history {
+            let _ = append_to_shell_history(&shell.name, step, code);
+        }
+        if code == 0 {
+            continue;
+        }
+        println!(
+            "{}",
+            color_text(
+                &format!("✗ Exited with code {code}"),
+                nu_ansi_term::Color::Red
+            )
+        );
+        match read_single_key(&['f', 'c', 'q'], 'f', &format!("{failure_prompt}: "))? {
+            'f' => {
+                let output = truncate_output(&output, COMMAND_OUTPUT_LIMIT);
+                return Ok(Some(format!(
+                    "Step {} `{step}` failed with exit code {code} and output:\n{output}\nThe steps before it have been run. Provide a revised plan for the remaining work.",
+                    i + 1
+                )));
+            }
+            'c' => continue,
+            _ => process::exit(code),
+        }
+    }
+    Ok(None)
+}
+
+async fn describe_command(
+    config: &GlobalConfig,
+    client: &dyn Client,
+    command: &str,
+    abort_signal: AbortSignal,
+) -> Result<()> {
+    let role = config.read().retrieve_role(EXPLAIN_SHELL_ROLE)?;
+    let input = Input::from_str(config, command, Some(role));
+    if input.stream() {
+        call_chat_completions_streaming(&input, client, abort_signal).await?;
+    } else {
+        call_chat_completions(&input, true, false, client, abort_signal).await?;
+    }
+    println!();
+    Ok(())
+}
+
+fn print_command_risks(command: &str) {
+    let risks = command_risks(command);
+    if !risks.is_empty() {
+        let text = format!("⚠ {}", risks.join(", "));
+        println!("   {}", color_text(&text, nu_ansi_term::Color::Red));
+    }
+}
+
+fn options_prompt(options: &[&str]) -> String {
+    let first_letter_color = nu_ansi_term::Color::Cyan;
+    options
+        .iter()
+        .map(|v| format!("{}{}", color_text(&v[0..1], first_letter_color), &v[1..]))
+        .collect::<Vec<String>>()
+        .join(&dimmed_text(" | "))
+}
+
 async fn create_input(
     config: &GlobalConfig,
     text: Option<String>,
@@ -334,7 +542,7 @@ async fn create_input(
     abort_signal: AbortSignal,
 ) -> Result<Input> {
     let input = if file.is_empty() {
-        Input::from_str(config, &text.unwrap_or_default(), None)
+        Input::from_text(config, &text.unwrap_or_default(), None, abort_signal).await?
     } else {
         Input::from_files_with_spinner(
             config,
@@ -0,0 +1,455 @@
+mod server;
+mod transport;
+
+pub use self::server::run as serve_stdio;
+
+use self::transport::McpTransport;
+
+use crate::function::{FunctionDeclaration, JsonSchema};
+use crate::utils::*;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::join_all;
+use indexmap::IndexMap;
+use inquire::Text;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const MCP_PREFIX: &str = "mcp:";
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct McpServerConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub env: IndexMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub headers: IndexMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct McpClients {
+    clients: IndexMap<String, Arc<McpClient>>,
+}
+
+impl McpClients {
+    pub async fn init(servers: &IndexMap<String, McpServerConfig>) -> Self {
+        let results = join_all(servers.iter().map(|(name, config)| async move {
+            let ret = tokio::time::timeout(CONNECT_TIMEOUT, McpClient::connect(name, config))
+                .await
+                .map_err(|_| anyhow!("Timeout"))
+                .and_then(|v| v);
+            (name, ret)
+        }))
+        .await;
+        let mut clients = IndexMap::new();
+        for (name, ret) in results {
+            match ret {
+                Ok(client) => {
+                    clients.insert(name.clone(), Arc::new(client));
+                }
+                Err(err) => {
+                    let message = format!("⚠️ Failed to connect MCP server '{name}': {err:#}");
+                    eprintln!("{}", warning_text(&message));
+                }
+            }
+        }
+        Self { clients }
+    }
+
+    pub fn servers(&self) -> impl Iterator<Item = &str> {
+        self.clients.keys().map(|v| v.as_str())
+    }
+
+    pub fn declarations(&self) -> Vec<FunctionDeclaration> {
+        let mut output = vec![];
+        for (server, client) in &self.clients {
+            for tool in &client.tools {
+                let parameters =
+                    match serde_json::from_value::<JsonSchema>(tool.input_schema.clone()) {
+                        Ok(v) => v,
+                        Err(err) => {
+                            warn!("Skip MCP tool '{}' of '{server}': {err}", tool.name);
+                            continue;
+                        }
+                    };
+                output.push(FunctionDeclaration {
+                    name: tool_function_name(server, &tool.name),
+                    description: tool.description.clone(),
+                    parameters,
+                    agent: false,
+                    mcp: Some((server.clone(), tool.name.clone())),
+                    native: false,
+                    sub_agent: None,
+                });
+            }
+        }
+        output
+    }
+
+    pub async fn call_tool(&self, server: &str, tool: &str, arguments: Value) -> Result<Value> {
+        let client = self.get(server)?;
+        if *IS_STDOUT_TERMINAL {
+            println!(
+                "{}",
+                dimmed_text(&format!("Call {server}:{tool} {arguments}"))
+            );
+        }
+        let result = client
+            .request(
+                "tools/call",
+                json!({ "name": tool, "arguments": arguments }),
+            )
+            .await?;
+        let text = contents_to_text(&result["content"]);
+        if result["isError"].as_bool().unwrap_or_default() {
+            return Ok(json!({ "error": text }));
+        }
+        match result.get("structuredContent") {
+            Some(value) if !value.is_null() => Ok(value.clone()),
+            _ if text.is_empty() => Ok(Value::Null),
+            _ => Ok(json!({ "output": text })),
+        }
+    }
+
+    /// List resources as `mcp:<server>:<uri>` paths
+    pub fn list_resources(&self) -> Vec<(String, Option<String>)> {
+        self.clients
+            .iter()
+            .flat_map(|(server, client)| {
+                client.resources.iter().map(move |v| {
+                    let description = v.description.clone().or_else(|| v.name.clone());
+                    (format!("{MCP_PREFIX}{server}:{}", v.uri), description)
+                })
+            })
+            .collect()
+    }
+
+    /// Read an `mcp:<server>:<uri>` path, returning texts and data urls of images
+    pub async fn read_resource(&self, path: &str) -> Result<(Vec<String>, Vec<String>)> {
+        let (server, uri) = parse_mcp_path(path)?;
+        let client = self.get(server)?;
+        let result = client
+            .request("resources/read", json!({ "uri": uri }))
+            .await
+            .with_context(|| format!("Failed to read '{path}'"))?;
+        let mut texts = vec![];
+        let mut images = vec![];
+        for content in result["contents"].as_array().into_iter().flatten() {
+            let mime_type = content["mimeType"].as_str().unwrap_or_default();
+            if let Some(text) = content["text"].as_str() {
+                texts.push(text.to_string());
+            } else if let Some(blob) = content["blob"].as_str() {
+                if mime_type.starts_with("image/") {
+                    images.push(format!("data:{mime_type};base64,{blob}"));
+                } else {
+                    texts.push(format!("[binary resource: {mime_type}]"));
+                }
+            }
+        }
+        Ok((texts, images))
+    }
+
+    /// List prompts as `mcp:<server>:<name>` role names
+    pub fn list_prompts(&self) -> Vec<(String, Option<String>)> {
+        self.clients
+            .iter()
+            .flat_map(|(server, client)| {
+                client.prompts.iter().map(move |v| {
+                    (
+                        format!("{MCP_PREFIX}{server}:{}", v.name),
+                        v.description.clone(),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Get an `mcp:<server>:<name>` prompt as role content, asking for its arguments
+    pub fn get_prompt(&self, path: &str) -> Result<String> {
+        let (server, name) = parse_mcp_path(path)?;
+        let client = self.get(server)?;
+        let prompt = client
+            .prompts
+            .iter()
+            .find(|v| v.name == name)
+            .ok_or_else(|| anyhow!("Unknown MCP prompt '{path}'"))?;
+        let mut arguments = Map::new();
+        for argument in &prompt.arguments {
+            let required = argument.required.unwrap_or_default();
+            if !*IS_STDOUT_TERMINAL {
+                if required {
+                    bail!(
+                        "MCP prompt '{path}' requires the argument '{}'",
+                        argument.name
+                    );
+                }
+                continue;
+            }
+            let message = format!("{}:", argument.name);
+            let mut text = Text::new(&message);
+            if let Some(description) = &argument.description {
+                text = text.with_help_message(description);
+            }
+            if required {
+                text = text.with_validator(inquire::required!("This field is required"));
+            }
+            let value = text.prompt()?;
+            if !value.is_empty() {
+                arguments.insert(argument.name.clone(), value.into());
+            }
+        }
+        let result = block_on(client.request(
+            "prompts/get",
+            json!({ "name": name, "arguments": arguments }),
+        ))
+        .with_context(|| format!("Failed to get '{path}'"))?;
+        Ok(prompt_messages_to_role(&result["messages"]))
+    }
+
+    fn get(&self, server: &str) -> Result<&Arc<McpClient>> {
+        self.clients
+            .get(server)
+            .ok_or_else(|| anyhow!("Unknown MCP server '{server}'"))
+    }
+}
+
+#[derive(Debug)]
+struct McpClient {
+    name: String,
+    transport: McpTransport,
+    next_id: AtomicU64,
+    tools: Vec<McpTool>,
+    resources: Vec<McpResource>,
+    prompts: Vec<McpPrompt>,
+}
+
+impl McpClient {
+    async fn connect(name: &str, config: &McpServerConfig) -> Result<Self> {
+        let mut client = Self {
+            name: name.to_string(),
+            transport: McpTransport::new(name, config)?,
+            next_id: AtomicU64::new(1),
+            tools: vec![],
+            resources: vec![],
+            prompts: vec![],
+        };
+        let result = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_CRATE_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        client
+            .transport
+            .notify(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+            .await?;
+        let capabilities = &result["capabilities"];
+        if capabilities.get("tools").is_some() {
+            client.tools = client.list("tools/list", "tools").await?;
+        }
+        if capabilities.get("resources").is_some() {
+            client.resources = client.list("resources/list", "resources").await?;
+        }
+        if capabilities.get("prompts").is_some() {
+            client.prompts = client.list("prompts/list", "prompts").await?;
+        }
+        debug!(
+            "mcp {name}: {} tools, {} resources, {} prompts",
+            client.tools.len(),
+            client.resources.len(),
+            client.prompts.len()
+        );
+        Ok(client)
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        debug!("mcp {} request: {message}", self.name);
+        let mut response = self.transport.request(id, message).await?;
+        debug!("mcp {} response: {response}", self.name);
+        if let Some(error) = response.get("error") {
+            bail!(
+                "{} ({})",
+                error["message"].as_str().unwrap_or("Unknown error"),
+                error["code"]
+            );
+        }
+        Ok(response["result"].take())
+    }
+
+    async fn list<T: for<'de> Deserialize<'de>>(&self, method: &str, key: &str) -> Result<Vec<T>> {
+        let mut output = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let mut result = self.request(method, params).await?;
+            let items: Vec<T> = serde_json::from_value(result[key].take())
+                .with_context(|| format!("Invalid {method} response"))?;
+            output.extend(items);
+            match result["nextCursor"].as_str() {
+                Some(v) if !v.is_empty() => cursor = Some(v.to_string()),
+                _ => break,
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McpTool {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(rename = "inputSchema", default)]
+    input_schema: Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McpResource {
+    uri: String,
+    name: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McpPrompt {
+    name: String,
+    description: Option<String>,
+    #[serde(default)]
+    arguments: Vec<McpPromptArgument>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct McpPromptArgument {
+    name: String,
+    description: Option<String>,
+    required: Option<bool>,
+}
+
+pub fn is_mcp_path(path: &str) -> bool {
+    path.starts_with(MCP_PREFIX)
+}
+
+fn parse_mcp_path(path: &str) -> Result<(&str, &str)> {
+    path.strip_prefix(MCP_PREFIX)
+        .and_then(|v| v.split_once(':'))
+        .filter(|(server, name)| !server.is_empty() && !name.is_empty())
+        .ok_or_else(|| anyhow!("Invalid MCP path '{path}', expected mcp:<server>:<name>"))
+}
+
+fn tool_function_name(server: &str, tool: &str) -> String {
+    let name: String = format!("{server}_{tool}")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    name.chars().take(64).collect()
+}
+
+fn contents_to_text(contents: &Value) -> String {
+    contents
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| match v["type"].as_str() {
+            Some("text") => v["text"].as_str().map(|v| v.to_string()),
+            Some("resource") => v["resource"]["text"].as_str().map(|v| v.to_string()),
+            Some(kind) => Some(format!("[{kind} content]")),
+            None => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn prompt_messages_to_role(messages: &Value) -> String {
+    let mut prompt = vec![];
+    let mut examples = vec![];
+    for message in messages.as_array().into_iter().flatten() {
+        let text = contents_to_text(&json!([message["content"]]));
+        match message["role"].as_str() {
+            Some("assistant") => examples.push(format!("### OUTPUT:\n{text}")),
+            _ if examples.is_empty() => prompt.push(text),
+            _ => examples.push(format!("### INPUT:\n{text}")),
+        }
+    }
+    let mut output = prompt.join("\n\n");
+    if !examples.is_empty() {
+        if let Some(input) = prompt.pop() {
+            output = prompt.join("\n\n");
+            examples.insert(0, format!("### INPUT:\n{input}"));
+        }
+        output = format!("{output}\n{}", examples.join("\n"));
+    }
+    output.trim().to_string()
+}
+
+/// Run a future to completion from synchronous code inside the runtime
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_function_name() {
+        assert_eq!(
+            tool_function_name("github", "create_issue"),
+            "github_create_issue"
+        );
+        assert_eq!(
+            tool_function_name("my.server", "read file"),
+            "my_server_read_file"
+        );
+    }
+
+    #[test]
+    fn test_prompt_messages_to_role() {
+        let messages = json!([
+            { "role": "user", "content": { "type": "text", "text": "Translate to French" } },
+        ]);
+        assert_eq!(prompt_messages_to_role(&messages), "Translate to French");
+        let messages = json!([
+            { "role": "user", "content": { "type": "text", "text": "Be concise" } },
+            { "role": "user", "content": { "type": "text", "text": "hello" } },
+            { "role": "assistant", "content": { "type": "text", "text": "bonjour" } },
+        ]);
+        assert_eq!(
+            prompt_messages_to_role(&messages),
+            "Be concise\n### INPUT:\nhello\n### OUTPUT:\nbonjour"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use super::{tool_function_name, PROTOCOL_VERSION};
+
+use crate::config::{list_agents, AgentDefinition, Config, GlobalConfig, Role, INPUT_PLACEHOLDER};
+use crate::rag::Rag;
+use crate::utils::*;
+
+use anyhow::{anyhow, bail, Context, Result};
+use parking_lot::Mutex;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, process::Stdio, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum ServerTool {
+    Rag(String),
+    Agent(String),
+    Macro(String),
+}
+
+/// Speak MCP over stdio, publishing roles as prompts and RAGs, agents and macros as tools
+pub async fn run(config: GlobalConfig) -> Result<()> {
+    let server = McpServer {
+        config,
+        rags: Default::default(),
+    };
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Value>(line) {
+            Ok(message) => server.handle(message).await,
+            Err(err) => Some(error_response(
+                Value::Null,
+                -32700,
+                &format!("Parse error: {err}"),
+            )),
+        };
+        if let Some(reply) = reply {
+            let mut data = reply.to_string();
+            data.push('\n');
+            stdout.write_all(data.as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+struct McpServer {
+    config: GlobalConfig,
+    rags: Mutex<HashMap<String, Arc<Rag>>>,
+}
+
+impl McpServer {
+    async fn handle(&self, message: Value) -> Option<Value> {
+        let method = message["method"].as_str()?;
+        // Notifications and responses need no reply
+        let id = message.get("id").cloned()?;
+        debug!("mcp request: {message}");
+        let params = &message["params"];
+        let ret = match method {
+            "initialize" => Ok(initialize(params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": self.list_tools() })),
+            "tools/call" => self.call_tool(params).await,
+            "prompts/list" => Ok(json!({ "prompts": list_prompts() })),
+            "prompts/get" => get_prompt(params),
+            _ => return Some(error_response(id, -32601, "Method not found")),
+        };
+        let reply = match ret {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => error_response(id, -32602, &format!("{err:#}")),
+        };
+        Some(reply)
+    }
+
+    fn list_tools(&self) -> Vec<Value> {
+        tools()
+            .into_iter()
+            .map(|(name, tool)| {
+                let (description, input_schema) = match &tool {
+                    ServerTool::Rag(rag) => (
+                        format!("Search the '{rag}' knowledge base for relevant passages"),
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "query": { "type": "string", "description": "The search query" },
+                                "top_k": { "type": "integer", "description": "Maximum number of passages" }
+                            },
+                            "required": ["query"]
+                        }),
+                    ),
+                    ServerTool::Agent(agent) => agent_tool_schema(agent),
+                    ServerTool::Macro(name) => {
+                        let usage = Config::load_macro(name)
+                            .map(|v| v.usage(name))
+                            .unwrap_or_else(|_| name.to_string());
+                        (
+                            format!("Run the '{name}' macro, usage: {usage}"),
+                            json!({
+                                "type": "object",
+                                "properties": {
+                                    "args": { "type": "string", "description": "Macro arguments" }
+                                }
+                            }),
+                        )
+                    }
+                };
+                json!({ "name": name, "description": description, "inputSchema": input_schema })
+            })
+            .collect()
+    }
+
+    async fn call_tool(&self, params: &Value) -> Result<Value> {
+        let name = params["name"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing tool name"))?;
+        let arguments = &params["arguments"];
+        let Some((_, tool)) = tools().into_iter().find(|(v, _)| v == name) else {
+            bail!("Unknown tool '{name}'");
+        };
+        let ret = match tool {
+            ServerTool::Rag(rag) => self.search_rag(&rag, arguments).await,
+            ServerTool::Agent(agent) => {
+                let prompt = arguments["prompt"].as_str().unwrap_or_default();
+                let mut args = vec!["--agent".to_string(), agent];
+                if let Some(variables) = arguments["variables"].as_object() {
+                    for (key, value) in variables {
+                        let value = match value {
+                            Value::String(v) => v.clone(),
+                            _ => value.to_string(),
+                        };
+                        args.extend(["--agent-variable".into(), key.clone(), value]);
+                    }
+                }
+                run_subcommand(&args, prompt).await
+            }
+            ServerTool::Macro(name) => {
+                let input = arguments["args"].as_str().unwrap_or_default();
+                run_subcommand(&["--macro".into(), name], input).await
+            }
+        };
+        let (text, is_error) = match ret {
+            Ok(text) => (text, false),
+            Err(err) => (format!("{err:#}"), true),
+        };
+        Ok(json!({
+            "content": [{ "type": "text", "text": text }],
+            "isError": is_error,
+        }))
+    }
+
+    async fn search_rag(&self, name: &str, arguments: &Value) -> Result<String> {
+        let query = arguments["query"]
+            .as_str()
+            .filter(|v| !v.trim().is_empty())
+            .ok_or_else(|| anyhow!("Missing query"))?;
+        let cached = self.rags.lock().get(name).cloned();
+        let rag = match cached {
+            Some(rag) => rag,
+            None => {
+                let rag_path = self.config.read().rag_file(name);
+                let rag = Arc::new(Rag::load(&self.config, name, &rag_path)?);
+                self.rags.lock().insert(name.to_string(), rag.clone());
+                rag
+            }
+        };
+        let (reranker_model, top_k) = rag.get_config();
+        let top_k = arguments["top_k"]
+            .as_u64()
+            .map(|v| v as usize)
+            .unwrap_or(top_k);
+        let (embeddings, _) = rag
+            .search(
+                query,
+                top_k,
+                reranker_model.as_deref(),
+                None,
+                create_abort_signal(),
+            )
+            .await?;
+        if embeddings.is_empty() {
+            return Ok("No relevant content found".into());
+        }
+        Ok(embeddings)
+    }
+}
+
+fn initialize(params: &Value) -> Value {
+    let protocol_version = params["protocolVersion"]
+        .as_str()
+        .unwrap_or(PROTOCOL_VERSION);
+    json!({
+        "protocolVersion": protocol_version,
+        "capabilities": {
+            "tools": {},
+            "prompts": {},
+        },
+        "serverInfo": {
+            "name": env!("CARGO_CRATE_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+    })
+}
+
+fn tools() -> Vec<(String, ServerTool)> {
+    let rags = Config::list_rags()
+        .into_iter()
+        .map(|v| (tool_function_name("search", &v), ServerTool::Rag(v)));
+    let agents = list_agents()
+        .into_iter()
+        .map(|v| (tool_function_name("agent", &v), ServerTool::Agent(v)));
+    let macros = Config::list_macros()
+        .into_iter()
+        .map(|v| (tool_function_name("macro", &v), ServerTool::Macro(v)));
+    rags.chain(agents).chain(macros).collect()
+}
+
+fn agent_tool_schema(name: &str) -> (String, Value) {
+    let index_path = Config::agent_functions_dir(name).join("index.yaml");
+    let definition = AgentDefinition::load(&index_path).unwrap_or_default();
+    let description = match definition.description.is_empty() {
+        true => format!("Ask the '{name}' agent"),
+        false => format!("Ask the '{name}' agent: {}", definition.description),
+    };
+    let mut schema = json!({
+        "type": "object",
+        "properties": {
+            "prompt": { "type": "string", "description": "The message sent to the agent" }
+        },
+        "required": ["prompt"]
+    });
+    if !definition.variables.is_empty() {
+        let mut properties = Map::new();
+        let mut required = vec![];
+        for variable in &definition.variables {
+            properties.insert(
+                variable.name.clone(),
+                json!({ "type": "string", "description": variable.description }),
+            );
+            if variable.default.is_none() {
+                required.push(variable.name.clone());
+            }
+        }
+        schema["properties"]["variables"] = json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+        });
+        if !required.is_empty() {
+            schema["required"] = json!(["prompt", "variables"]);
+        }
+    }
+    (description, schema)
+}
+
+/// Run agents and macros in a child process, so their output never reaches the protocol channel
+async fn run_subcommand(args: &[String], input: &str) -> Result<String> {
+    let program = std::env::current_exe().context("Failed to locate the executable")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        bail!("{}", if stderr.is_empty() { stdout } else { stderr });
+    }
+    Ok(stdout)
+}
+
+fn list_prompts() -> Vec<Value> {
+    prompt_roles()
+        .iter()
+        .map(|role| {
+            json!({
+                "name": role.name(),
+                "description": format!("Use the '{}' role", role.name()),
+                "arguments": [{
+                    "name": "input",
+                    "description": "The input for the role",
+                    "required": role.is_embedded_prompt(),
+                }],
+            })
+        })
+        .collect()
+}
+
+fn get_prompt(params: &Value) -> Result<Value> {
+    let name = params["name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Missing prompt name"))?;
+    let role = prompt_roles()
+        .into_iter()
+        .find(|v| v.name() == name)
+        .ok_or_else(|| anyhow!("Unknown prompt '{name}'"))?;
+    let input = params["arguments"]["input"].as_str().unwrap_or_default();
+    Ok(json!({
+        "description": format!("Use the '{name}' role"),
+        "messages": role_messages(&role, input),
+    }))
+}
+
+fn prompt_roles() -> Vec<Role> {
+    Config::all_roles()
+        .into_iter()
+        .filter(|v| !v.name().starts_with('%') && !v.is_empty_prompt())
+        .collect()
+}
+
+fn role_messages(role: &Role, input: &str) -> Vec<Value> {
+    let user_message =
+        |text: &str| json!({ "role": "user", "content": { "type": "text", "text": text } });
+    if role.is_embedded_prompt() {
+        return vec![user_message(
+            &role.prompt().replace(INPUT_PLACEHOLDER, input),
+        )];
+    }
+    let mut messages = vec![user_message(role.prompt())];
+    if !input.is_empty() {
+        messages.push(user_message(input));
+    }
+    messages
+}
+
+fn error_response(id: Value, code: i32, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_messages() {
+        let role = Role::new("translate", "Translate to English: __INPUT__");
+        let messages = role_messages(&role, "bonjour");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0]["content"]["text"],
+            "Translate to English: bonjour"
+        );
+
+        let role = Role::new("coder", "You are a coder");
+        let messages = role_messages(&role, "hello");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["content"]["text"], "hello");
+    }
+}
@@ -0,0 +1,280 @@
+use super::McpServerConfig;
+
+use anyhow::{anyhow, bail, Context, Result};
+use indexmap::IndexMap;
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::{collections::HashMap, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::{oneshot, Mutex as AsyncMutex},
+};
+
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+/// A hung server fails the request instead of the whole turn
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>>;
+
+#[derive(Debug)]
+pub enum McpTransport {
+    Stdio(StdioTransport),
+    Http(HttpTransport),
+}
+
+impl McpTransport {
+    pub fn new(name: &str, config: &McpServerConfig) -> Result<Self> {
+        match (&config.command, &config.url) {
+            (Some(command), None) => Ok(Self::Stdio(StdioTransport::spawn(name, command, config)?)),
+            (None, Some(url)) => Ok(Self::Http(HttpTransport::new(url, &config.headers)?)),
+            _ => bail!("MCP server '{name}' must set exactly one of `command` or `url`"),
+        }
+    }
+
+    /// Send a request and wait for its response
+    pub async fn request(&self, id: u64, message: Value) -> Result<Value> {
+        match self {
+            McpTransport::Stdio(transport) => transport.request(id, message, REQUEST_TIMEOUT).await,
+            McpTransport::Http(transport) => {
+                tokio::time::timeout(REQUEST_TIMEOUT, transport.send(Some(id), message))
+                    .await
+                    .map_err(|_| anyhow!("Timeout waiting for the response to request {id}"))??
+                    .ok_or_else(|| anyhow!("No response to request {id}"))
+            }
+        }
+    }
+
+    pub async fn notify(&self, message: Value) -> Result<()> {
+        match self {
+            McpTransport::Stdio(transport) => transport.write(&message).await,
+            McpTransport::Http(transport) => transport.send(None, message).await.map(|_| ()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StdioTransport {
+    _child: Child,
+    stdin: Arc<AsyncMutex<ChildStdin>>,
+    pending: Pending,
+}
+
+impl StdioTransport {
+    fn spawn(name: &str, command: &str, config: &McpServerConfig) -> Result<Self> {
+        let program = which::which(command).unwrap_or_else(|_| command.into());
+        let mut child = Command::new(program)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start MCP server '{name}'"))?;
+        let stdin = Arc::new(AsyncMutex::new(
+            child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?,
+        ));
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
+        let pending: Pending = Default::default();
+
+        let (reader_stdin, reader_pending, name) =
+            (stdin.clone(), pending.clone(), name.to_string());
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                    debug!("mcp {name} non-json output: {line}");
+                    continue;
+                };
+                if let Some(method) = message["method"].as_str() {
+                    // Requests from the server; only `ping` is supported
+                    if let Some(id) = message.get("id") {
+                        let reply = match method {
+                            "ping" => json!({ "jsonrpc": "2.0", "id": id, "result": {} }),
+                            _ => json!({
+                                "jsonrpc": "2.0",
+                                "id": id,
+                                "error": { "code": -32601, "message": "Method not found" }
+                            }),
+                        };
+                        let _ = write_line(&reader_stdin, &reply).await;
+                    }
+                    continue;
+                }
+                if let Some(id) = message["id"].as_u64() {
+                    if let Some(tx) = reader_pending.lock().remove(&id) {
+                        let _ = tx.send(Ok(message));
+                    }
+                }
+            }
+            for (_, tx) in reader_pending.lock().drain() {
+                let _ = tx.send(Err(anyhow!("MCP server '{name}' exited")));
+            }
+        });
+
+        Ok(Self {
+            _child: child,
+            stdin,
+            pending,
+        })
+    }
+
+    async fn request(&self, id: u64, message: Value, timeout: Duration) -> Result<Value> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id, tx);
+        if let Err(err) = self.write(&message).await {
+            self.pending.lock().remove(&id);
+            return Err(err);
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(ret) => ret.map_err(|_| anyhow!("MCP server closed"))?,
+            Err(_) => {
+                self.pending.lock().remove(&id);
+                bail!("Timeout waiting for the response to request {id}")
+            }
+        }
+    }
+
+    async fn write(&self, message: &Value) -> Result<()> {
+        write_line(&self.stdin, message).await
+    }
+}
+
+async fn write_line(stdin: &AsyncMutex<ChildStdin>, message: &Value) -> Result<()> {
+    let mut data = message.to_string();
+    data.push('\n');
+    let mut stdin = stdin.lock().await;
+    stdin.write_all(data.as_bytes()).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+    headers: HeaderMap,
+    session_id: Mutex<Option<String>>,
+}
+
+impl HttpTransport {
+    fn new(url: &str, headers: &IndexMap<String, String>) -> Result<Self> {
+        let mut header_map = HeaderMap::new();
+        for (key, value) in headers {
+            header_map.insert(
+                HeaderName::from_bytes(key.as_bytes())
+                    .with_context(|| format!("Invalid header '{key}'"))?,
+                HeaderValue::from_str(value)
+                    .with_context(|| format!("Invalid value of header '{key}'"))?,
+            );
+        }
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            headers: header_map,
+            session_id: Mutex::new(None),
+        })
+    }
+
+    async fn send(&self, id: Option<u64>, message: Value) -> Result<Option<Value>> {
+        let mut builder = self
+            .client
+            .post(&self.url)
+            .headers(self.headers.clone())
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(&message);
+        if let Some(session_id) = self.session_id.lock().clone() {
+            builder = builder.header(SESSION_ID_HEADER, session_id);
+        }
+        let mut res = builder.send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let text = res.text().await.unwrap_or_default();
+            bail!("Invalid status: {status}, {text}");
+        }
+        if let Some(session_id) = res
+            .headers()
+            .get(SESSION_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            *self.session_id.lock() = Some(session_id.to_string());
+        }
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        let is_event_stream = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("text/event-stream"))
+            .unwrap_or_default();
+        if !is_event_stream {
+            return Ok(Some(res.json().await?));
+        }
+        let mut buffer = vec![];
+        while let Some(chunk) = res.chunk().await? {
+            buffer.extend(chunk.iter().filter(|v| **v != b'\r'));
+            while let Some(data) = take_sse_data(&mut buffer) {
+                if let Ok(message) = serde_json::from_str::<Value>(&data) {
+                    if message["id"].as_u64() == Some(id) && message.get("method").is_none() {
+                        return Ok(Some(message));
+                    }
+                }
+            }
+        }
+        bail!("No response to request {id}")
+    }
+}
+
+/// Take the data of the next complete event, decoding only whole events so that
+/// multibyte characters split across chunks stay intact
+fn take_sse_data(buffer: &mut Vec<u8>) -> Option<String> {
+    let index = buffer.windows(2).position(|v| v == b"\n\n")?;
+    let event: Vec<u8> = buffer.drain(..index + 2).collect();
+    let data = String::from_utf8_lossy(&event)
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|v| v.trim_start())
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_sse_data() {
+        let event = "data: {\"text\":\"héllo\"}\n\n".as_bytes();
+        let split = event.iter().position(|v| *v == 0xc3).unwrap() + 1;
+        let mut buffer = event[..split].to_vec();
+        assert_eq!(take_sse_data(&mut buffer), None);
+        buffer.extend(&event[split..]);
+        assert_eq!(
+            take_sse_data(&mut buffer).as_deref(),
+            Some("{\"text\":\"héllo\"}")
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stdio_request_timeout() {
+        let config = McpServerConfig {
+            command: Some("sh".into()),
+            args: vec!["-c".into(), "cat >/dev/null".into()],
+            ..Default::default()
+        };
+        let transport = StdioTransport::spawn("hung", "sh", &config).unwrap();
+        let message = json!({ "jsonrpc": "2.0", "id": 1, "method": "ping" });
+        let err = transport
+            .request(1, message, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Timeout"));
+        assert!(transport.pending.lock().is_empty());
+    }
+}
@@ -0,0 +1,344 @@
+use super::*;
+
+use std::future::Future;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvalQuestion {
+    pub question: String,
+    #[serde(default)]
+    pub expected_sources: Vec<String>,
+    #[serde(default)]
+    pub expected_texts: Vec<String>,
+}
+
+impl EvalQuestion {
+    fn expectations_len(&self) -> usize {
+        self.expected_sources.len() + self.expected_texts.len()
+    }
+
+    fn is_relevant(&self, hit: &EvalHit) -> bool {
+        self.expected_sources
+            .iter()
+            .any(|v| match_source(&hit.path, v))
+            || self.expected_texts.iter().any(|v| hit.content.contains(v))
+    }
+
+    fn recall(&self, hits: &[EvalHit]) -> f32 {
+        let matched = self
+            .expected_sources
+            .iter()
+            .filter(|v| hits.iter().any(|hit| match_source(&hit.path, v)))
+            .count()
+            + self
+                .expected_texts
+                .iter()
+                .filter(|v| hits.iter().any(|hit| hit.content.contains(v.as_str())))
+                .count();
+        matched as f32 / self.expectations_len() as f32
+    }
+
+    fn reciprocal_rank(&self, hits: &[EvalHit]) -> f32 {
+        hits.iter()
+            .position(|hit| self.is_relevant(hit))
+            .map(|i| 1.0 / (i + 1) as f32)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EvalHit {
+    pub path: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct EvalReport {
+    pub name: String,
+    pub top_k: usize,
+    pub questions: usize,
+    pub recall: f32,
+    pub mrr: f32,
+    pub latencies: Vec<Duration>,
+}
+
+impl EvalReport {
+    pub fn avg_latency(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+
+    pub fn p95_latency(&self) -> Duration {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        let index = (latencies.len() as f32 * 0.95).ceil() as usize;
+        latencies
+            .get(index.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct EvalVariant {
+    name: String,
+    rag_name: String,
+    top_k: Option<usize>,
+    reranker_model: Option<Option<String>>,
+    retrieval_strategy: Option<RetrievalStrategy>,
+    min_score: Option<f32>,
+}
+
+impl FromStr for EvalVariant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().split(':');
+        let rag_name = match parts.next() {
+            Some(v) if !v.is_empty() => v.to_string(),
+            _ => bail!("Invalid variant '{s}'"),
+        };
+        let mut variant = Self {
+            name: s.trim().to_string(),
+            rag_name,
+            top_k: None,
+            reranker_model: None,
+            retrieval_strategy: None,
+            min_score: None,
+        };
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid variant option '{part}'"))?;
+            let err = || format!("Invalid value for '{key}' in variant '{s}'");
+            match key {
+                "top_k" => variant.top_k = Some(value.parse().with_context(err)?),
+                "reranker_model" => {
+                    variant.reranker_model = Some(match value {
+                        "null" => None,
+                        _ => Some(value.to_string()),
+                    })
+                }
+                "retrieval_strategy" => {
+                    variant.retrieval_strategy = Some(value.parse().with_context(err)?)
+                }
+                "min_score" => variant.min_score = Some(value.parse().with_context(err)?),
+                _ => bail!("Unknown variant option '{key}'"),
+            }
+        }
+        Ok(variant)
+    }
+}
+
+pub async fn eval_rag(
+    config: &GlobalConfig,
+    variants: &str,
+    questions_path: &str,
+    abort_signal: AbortSignal,
+) -> Result<String> {
+    let variants = variants
+        .split(',')
+        .map(|v| v.parse::<EvalVariant>())
+        .collect::<Result<Vec<_>>>()?;
+    let questions = load_questions(Path::new(questions_path))?;
+    let mut reports = vec![];
+    for variant in &variants {
+        let rag = load_variant_rag(config, variant)?;
+        let (reranker_model, top_k) = rag.get_config();
+        let top_k = variant.top_k.unwrap_or(top_k);
+        let reranker_model = variant.reranker_model.clone().unwrap_or(reranker_model);
+        let (rag, reranker_model, abort_signal) = (&rag, reranker_model.as_deref(), &abort_signal);
+        let report = eval_questions(&variant.name, &questions, top_k, |text| async move {
+            rag.search_hits(&text, top_k, reranker_model, abort_signal.clone())
+                .await
+        })
+        .await?;
+        reports.push(report);
+    }
+    Ok(format_reports(&reports))
+}
+
+pub async fn eval_questions<F, Fut>(
+    name: &str,
+    questions: &[EvalQuestion],
+    top_k: usize,
+    mut search: F,
+) -> Result<EvalReport>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<Vec<EvalHit>>>,
+{
+    let mut recall = 0.0;
+    let mut mrr = 0.0;
+    let mut latencies = vec![];
+    for question in questions {
+        let start = Instant::now();
+        let mut hits = search(question.question.clone()).await?;
+        latencies.push(start.elapsed());
+        hits.truncate(top_k);
+        recall += question.recall(&hits);
+        mrr += question.reciprocal_rank(&hits);
+    }
+    let len = questions.len().max(1) as f32;
+    Ok(EvalReport {
+        name: name.to_string(),
+        top_k,
+        questions: questions.len(),
+        recall: recall / len,
+        mrr: mrr / len,
+        latencies,
+    })
+}
+
+impl Rag {
+    async fn search_hits(
+        &self,
+        text: &str,
+        top_k: usize,
+        rerank_model: Option<&str>,
+        abort_signal: AbortSignal,
+    ) -> Result<Vec<EvalHit>> {
+        let (_, ids) = self
+            .search(text, top_k, rerank_model, self.filter(), abort_signal)
+            .await?;
+        let output = ids
+            .into_iter()
+            .filter_map(|id| {
+                let (file_index, _) = id.split();
+                let file = self.data.files.get(&file_index)?;
+                Some(EvalHit {
+                    path: file.path.clone(),
+                    content: self.data.get(id)?.page_content.clone(),
+                })
+            })
+            .collect();
+        Ok(output)
+    }
+}
+
+fn load_variant_rag(config: &GlobalConfig, variant: &EvalVariant) -> Result<Rag> {
+    let path = config.read().rag_file(&variant.rag_name);
+    let mut rag = Rag::load(config, &variant.rag_name, &path)?;
+    if let Some(retrieval_strategy) = variant.retrieval_strategy {
+        rag.data.retrieval_strategy = retrieval_strategy;
+    }
+    if let Some(min_score) = variant.min_score {
+        rag.data.min_score = min_score;
+    }
+    Ok(rag)
+}
+
+fn load_questions(path: &Path) -> Result<Vec<EvalQuestion>> {
+    let err = || format!("Failed to load questions at '{}'", path.display());
+    let content = fs::read_to_string(path).with_context(err)?;
+    let mut questions = vec![];
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let question: EvalQuestion = serde_json::from_str(line)
+            .with_context(|| format!("Invalid question at line {}", i + 1))?;
+        if question.expectations_len() == 0 {
+            bail!(
+                "Question at line {} has neither expected_sources nor expected_texts",
+                i + 1
+            );
+        }
+        questions.push(question);
+    }
+    if questions.is_empty() {
+        bail!("No questions in '{}'", path.display());
+    }
+    Ok(questions)
+}
+
+fn match_source(path: &str, expected: &str) -> bool {
+    let path = path.replace('\\', "/");
+    let expected = expected.replace('\\', "/");
+    path == expected || path.ends_with(&format!("/{expected}"))
+}
+
+fn format_reports(reports: &[EvalReport]) -> String {
+    let name_width = reports
+        .iter()
+        .map(|v| v.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(7)
+        + 2;
+    let mut output = format!(
+        "{:<name_width$}{:>10}{:>12}{:>8}{:>14}{:>14}\n",
+        "variant", "questions", "recall@k", "mrr", "avg_latency", "p95_latency"
+    );
+    for report in reports {
+        output.push_str(&format!(
+            "{:<name_width$}{:>10}{:>12}{:>8.3}{:>14}{:>14}\n",
+            report.name,
+            report.questions,
+            format!("{:.3} (k={})", report.recall, report.top_k),
+            report.mrr,
+            format!("{}ms", report.avg_latency().as_millis()),
+            format!("{}ms", report.p95_latency().as_millis()),
+        ));
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rag::tests::stub_rag;
+
+    #[tokio::test]
+    async fn test_eval_questions() {
+        let mut rag = stub_rag();
+        for (path, content) in [
+            (
+                "/docs/install.md",
+                "Install aichat with cargo install aichat",
+            ),
+            (
+                "/docs/rag.md",
+                "RAG builds embeddings for documents and searches them",
+            ),
+            (
+                "/docs/roles.md",
+                "Roles define a system prompt for the model",
+            ),
+        ] {
+            rag.add_documents(path, vec![RagDocument::new(content)])
+                .await
+                .unwrap();
+        }
+        let questions: Vec<EvalQuestion> = [
+            r#"{"question":"how to install with cargo","expected_sources":["docs/install.md"]}"#,
+            r#"{"question":"what does a system prompt role do","expected_texts":["Roles define"]}"#,
+            r#"{"question":"unrelated question","expected_sources":["docs/missing.md"]}"#,
+        ]
+        .iter()
+        .map(|v| serde_json::from_str(v).unwrap())
+        .collect();
+        let (rag, abort_signal) = (&rag, create_abort_signal());
+        let report = eval_questions("stub", &questions, 1, |text| {
+            let abort_signal = abort_signal.clone();
+            async move { rag.search_hits(&text, 1, None, abort_signal).await }
+        })
+        .await
+        .unwrap();
+        assert_eq!(report.questions, 3);
+        assert!((report.recall - 2.0 / 3.0).abs() < 1e-6);
+        assert!((report.mrr - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(report.latencies.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_eval_variant() {
+        let variant: EvalVariant = "docs:top_k=10:retrieval_strategy=hyde".parse().unwrap();
+        assert_eq!(variant.rag_name, "docs");
+        assert_eq!(variant.top_k, Some(10));
+        assert_eq!(variant.retrieval_strategy, Some(RetrievalStrategy::Hyde));
+        assert!("docs:unknown=1".parse::<EvalVariant>().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use super::RagDocument;
+
+use crate::utils::{get_patch_extension, EXTENSION_METADATA, LOADER_METADATA};
+
+use anyhow::{bail, Result};
+use fancy_regex::Regex;
+use indexmap::IndexMap;
+use std::str::FromStr;
+
+pub const FILTER_KEYS: [&str; 4] = ["path:", "ext:", "lang:", "loader:"];
+
+#[derive(Debug, Clone)]
+pub struct RagFilter {
+    expr: String,
+    conditions: IndexMap<FilterKey, Vec<FilterValue>>,
+}
+
+impl RagFilter {
+    pub fn matches(&self, path: &str, document: &RagDocument) -> bool {
+        self.conditions.iter().all(|(key, values)| {
+            let targets: Vec<String> = match key {
+                FilterKey::Path => vec![path.replace('\\', "/")],
+                FilterKey::Extension => document
+                    .metadata
+                    .get(EXTENSION_METADATA)
+                    .cloned()
+                    .into_iter()
+                    .chain(get_patch_extension(path))
+                    .collect(),
+                FilterKey::Loader => document
+                    .metadata
+                    .get(LOADER_METADATA)
+                    .cloned()
+                    .into_iter()
+                    .collect(),
+                FilterKey::Metadata(name) => {
+                    document.metadata.get(name).cloned().into_iter().collect()
+                }
+            };
+            targets
+                .iter()
+                .any(|target| values.iter().any(|v| v.is_match(target)))
+        })
+    }
+
+    pub fn expr(&self) -> &str {
+        &self.expr
+    }
+}
+
+impl FromStr for RagFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut conditions: IndexMap<FilterKey, Vec<FilterValue>> = IndexMap::new();
+        for term in s.split_whitespace() {
+            let (key, value) = match term.split_once(':') {
+                Some((key, value)) if !key.is_empty() && !value.is_empty() => (key, value),
+                _ => bail!("Invalid filter '{term}', expected <key>:<value>"),
+            };
+            match key {
+                "path" => conditions
+                    .entry(FilterKey::Path)
+                    .or_default()
+                    .push(FilterValue::path(value)?),
+                "ext" | "extension" => {
+                    conditions
+                        .entry(FilterKey::Extension)
+                        .or_default()
+                        .push(FilterValue::Exact(
+                            value.trim_start_matches('.').to_lowercase(),
+                        ))
+                }
+                "lang" | "language" => conditions.entry(FilterKey::Extension).or_default().extend(
+                    language_extensions(value)
+                        .into_iter()
+                        .map(FilterValue::Exact),
+                ),
+                "loader" => conditions
+                    .entry(FilterKey::Loader)
+                    .or_default()
+                    .push(FilterValue::Exact(value.to_string())),
+                _ => conditions
+                    .entry(FilterKey::Metadata(key.to_string()))
+                    .or_default()
+                    .push(FilterValue::glob(value)?),
+            }
+        }
+        if conditions.is_empty() {
+            bail!("Empty filter");
+        }
+        Ok(Self {
+            expr: s.split_whitespace().collect::<Vec<_>>().join(" "),
+            conditions,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FilterKey {
+    Path,
+    Extension,
+    Loader,
+    Metadata(String),
+}
+
+#[derive(Debug, Clone)]
+enum FilterValue {
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl FilterValue {
+    fn path(value: &str) -> Result<Self> {
+        let value = value.replace('\\', "/");
+        let value = value.trim_end_matches('/');
+        let prefix = if value.starts_with('/') || value.contains(':') {
+            "^"
+        } else {
+            "(^|/)"
+        };
+        let pattern = format!("{prefix}{}(/.*)?$", glob_to_regex(value));
+        Ok(Self::Pattern(Regex::new(&pattern)?))
+    }
+
+    fn glob(value: &str) -> Result<Self> {
+        if value.contains(['*', '?', '{']) {
+            let pattern = format!("^{}$", glob_to_regex(value));
+            Ok(Self::Pattern(Regex::new(&pattern)?))
+        } else {
+            Ok(Self::Exact(value.to_string()))
+        }
+    }
+
+    fn is_match(&self, target: &str) -> bool {
+        match self {
+            FilterValue::Exact(value) => value.eq_ignore_ascii_case(target),
+            FilterValue::Pattern(re) => re.is_match(target).unwrap_or_default(),
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut output = String::new();
+    let mut chars = glob.chars().peekable();
+    let mut in_braces = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        output.push_str("(.*/)?");
+                    } else {
+                        output.push_str(".*");
+                    }
+                } else {
+                    output.push_str("[^/]*");
+                }
+            }
+            '?' => output.push_str("[^/]"),
+            '{' => {
+                in_braces = true;
+                output.push('(');
+            }
+            '}' if in_braces => {
+                in_braces = false;
+                output.push(')');
+            }
+            ',' if in_braces => output.push('|'),
+            _ => output.push_str(&fancy_regex::escape(&c.to_string())),
+        }
+    }
+    output
+}
+
+fn language_extensions(lang: &str) -> Vec<String> {
+    let lang = lang.to_lowercase();
+    let extensions: &[&str] = match lang.as_str() {
+        "c" => &["c", "h"],
+        "cpp" | "c++" => &["cc", "cpp", "cxx", "hpp", "hh", "h"],
+        "go" | "golang" => &["go"],
+        "java" => &["java"],
+        "javascript" | "js" => &["js", "mjs", "cjs", "jsx"],
+        "typescript" | "ts" => &["ts", "mts", "cts", "tsx"],
+        "php" => &["php"],
+        "proto" | "protobuf" => &["proto"],
+        "python" | "py" => &["py"],
+        "rst" => &["rst"],
+        "ruby" | "rb" => &["rb"],
+        "rust" | "rs" => &["rs"],
+        "scala" => &["scala"],
+        "swift" => &["swift"],
+        "markdown" | "md" => &["md", "mkd", "mdx"],
+        "latex" | "tex" => &["tex"],
+        "html" => &["htm", "html"],
+        "solidity" | "sol" => &["sol"],
+        _ => return vec![lang],
+    };
+    extensions.iter().map(|v| v.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(extension: &str) -> RagDocument {
+        let mut document = RagDocument::new("");
+        document
+            .metadata
+            .insert(EXTENSION_METADATA.into(), extension.into());
+        document
+    }
+
+    #[test]
+    fn test_rag_filter() {
+        let filter: RagFilter = "path:src/** lang:rust".parse().unwrap();
+        assert!(filter.matches("/repo/src/main.rs", &document("rs")));
+        assert!(filter.matches("/repo/src/rag/mod.rs", &document("rs")));
+        assert!(!filter.matches("/repo/src/main.py", &document("py")));
+        assert!(!filter.matches("/repo/docs/main.rs", &document("rs")));
+
+        let filter: RagFilter = "path:/repo/docs ext:md ext:txt".parse().unwrap();
+        assert!(filter.matches("/repo/docs/README.md", &document("md")));
+        assert!(filter.matches("/repo/docs/a/b.txt", &document("txt")));
+        assert!(!filter.matches("/other/repo/docs/README.md", &document("md")));
+
+        let filter: RagFilter = "path:*.{md,txt}".parse().unwrap();
+        assert!(filter.matches("/repo/README.md", &document("md")));
+        assert!(!filter.matches("/repo/README.rst", &document("rst")));
+
+        assert!("path".parse::<RagFilter>().is_err());
+    }
+}
@@ -1,23 +1,35 @@
+pub use self::filter::RagFilter;
 use self::splitter::*;
 
 use crate::client::*;
 use crate::config::*;
 use crate::utils::*;
 
+mod eval;
+mod filter;
 mod serde_vectors;
 mod splitter;
 
+pub use self::eval::eval_rag;
+pub use self::filter::FILTER_KEYS;
+
 use anyhow::{anyhow, bail, Context, Result};
 use bm25::{Language, SearchEngine, SearchEngineBuilder};
+use futures_util::future::try_join_all;
 use hnsw_rs::prelude::*;
 use indexmap::{IndexMap, IndexSet};
 use inquire::{required, validator::Validation, Confirm, Select, Text};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, env, fmt::Debug, fs, hash::Hash, path::Path, time::Duration};
+use std::{
+    collections::HashMap, env, fmt::Debug, fs, hash::Hash, path::Path, str::FromStr, time::Duration,
+};
 use tokio::time::sleep;
 
+const DEFAULT_FUSION_WEIGHTS: [f32; 2] = [1.125, 1.0];
+const CHAT_HISTORY_LIMIT: usize = 6;
+
 pub struct Rag {
     config: GlobalConfig,
     name: String,
@@ -26,6 +38,7 @@ pub struct Rag {
     hnsw: Hnsw<'static, f32, DistCosine>,
     bm25: SearchEngine<DocumentId>,
     data: RagData,
+    filter: Option<RagFilter>,
     last_sources: RwLock<Option<String>>,
 }
 
@@ -36,6 +49,7 @@ impl Debug for Rag {
             .field("path", &self.path)
             .field("embedding_model", &self.embedding_model)
             .field("data", &self.data)
+            .field("filter", &self.filter)
             .finish()
     }
 }
@@ -50,6 +64,7 @@ impl Clone for Rag {
             hnsw: self.data.build_hnsw(),
             bm25: self.data.build_bm25(),
             data: self.data.clone(),
+            filter: self.filter.clone(),
             last_sources: RwLock::new(None),
         }
     }
@@ -68,11 +83,15 @@ impl Rag {
         }
         println!("⚙ Initializing RAG...");
         let (embedding_model, chunk_size, chunk_overlap) = Self::create_config(config)?;
-        let (reranker_model, top_k) = {
+        let (reranker_model, top_k, retrieval_strategy) = {
             let config = config.read();
-            (config.rag_reranker_model.clone(), config.rag_top_k)
+            (
+                config.rag_reranker_model.clone(),
+                config.rag_top_k,
+                config.rag_retrieval_strategy,
+            )
         };
-        let data = RagData::new(
+        let mut data = RagData::new(
             embedding_model.id(),
             chunk_size,
             chunk_overlap,
@@ -80,6 +99,7 @@ impl Rag {
             top_k,
             embedding_model.max_batch_size(),
         );
+        data.retrieval_strategy = retrieval_strategy;
         let mut rag = Self::create(config, name, save_path, data)?;
         let mut paths = doc_paths.to_vec();
         if paths.is_empty() {
@@ -119,6 +139,7 @@ impl Rag {
             embedding_model,
             hnsw,
             bm25,
+            filter: None,
             last_sources: RwLock::new(None),
         };
         Ok(rag)
@@ -199,6 +220,18 @@ impl Rag {
         (self.data.reranker_model.clone(), self.data.top_k)
     }
 
+    pub fn retrieval_strategy(&self) -> RetrievalStrategy {
+        self.data.retrieval_strategy
+    }
+
+    pub fn filter(&self) -> Option<&RagFilter> {
+        self.filter.as_ref()
+    }
+
+    pub fn set_filter(&mut self, filter: Option<RagFilter>) {
+        self.filter = filter;
+    }
+
     pub fn get_last_sources(&self) -> Option<String> {
         self.last_sources.read().clone()
     }
@@ -240,6 +273,24 @@ impl Rag {
         Ok(())
     }
 
+    pub fn set_retrieval_strategy(&mut self, strategy: RetrievalStrategy) -> Result<()> {
+        self.data.retrieval_strategy = strategy;
+        self.save()?;
+        Ok(())
+    }
+
+    pub fn set_fusion_weights(&mut self, fusion_weights: [f32; 2]) -> Result<()> {
+        self.data.fusion_weights = fusion_weights;
+        self.save()?;
+        Ok(())
+    }
+
+    pub fn set_min_score(&mut self, min_score: f32) -> Result<()> {
+        self.data.min_score = min_score;
+        self.save()?;
+        Ok(())
+    }
+
     pub fn save(&self) -> Result<bool> {
         if self.is_temp() {
             return Ok(false);
@@ -275,8 +326,12 @@ impl Rag {
             "chunk_overlap": self.data.chunk_overlap,
             "reranker_model": self.data.reranker_model,
             "top_k": self.data.top_k,
+            "retrieval_strategy": self.data.retrieval_strategy,
+            "fusion_weights": self.data.fusion_weights,
+            "min_score": self.data.min_score,
             "batch_size": self.data.batch_size,
             "document_paths": self.data.document_paths,
+            "filter": self.filter.as_ref().map(|v| v.expr()),
             "files": files,
         });
         let output = serde_yaml::to_string(&data)
@@ -297,10 +352,11 @@ impl Rag {
         text: &str,
         top_k: usize,
         rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
         abort_signal: AbortSignal,
     ) -> Result<(String, Vec<DocumentId>)> {
         let ret = abortable_run_with_spinner(
-            self.hybird_search(text, top_k, rerank_model),
+            self.hybird_search(text, top_k, rerank_model, filter),
             "Searching",
             abort_signal,
         )
@@ -426,11 +482,20 @@ impl Rag {
             }
         }
 
+        let vision_model = {
+            let config = self.config.read();
+            match &config.rag_vision_model {
+                Some(model_id) => Some(Model::retrieve_model(&config, model_id, ModelType::Chat)?),
+                None => None,
+            }
+        };
+        let mut skipped_images = 0;
         let mut rag_files = vec![];
         for LoadedDocument {
             path,
             contents,
             mut metadata,
+            sections,
         } in loaded_documents
         {
             let hash = sha256(&contents);
@@ -449,8 +514,9 @@ impl Rag {
                 }
             }
             let extension = metadata
-                .swap_remove(EXTENSION_METADATA)
-                .unwrap_or_else(|| DEFAULT_EXTENSION.into());
+                .entry(EXTENSION_METADATA.into())
+                .or_insert_with(|| DEFAULT_EXTENSION.into())
+                .clone();
             let separator = get_separators(&extension);
             let splitter = RecursiveCharacterTextSplitter::new(
                 self.data.chunk_size,
@@ -459,8 +525,48 @@ impl Rag {
             );
 
             let split_options = SplitterChunkHeaderOptions::default();
-            let document = RagDocument::new(contents);
-            let split_documents = splitter.split_documents(&[document], &split_options);
+            let mut images = vec![];
+            let documents = if sections.is_empty() {
+                images.extend(
+                    extract_image_urls(&contents, &extension, &path)
+                        .into_iter()
+                        .map(|url| (url, metadata.clone())),
+                );
+                let mut document = RagDocument::new(contents);
+                document.metadata = metadata;
+                vec![document]
+            } else {
+                sections
+                    .into_iter()
+                    .map(|section| {
+                        let mut metadata = metadata.clone();
+                        metadata.extend(section.metadata);
+                        images.extend(
+                            section
+                                .images
+                                .into_iter()
+                                .map(|url| (url, metadata.clone())),
+                        );
+                        RagDocument {
+                            page_content: section.contents,
+                            metadata,
+                        }
+                    })
+                    .collect()
+            };
+            let mut split_documents = splitter.split_documents(&documents, &split_options);
+            if !images.is_empty() {
+                match &vision_model {
+                    Some(vision_model) => {
+                        progress(&spinner, format!("Captioning images in {path}"));
+                        split_documents.extend(
+                            self.caption_images(&loaders, vision_model, &path, images)
+                                .await,
+                        );
+                    }
+                    None => skipped_images += images.len(),
+                }
+            }
             rag_files.push(RagFile {
                 hash: hash.clone(),
                 path,
@@ -468,6 +574,15 @@ impl Rag {
             });
         }
 
+        if skipped_images > 0 {
+            println!(
+                "{}",
+                warning_text(&format!(
+                    "Skipped {skipped_images} images, set `rag_vision_model` to index them"
+                ))
+            );
+        }
+
         let mut next_file_id = self.data.next_file_id;
         let mut files = vec![];
         let mut document_ids = vec![];
@@ -506,36 +621,231 @@ impl Rag {
         Ok(())
     }
 
+    async fn caption_images(
+        &self,
+        loaders: &HashMap<String, String>,
+        vision_model: &Model,
+        path: &str,
+        images: Vec<(String, DocumentMetadata)>,
+    ) -> Vec<RagDocument> {
+        let mut documents = vec![];
+        for (url, mut metadata) in images {
+            match self.caption_image(loaders, vision_model, &url).await {
+                Ok((image, caption)) => {
+                    metadata.insert(IMAGE_METADATA.into(), image);
+                    documents.push(RagDocument {
+                        page_content: format!("Image from {path}:\n{caption}"),
+                        metadata,
+                    });
+                }
+                Err(err) => {
+                    let url = if url.starts_with("data:") {
+                        "<embedded>"
+                    } else {
+                        url.as_str()
+                    };
+                    let message = format!("⚠️ Failed to caption image {url} in {path}: {err}");
+                    println!("{}", warning_text(&message));
+                }
+            }
+        }
+        documents
+    }
+
+    async fn caption_image(
+        &self,
+        loaders: &HashMap<String, String>,
+        vision_model: &Model,
+        url: &str,
+    ) -> Result<(String, String)> {
+        let data_url = load_image(loaders, url).await?;
+        let image = if url.starts_with("data:") {
+            self.save_image(&data_url)?
+        } else {
+            url.to_string()
+        };
+        let mut role = self.config.read().retrieve_role(CAPTION_IMAGE_ROLE)?;
+        role.set_model(vision_model.clone());
+        let mut input = Input::from_str(&self.config, "", Some(role));
+        input.add_media(data_url, image.clone());
+        let caption = input.fetch_chat_text().await?;
+        Ok((image, caption.trim().to_string()))
+    }
+
+    fn save_image(&self, data_url: &str) -> Result<String> {
+        let (mime_type, data) = data_url
+            .strip_prefix("data:image/")
+            .and_then(|v| v.split_once(";base64,"))
+            .ok_or_else(|| anyhow!("Invalid image data url"))?;
+        let extension = match mime_type {
+            "jpeg" => "jpg",
+            v => v,
+        };
+        let dir = Path::new(&self.path).with_extension("").join("images");
+        let path = dir.join(format!("{}.{extension}", sha256(data_url)));
+        if !path.exists() {
+            let data = base64_decode(data)?;
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+            fs::write(&path, data)
+                .with_context(|| format!("Failed to save image to '{}'", path.display()))?;
+        }
+        Ok(path.display().to_string())
+    }
+
+    /// Add standalone documents, each stored as its own file under `path`.
+    /// Documents whose content is already stored are skipped.
+    pub async fn add_documents(
+        &mut self,
+        path: &str,
+        documents: Vec<RagDocument>,
+    ) -> Result<Vec<FileId>> {
+        let mut next_file_id = self.data.next_file_id;
+        let mut files = vec![];
+        let mut document_ids = vec![];
+        let mut texts = vec![];
+        for document in documents {
+            let hash = sha256(&document.page_content);
+            if self.data.files.values().any(|v| v.hash == hash)
+                || files
+                    .iter()
+                    .any(|(_, v): &(FileId, RagFile)| v.hash == hash)
+            {
+                continue;
+            }
+            document_ids.push(DocumentId::new(next_file_id, 0));
+            texts.push(document.page_content.clone());
+            files.push((
+                next_file_id,
+                RagFile {
+                    hash,
+                    path: path.to_string(),
+                    documents: vec![document],
+                },
+            ));
+            next_file_id += 1;
+        }
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
+        let file_ids = files.iter().map(|(id, _)| *id).collect();
+        let embeddings = self
+            .create_embeddings(EmbeddingsData::new(texts, false), None)
+            .await?;
+        self.data.add(next_file_id, files, document_ids, embeddings);
+        self.hnsw = self.data.build_hnsw();
+        self.bm25 = self.data.build_bm25();
+        Ok(file_ids)
+    }
+
+    pub fn remove_files(&mut self, file_ids: &[FileId]) -> Result<()> {
+        if let Some(id) = file_ids.iter().find(|v| !self.data.files.contains_key(*v)) {
+            bail!("Unknown id '{id}'");
+        }
+        self.data.del(file_ids.to_vec());
+        self.hnsw = self.data.build_hnsw();
+        self.bm25 = self.data.build_bm25();
+        Ok(())
+    }
+
+    /// Vector search only, returning the documents that score above `min_score`
+    pub async fn search_similar(
+        &self,
+        query: &str,
+        top_k: usize,
+        min_score: f32,
+    ) -> Result<Vec<String>> {
+        if self.data.vectors.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut results = self.vector_search(query, top_k, min_score, None).await?;
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let documents = results
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<IndexSet<_>>()
+            .into_iter()
+            .take(top_k)
+            .filter_map(|id| Some(self.data.get(id)?.page_content.clone()))
+            .collect();
+        Ok(documents)
+    }
+
+    pub fn get_images(&self, ids: &[DocumentId]) -> Vec<String> {
+        ids.iter()
+            .filter_map(|id| self.data.get(*id)?.metadata.get(IMAGE_METADATA).cloned())
+            .collect::<IndexSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
     async fn hybird_search(
         &self,
         query: &str,
         top_k: usize,
         rerank_model: Option<&str>,
+        filter: Option<&RagFilter>,
     ) -> Result<Vec<(DocumentId, String)>> {
+        let allowed_ids = match filter {
+            Some(filter) => {
+                let ids = self.data.filter_ids(filter);
+                debug!("filtered_ids: {}", ids.len());
+                if ids.is_empty() {
+                    return Ok(vec![]);
+                }
+                Some(ids)
+            }
+            None => None,
+        };
+        let allowed_ids = allowed_ids.as_ref();
+        let queries = self.expand_query(query).await;
+        debug!("retrieval_queries: {queries:?}");
+        let RetrievalQueries {
+            query,
+            vector_queries,
+            keyword_queries,
+        } = queries;
+        let query = query.as_str();
+        let min_score = self.data.min_score;
+        let [vector_weight, keyword_weight] = self.data.fusion_weights;
+
         let (vector_search_results, keyword_search_results) = tokio::join!(
-            self.vector_search(query, top_k, 0.0),
-            self.keyword_search(query, top_k, 0.0),
+            try_join_all(vector_queries.iter().map(|v| self.vector_search(
+                v,
+                top_k,
+                min_score,
+                allowed_ids
+            ))),
+            try_join_all(keyword_queries.iter().map(|v| self.keyword_search(
+                v,
+                top_k,
+                0.0,
+                allowed_ids
+            ))),
         );
 
         let vector_search_results = vector_search_results?;
         debug!("vector_search_results: {vector_search_results:?}",);
-        let vector_search_ids: Vec<DocumentId> =
-            vector_search_results.into_iter().map(|(v, _)| v).collect();
-
         let keyword_search_results = keyword_search_results?;
         debug!("keyword_search_results: {keyword_search_results:?}",);
-        let keyword_search_ids: Vec<DocumentId> =
-            keyword_search_results.into_iter().map(|(v, _)| v).collect();
+
+        let mut list_of_ids = vec![];
+        let mut list_of_weights = vec![];
+        for results in vector_search_results {
+            list_of_ids.push(results.into_iter().map(|(v, _)| v).collect::<Vec<_>>());
+            list_of_weights.push(vector_weight);
+        }
+        for results in keyword_search_results {
+            list_of_ids.push(results.into_iter().map(|(v, _)| v).collect::<Vec<_>>());
+            list_of_weights.push(keyword_weight);
+        }
 
         let ids = match rerank_model {
             Some(model_id) => {
                 let model =
                     Model::retrieve_model(&self.config.read(), model_id, ModelType::Reranker)?;
                 let client = init_client(&self.config, Some(model))?;
-                let ids: IndexSet<DocumentId> = [vector_search_ids, keyword_search_ids]
-                    .concat()
-                    .into_iter()
-                    .collect();
+                let ids: IndexSet<DocumentId> = list_of_ids.concat().into_iter().collect();
                 let mut documents = vec![];
                 let mut documents_ids = vec![];
                 for id in ids {
@@ -555,11 +865,7 @@ impl Rag {
                 ids
             }
             None => {
-                let ids = reciprocal_rank_fusion(
-                    vec![vector_search_ids, keyword_search_ids],
-                    vec![1.125, 1.0],
-                    top_k,
-                );
+                let ids = reciprocal_rank_fusion(list_of_ids, list_of_weights, top_k);
                 debug!("rrf_ids: {ids:?}");
                 ids
             }
@@ -574,11 +880,76 @@ impl Rag {
         Ok(output)
     }
 
+    async fn expand_query(&self, query: &str) -> RetrievalQueries {
+        let strategy = self.data.retrieval_strategy;
+        let mut queries = RetrievalQueries::new(query);
+        if strategy == RetrievalStrategy::Default {
+            return queries;
+        }
+        let chat_history = self
+            .config
+            .read()
+            .session
+            .as_ref()
+            .and_then(|v| v.chat_history(CHAT_HISTORY_LIMIT));
+        if let Some(chat_history) = &chat_history {
+            match self
+                .generate_query_text(REWRITE_QUERY_ROLE, query, Some(chat_history))
+                .await
+            {
+                Ok(text) if !text.is_empty() => queries = RetrievalQueries::new(&text),
+                Ok(_) => {}
+                Err(err) => warn!("Failed to rewrite the query: {err}"),
+            }
+        }
+        match strategy {
+            RetrievalStrategy::MultiQuery => {
+                match self
+                    .generate_query_text(MULTI_QUERY_ROLE, &queries.query, None)
+                    .await
+                {
+                    Ok(text) => queries.add_queries(&text),
+                    Err(err) => warn!("Failed to generate multiple queries: {err}"),
+                }
+            }
+            RetrievalStrategy::Hyde => {
+                match self
+                    .generate_query_text(HYDE_ROLE, &queries.query, None)
+                    .await
+                {
+                    Ok(text) => queries.set_hypothetical_document(text),
+                    Err(err) => warn!("Failed to generate hypothetical document: {err}"),
+                }
+            }
+            RetrievalStrategy::Default | RetrievalStrategy::Rewrite => {}
+        }
+        queries
+    }
+
+    async fn generate_query_text(
+        &self,
+        role_name: &str,
+        query: &str,
+        chat_history: Option<&str>,
+    ) -> Result<String> {
+        let role = self.config.read().retrieve_role(role_name)?;
+        let text = match chat_history {
+            Some(chat_history) => format!(
+                "<chat_history>\n{chat_history}</chat_history>\n\n<question>\n{query}\n</question>"
+            ),
+            None => query.to_string(),
+        };
+        let input = Input::from_str(&self.config, &text, Some(role));
+        let output = input.fetch_chat_text().await?;
+        Ok(output.trim().to_string())
+    }
+
     async fn vector_search(
         &self,
         query: &str,
         top_k: usize,
         min_score: f32,
+        allowed_ids: Option<&Vec<usize>>,
     ) -> Result<Vec<(DocumentId, f32)>> {
         let splitter = RecursiveCharacterTextSplitter::new(
             self.data.chunk_size,
@@ -588,9 +959,17 @@ impl Rag {
         let texts = splitter.split_text(query);
         let embeddings_data = EmbeddingsData::new(texts, true);
         let embeddings = self.create_embeddings(embeddings_data, None).await?;
-        let output = self
-            .hnsw
-            .parallel_search(&embeddings, top_k, 30)
+        let neighbours = match allowed_ids {
+            Some(allowed_ids) => embeddings
+                .iter()
+                .map(|v| {
+                    self.hnsw
+                        .search_filter(v, top_k, 30.max(top_k), Some(allowed_ids))
+                })
+                .collect(),
+            None => self.hnsw.parallel_search(&embeddings, top_k, 30),
+        };
+        let output = neighbours
             .into_iter()
             .flat_map(|list| {
                 list.into_iter()
@@ -613,8 +992,18 @@ impl Rag {
         query: &str,
         top_k: usize,
         min_score: f32,
+        allowed_ids: Option<&Vec<usize>>,
     ) -> Result<Vec<(DocumentId, f32)>> {
-        let results = self.bm25.search(query, top_k);
+        let results = match allowed_ids {
+            Some(allowed_ids) => self
+                .bm25
+                .search(query, None::<usize>)
+                .into_iter()
+                .filter(|v| allowed_ids.binary_search(&v.document.id.0).is_ok())
+                .take(top_k)
+                .collect(),
+            None => self.bm25.search(query, top_k),
+        };
         let output: Vec<(DocumentId, f32)> = results
             .into_iter()
             .filter_map(|v| {
@@ -696,6 +1085,13 @@ pub struct RagData {
     pub chunk_overlap: usize,
     pub reranker_model: Option<String>,
     pub top_k: usize,
+    #[serde(default)]
+    pub retrieval_strategy: RetrievalStrategy,
+    #[serde(default = "default_fusion_weights")]
+    pub fusion_weights: [f32; 2],
+    /// Minimum similarity for vector search results; BM25 keyword scores are unbounded and never filtered
+    #[serde(default)]
+    pub min_score: f32,
     pub batch_size: Option<usize>,
     pub next_file_id: FileId,
     pub document_paths: Vec<String>,
@@ -712,6 +1108,9 @@ impl Debug for RagData {
             .field("chunk_overlap", &self.chunk_overlap)
             .field("reranker_model", &self.reranker_model)
             .field("top_k", &self.top_k)
+            .field("retrieval_strategy", &self.retrieval_strategy)
+            .field("fusion_weights", &self.fusion_weights)
+            .field("min_score", &self.min_score)
             .field("batch_size", &self.batch_size)
             .field("next_file_id", &self.next_file_id)
             .field("document_paths", &self.document_paths)
@@ -735,6 +1134,9 @@ impl RagData {
             chunk_overlap,
             reranker_model,
             top_k,
+            retrieval_strategy: Default::default(),
+            fusion_weights: DEFAULT_FUSION_WEIGHTS,
+            min_score: 0.0,
             batch_size,
             next_file_id: 0,
             document_paths: Default::default(),
@@ -774,6 +1176,26 @@ impl RagData {
             .extend(document_ids.into_iter().zip(embeddings));
     }
 
+    pub fn list_documents(&self) -> Vec<(FileId, &str, &RagDocument)> {
+        self.files
+            .iter()
+            .flat_map(|(id, file)| file.documents.iter().map(|v| (*id, file.path.as_str(), v)))
+            .collect()
+    }
+
+    pub fn filter_ids(&self, filter: &RagFilter) -> Vec<usize> {
+        let mut ids = vec![];
+        for (file_index, file) in self.files.iter() {
+            for (document_index, document) in file.documents.iter().enumerate() {
+                if filter.matches(&file.path, document) {
+                    ids.push(DocumentId::new(*file_index, document_index).0);
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids
+    }
+
     pub fn build_hnsw(&self) -> Hnsw<'static, f32, DistCosine> {
         let hnsw = Hnsw::new(32, self.vectors.len(), 16, 200, DistCosine {});
         let list: Vec<_> = self.vectors.iter().map(|(k, v)| (v, k.0)).collect();
@@ -796,6 +1218,90 @@ impl RagData {
     }
 }
 
+fn default_fusion_weights() -> [f32; 2] {
+    DEFAULT_FUSION_WEIGHTS
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetrievalStrategy {
+    #[default]
+    Default,
+    Rewrite,
+    MultiQuery,
+    Hyde,
+}
+
+impl RetrievalStrategy {
+    pub const ALL: [RetrievalStrategy; 4] = [
+        RetrievalStrategy::Default,
+        RetrievalStrategy::Rewrite,
+        RetrievalStrategy::MultiQuery,
+        RetrievalStrategy::Hyde,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetrievalStrategy::Default => "default",
+            RetrievalStrategy::Rewrite => "rewrite",
+            RetrievalStrategy::MultiQuery => "multi-query",
+            RetrievalStrategy::Hyde => "hyde",
+        }
+    }
+}
+
+impl std::fmt::Display for RetrievalStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for RetrievalStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|v| v.as_str() == s)
+            .ok_or_else(|| anyhow!("Invalid retrieval strategy '{s}'"))
+    }
+}
+
+#[derive(Debug)]
+struct RetrievalQueries {
+    query: String,
+    vector_queries: Vec<String>,
+    keyword_queries: Vec<String>,
+}
+
+impl RetrievalQueries {
+    fn new(query: &str) -> Self {
+        Self {
+            query: query.to_string(),
+            vector_queries: vec![query.to_string()],
+            keyword_queries: vec![query.to_string()],
+        }
+    }
+
+    /// Add one query per line, skipping blank lines and duplicates
+    fn add_queries(&mut self, text: &str) {
+        for line in text.lines().map(|v| v.trim()) {
+            if line.is_empty() || self.vector_queries.iter().any(|v| v == line) {
+                continue;
+            }
+            self.vector_queries.push(line.to_string());
+            self.keyword_queries.push(line.to_string());
+        }
+    }
+
+    /// Search vectors by the hypothetical document, keeping the keyword query as is
+    fn set_hypothetical_document(&mut self, text: String) {
+        if !text.is_empty() {
+            self.vector_queries = vec![text];
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RagFile {
     hash: String,
@@ -1011,3 +1517,192 @@ fn reciprocal_rank_fusion(
         .map(|(v, _)| v)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, LazyLock};
+
+    const STUB_DIMENSIONS: usize = 64;
+
+    /// A local OpenAI-compatible embeddings endpoint shared by every test
+    static STUB_API_BASE: LazyLock<String> = LazyLock::new(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = serve_embeddings(stream);
+            }
+        });
+        format!("http://{addr}/v1")
+    });
+
+    /// Embed text as a normalized bag of hashed words
+    fn stub_embed(text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; STUB_DIMENSIONS];
+        for word in text.split(|c: char| !c.is_alphanumeric()) {
+            if word.is_empty() {
+                continue;
+            }
+            let hash = word
+                .to_lowercase()
+                .bytes()
+                .fold(5381usize, |acc, b| acc.wrapping_mul(33) ^ b as usize);
+            vector[hash % STUB_DIMENSIONS] += 1.0;
+        }
+        let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt().max(1e-6);
+        vector.into_iter().map(|v| v / norm).collect()
+    }
+
+    fn serve_embeddings(mut stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                if key.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or_default();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        let data: Vec<_> = body["input"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|v| json!({ "embedding": stub_embed(v.as_str().unwrap_or_default()) }))
+            .collect();
+        let body = json!({ "data": data }).to_string();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// An empty rag whose embeddings come from the stub endpoint
+    pub(super) fn stub_rag() -> Rag {
+        let clients = format!(
+            r#"
+- type: openai-compatible
+  name: stub
+  api_base: {}
+  models:
+    - name: embed
+      type: embedding
+"#,
+            *STUB_API_BASE
+        );
+        let config = Config {
+            clients: serde_yaml::from_str(&clients).unwrap(),
+            ..Default::default()
+        };
+        let config = Arc::new(RwLock::new(config));
+        let data = RagData::new("stub:embed".into(), 1000, 0, None, 5, None);
+        Rag::create(&config, "test", Path::new("test.yaml"), data).unwrap()
+    }
+
+    #[test]
+    fn test_retrieval_strategy() {
+        for strategy in RetrievalStrategy::ALL {
+            assert_eq!(
+                strategy.to_string().parse::<RetrievalStrategy>().unwrap(),
+                strategy
+            );
+        }
+        assert_eq!(
+            "multi-query".parse::<RetrievalStrategy>().unwrap(),
+            RetrievalStrategy::MultiQuery
+        );
+        assert!("multi_query".parse::<RetrievalStrategy>().is_err());
+        assert!("".parse::<RetrievalStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_retrieval_queries() {
+        let mut queries = RetrievalQueries::new("rust async");
+        queries.add_queries("rust async\n\n  tokio runtime  \nfutures\ntokio runtime");
+        assert_eq!(
+            queries.vector_queries,
+            ["rust async", "tokio runtime", "futures"]
+        );
+        assert_eq!(queries.keyword_queries, queries.vector_queries);
+
+        let mut queries = RetrievalQueries::new("rust async");
+        queries.set_hypothetical_document(String::new());
+        assert_eq!(queries.vector_queries, ["rust async"]);
+        queries.set_hypothetical_document("Rust futures are polled by an executor.".into());
+        assert_eq!(
+            queries.vector_queries,
+            ["Rust futures are polled by an executor."]
+        );
+        assert_eq!(queries.keyword_queries, ["rust async"]);
+        assert_eq!(queries.query, "rust async");
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let a = DocumentId::new(0, 0);
+        let b = DocumentId::new(0, 1);
+        let c = DocumentId::new(1, 0);
+        let lists = || vec![vec![a, b], vec![b, c]];
+        assert_eq!(
+            reciprocal_rank_fusion(lists(), vec![1.0, 1.0], 3),
+            [b, a, c]
+        );
+        assert_eq!(
+            reciprocal_rank_fusion(lists(), vec![1.0, 0.0], 3),
+            [a, b, c]
+        );
+        assert_eq!(reciprocal_rank_fusion(lists(), vec![0.2, 1.0], 2), [b, c]);
+        assert_eq!(
+            reciprocal_rank_fusion(lists(), DEFAULT_FUSION_WEIGHTS.to_vec(), 1),
+            [b]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_search_remove_documents() {
+        let mut rag = stub_rag();
+        let file_ids = rag
+            .add_documents(
+                "memory",
+                vec![
+                    RagDocument::new("the user prefers rust over go"),
+                    RagDocument::new("the user lives in paris"),
+                    RagDocument::new("the user prefers rust over go"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(file_ids.len(), 2);
+        assert_eq!(
+            rag.search_similar("which language does the user prefer, rust or go", 1, 0.0)
+                .await
+                .unwrap(),
+            ["the user prefers rust over go"]
+        );
+        assert!(rag
+            .search_similar("weather forecast tomorrow", 5, 0.5)
+            .await
+            .unwrap()
+            .is_empty());
+
+        rag.remove_files(&file_ids[..1]).unwrap();
+        assert_eq!(
+            rag.search_similar("the user prefers rust over go", 5, 0.0)
+                .await
+                .unwrap(),
+            ["the user lives in paris"]
+        );
+        assert!(rag.remove_files(&file_ids[..1]).is_err());
+    }
+}
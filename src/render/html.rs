@@ -0,0 +1,123 @@
+use super::markdown::{LANG_MAPS, SYNTAXES};
+
+use crate::utils::decode_bin;
+
+use anyhow::{Context, Result};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag, TagEnd};
+use syntect::highlighting::Theme;
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+/// Render markdown to html, highlighting fenced code blocks with the theme
+///
+/// Raw html in the markdown is escaped and links and images keep only safe urls,
+/// so chat content can't inject markup or scripts.
+pub struct HtmlRender {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+}
+
+impl HtmlRender {
+    pub fn init(theme: Theme) -> Result<Self> {
+        let syntax_set: SyntaxSet =
+            decode_bin(SYNTAXES).context("HtmlRender: invalid syntaxes binary")?;
+        Ok(Self { syntax_set, theme })
+    }
+
+    pub fn render(&self, text: &str) -> Result<String> {
+        let mut events = vec![];
+        let mut code: Option<(String, String)> = None;
+        for event in Parser::new_ext(text, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH) {
+            match event {
+                Event::Start(Tag::CodeBlock(kind)) => {
+                    let lang = match kind {
+                        CodeBlockKind::Fenced(lang) => lang
+                            .split_whitespace()
+                            .next()
+                            .unwrap_or_default()
+                            .to_string(),
+                        CodeBlockKind::Indented => String::new(),
+                    };
+                    code = Some((lang, String::new()));
+                }
+                Event::Text(value) if code.is_some() => {
+                    if let Some((_, text)) = code.as_mut() {
+                        text.push_str(&value);
+                    }
+                }
+                Event::End(TagEnd::CodeBlock) => {
+                    if let Some((lang, text)) = code.take() {
+                        let syntax = find_syntax(&self.syntax_set, &lang)
+                            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+                        let output = highlighted_html_for_string(
+                            &text,
+                            &self.syntax_set,
+                            syntax,
+                            &self.theme,
+                        )
+                        .context("Failed to highlight code")?;
+                        events.push(Event::Html(CowStr::from(output)));
+                    }
+                }
+                Event::Html(value) | Event::InlineHtml(value) => events.push(Event::Text(value)),
+                Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => events.push(Event::Start(Tag::Link {
+                    link_type,
+                    dest_url: safe_url(dest_url),
+                    title,
+                    id,
+                })),
+                Event::Start(Tag::Image {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => events.push(Event::Start(Tag::Image {
+                    link_type,
+                    dest_url: safe_url(dest_url),
+                    title,
+                    id,
+                })),
+                _ => events.push(event),
+            }
+        }
+        let mut output = String::new();
+        html::push_html(&mut output, events.into_iter());
+        Ok(output)
+    }
+}
+
+/// Keep relative, http(s), mailto and inline image urls, and neutralize the others like `javascript:`
+fn safe_url(url: CowStr) -> CowStr {
+    let scheme = match url.find([':', '/', '?', '#']) {
+        Some(index) if url[index..].starts_with(':') => url[..index].to_ascii_lowercase(),
+        _ => return url,
+    };
+    let safe = match scheme.as_str() {
+        "http" | "https" | "mailto" => true,
+        "data" => url[5..].to_ascii_lowercase().starts_with("image/"),
+        _ => false,
+    };
+    if safe {
+        url
+    } else {
+        CowStr::from("#")
+    }
+}
+
+fn find_syntax<'a>(syntax_set: &'a SyntaxSet, lang: &str) -> Option<&'a SyntaxReference> {
+    if lang.is_empty() {
+        return None;
+    }
+    if let Some(new_lang) = LANG_MAPS.get(&lang.to_ascii_lowercase()) {
+        syntax_set.find_syntax_by_name(new_lang)
+    } else {
+        syntax_set
+            .find_syntax_by_token(lang)
+            .or_else(|| syntax_set.find_syntax_by_extension(lang))
+    }
+}
@@ -11,9 +11,9 @@ use syntect::parsing::SyntaxSet;
 use syntect::{easy::HighlightLines, parsing::SyntaxReference};
 
 /// Comes from <https://github.com/sharkdp/bat/raw/5e77ca37e89c873e4490b42ff556370dc5c6ba4f/assets/syntaxes.bin>
-const SYNTAXES: &[u8] = include_bytes!("../../assets/syntaxes.bin");
+pub(super) const SYNTAXES: &[u8] = include_bytes!("../../assets/syntaxes.bin");
 
-static LANG_MAPS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
+pub(super) static LANG_MAPS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
     let mut m = HashMap::new();
     m.insert("csharp".into(), "C#".into());
     m.insert("php".into(), "PHP Source".into());
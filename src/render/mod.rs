@@ -1,6 +1,8 @@
+mod html;
 mod markdown;
 mod stream;
 
+pub use self::html::HtmlRender;
 pub use self::markdown::{MarkdownRender, RenderOptions};
 use self::stream::{markdown_stream, raw_stream};
 
@@ -1,9 +1,12 @@
 use super::{ReplCommand, REPL_COMMANDS};
 
-use crate::{config::GlobalConfig, utils::fuzzy_filter};
+use crate::{
+    config::{Config, GlobalConfig},
+    utils::{fuzzy_filter, resolve_home_dir},
+};
 
 use reedline::{Completer, Span, Suggestion};
-use std::collections::HashMap;
+use std::{collections::HashMap, fs::read_dir};
 
 impl Completer for ReplCompleter {
     fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
@@ -23,6 +26,15 @@ impl Completer for ReplCompleter {
         }
         let (cmd, cmd_start) = parts[0];
 
+        let (last_part, last_start) = parts[parts_len - 1];
+        if let Some(value) = last_part.strip_prefix('@') {
+            let span = Span::new(last_start, pos);
+            return complete_mention(value)
+                .iter()
+                .map(|(value, description)| create_suggestion(value, description, span))
+                .collect();
+        }
+
         if !cmd.starts_with('.') {
             return suggestions;
         }
@@ -124,6 +136,45 @@ fn create_suggestion(value: &str, description: &str, span: Span) -> Suggestion {
     }
 }
 
+fn complete_mention(value: &str) -> Vec<(String, &'static str)> {
+    let mut values = vec![];
+    if "rag:".starts_with(value) || value.starts_with("rag:") {
+        let filter = value.strip_prefix("rag:").unwrap_or_default();
+        values.extend(
+            Config::list_rags()
+                .into_iter()
+                .filter(|name| name.starts_with(filter))
+                .map(|name| (format!("@rag:{name}"), "rag")),
+        );
+    }
+    let (dir, prefix) = match value.rfind('/') {
+        Some(i) => (&value[..=i], &value[i + 1..]),
+        None => ("", value),
+    };
+    let read_path = if dir.is_empty() {
+        ".".to_string()
+    } else {
+        resolve_home_dir(dir)
+    };
+    let mut paths = vec![];
+    if let Ok(rd) = read_dir(read_path) {
+        for entry in rd.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                continue;
+            }
+            if entry.path().is_dir() {
+                paths.push((format!("@{dir}{name}/"), "dir"));
+            } else {
+                paths.push((format!("@{dir}{name}"), "file"));
+            }
+        }
+    }
+    paths.sort();
+    values.extend(paths);
+    values
+}
+
 fn split_line(line: &str) -> Vec<(&str, usize)> {
     let mut parts = vec![];
     let mut part_start = None;
@@ -1,12 +1,16 @@
 use super::REPL_COMMANDS;
 
-use crate::{config::GlobalConfig, utils::NO_COLOR};
+use crate::{
+    config::{parse_mentions, GlobalConfig},
+    utils::NO_COLOR,
+};
 
 use nu_ansi_term::{Color, Style};
 use reedline::{Highlighter, StyledText};
 
 const DEFAULT_COLOR: Color = Color::Default;
 const MATCH_COLOR: Color = Color::Green;
+const MENTION_COLOR: Color = Color::Cyan;
 
 pub struct ReplHighlighter;
 
@@ -37,13 +41,38 @@ impl Highlighter for ReplHighlighter {
             });
             let buffer_split: Vec<&str> = line.splitn(2, &longest_match).collect();
 
-            styled_text.push((Style::new().fg(DEFAULT_COLOR), buffer_split[0].to_string()));
+            push_text(&mut styled_text, buffer_split[0]);
             styled_text.push((Style::new().fg(MATCH_COLOR), longest_match));
-            styled_text.push((Style::new().fg(DEFAULT_COLOR), buffer_split[1].to_string()));
+            push_text(&mut styled_text, buffer_split[1]);
         } else {
-            styled_text.push((Style::new().fg(DEFAULT_COLOR), line.to_string()));
+            push_text(&mut styled_text, line);
         }
 
         styled_text
     }
 }
+
+fn push_text(styled_text: &mut StyledText, text: &str) {
+    let mut start = 0;
+    for (i, word) in text.split(' ').scan(0, |offset, word| {
+        let i = *offset;
+        *offset += word.len() + 1;
+        Some((i, word))
+    }) {
+        if !word.starts_with('@') {
+            continue;
+        }
+        let (paths, rag_name) = parse_mentions(word);
+        if paths.is_empty() && rag_name.is_none() {
+            continue;
+        }
+        if i > start {
+            styled_text.push((Style::new().fg(DEFAULT_COLOR), text[start..i].to_string()));
+        }
+        styled_text.push((Style::new().fg(MENTION_COLOR), word.to_string()));
+        start = i + word.len();
+    }
+    if start < text.len() || text.is_empty() {
+        styled_text.push((Style::new().fg(DEFAULT_COLOR), text[start..].to_string()));
+    }
+}
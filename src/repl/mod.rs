@@ -8,15 +8,17 @@ use self::prompt::ReplPrompt;
 
 use crate::client::{call_chat_completions, call_chat_completions_streaming};
 use crate::config::{
-    macro_execute, AgentVariables, AssertState, Config, GlobalConfig, Input, LastMessage,
-    StateFlags,
+    extract_memories, forget_memories, list_memories, macro_execute, remember, template_input,
+    AgentVariables, AssertState, Config, GlobalConfig, Input, LastMessage, StateFlags,
+    REMEMBER_SOURCE,
 };
+use crate::mcp::is_mcp_path;
 use crate::render::render_error;
 use crate::utils::{
     abortable_run_with_spinner, create_abort_signal, dimmed_text, set_text, temp_file, AbortSignal,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use crossterm::cursor::SetCursorStyle;
 use fancy_regex::Regex;
 use reedline::CursorConfig;
@@ -31,7 +33,7 @@ use std::{env, process};
 
 const MENU_NAME: &str = "completion_menu";
 
-static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
+static REPL_COMMANDS: LazyLock<[ReplCommand; 49]> = LazyLock::new(|| {
     [
         ReplCommand::new(".help", "Show this help guide", AssertState::pass()),
         ReplCommand::new(".info", "Show system info", AssertState::pass()),
@@ -99,6 +101,56 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             "Modify current session",
             AssertState::True(StateFlags::SESSION_EMPTY | StateFlags::SESSION),
         ),
+        ReplCommand::new(
+            ".search",
+            "Search messages of all sessions",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(
+            ".remember",
+            "Save a fact to long-term memory",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(
+            ".memory",
+            "List or forget long-term memories",
+            AssertState::pass(),
+        ),
+        ReplCommand::new(
+            ".history",
+            "Show the numbered turns of the session",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".rewind",
+            "Truncate the session to a turn",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".edit-message",
+            "Edit a user message and re-run from there",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".drop",
+            "Remove a turn from the session",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".pin",
+            "Keep a turn out of compression",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".unpin",
+            "Allow a pinned turn to be compressed",
+            AssertState::True(StateFlags::SESSION),
+        ),
+        ReplCommand::new(
+            ".branch",
+            "List, fork, switch or diff session branches",
+            AssertState::True(StateFlags::SESSION),
+        ),
         ReplCommand::new(
             ".save session",
             "Save current session to file",
@@ -150,6 +202,11 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             "Show citation sources used in last query",
             AssertState::True(StateFlags::RAG),
         ),
+        ReplCommand::new(
+            ".rag-filter",
+            "Restrict RAG search by path, extension or metadata",
+            AssertState::True(StateFlags::RAG),
+        ),
         ReplCommand::new(
             ".info rag",
             "Show RAG info",
@@ -161,6 +218,11 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             AssertState::TrueFalse(StateFlags::RAG, StateFlags::AGENT),
         ),
         ReplCommand::new(".macro", "Execute a macro", AssertState::pass()),
+        ReplCommand::new(
+            ".template",
+            "Fill a prompt template and send it",
+            AssertState::pass(),
+        ),
         ReplCommand::new(
             ".file",
             "Include files, directories, URLs or commands",
@@ -177,6 +239,11 @@ static REPL_COMMANDS: LazyLock<[ReplCommand; 36]> = LazyLock::new(|| {
             AssertState::pass(),
         ),
         ReplCommand::new(".copy", "Copy last response", AssertState::pass()),
+        ReplCommand::new(
+            ".trace",
+            "Show recorded requests and tool calls",
+            AssertState::pass(),
+        ),
         ReplCommand::new(".set", "Modify runtime settings", AssertState::pass()),
         ReplCommand::new(
             ".delete",
@@ -256,6 +323,8 @@ Type ".help" for additional help.
                 _ => {}
             }
         }
+        self.abort_signal.reset();
+        extract_session_memories(&self.config, self.abort_signal.clone()).await;
         self.config.write().exit_session()?;
         Ok(())
     }
@@ -424,12 +493,18 @@ pub async fn run_repl_command(
             ".role" => match args {
                 Some(args) => match args.split_once(['\n', ' ']) {
                     Some((name, text)) => {
+                        if is_mcp_path(name.trim()) {
+                            Config::load_mcp(config).await;
+                        }
                         let role = config.read().retrieve_role(name.trim())?;
                         let input = Input::from_str(config, text, Some(role));
                         ask(config, abort_signal.clone(), input, false).await?;
                     }
                     None => {
                         let name = args;
+                        if is_mcp_path(name) {
+                            Config::load_mcp(config).await;
+                        }
                         if !Config::has_role(name) {
                             config.write().new_role(name)?;
                         }
@@ -562,6 +637,117 @@ pub async fn run_repl_command(
                     println!(r#"Usage: .empty session"#)
                 }
             },
+            ".search" => match args {
+                Some(query) => {
+                    Config::search_history(config, query)?;
+                }
+                None => println!("Usage: .search <query>"),
+            },
+            ".remember" => match args {
+                Some(text) => {
+                    let added = abortable_run_with_spinner(
+                        remember(config, vec![text.to_string()], REMEMBER_SOURCE),
+                        "Remembering",
+                        abort_signal.clone(),
+                    )
+                    .await?;
+                    if added > 0 {
+                        println!("✓ Remembered.");
+                    } else {
+                        println!("Already remembered.");
+                    }
+                }
+                None => println!("Usage: .remember <text>"),
+            },
+            ".memory" => match split_first_arg(args) {
+                None | Some(("list", None)) => {
+                    let memories = list_memories()?;
+                    if memories.is_empty() {
+                        println!("No memories");
+                    }
+                    for (id, text, origin) in memories {
+                        println!("{id:>4}  {text} {}", dimmed_text(&format!("({origin})")));
+                    }
+                }
+                Some(("forget", Some(ids))) => {
+                    let ids: Vec<&str> = ids.split_whitespace().collect();
+                    let count = forget_memories(config, &ids)?;
+                    println!("✓ Forgot {count} memories.");
+                }
+                _ => println!(
+                    r#"Usage: .memory [list|forget] ...
+
+.memory                     List long-term memories
+.memory forget <id>...      Forget memories by id, or all of them with `all`"#
+                ),
+            },
+            ".history" => {
+                let output = config.read().session_history()?;
+                println!("{output}");
+            }
+            ".rewind" => match args.and_then(|v| v.parse::<usize>().ok()) {
+                Some(turn) => {
+                    config.write().rewind_session(turn)?;
+                    println!("✓ Rewound the session to turn {turn}.");
+                }
+                None => println!("Usage: .rewind <turn>"),
+            },
+            ".edit-message" => match args.and_then(|v| v.parse::<usize>().ok()) {
+                Some(turn) => {
+                    let text = config.write().edit_session_message(turn)?;
+                    let input = Input::from_str(config, &text, None);
+                    ask(config, abort_signal.clone(), input, true).await?;
+                }
+                None => println!("Usage: .edit-message <turn>"),
+            },
+            ".drop" => match args.and_then(|v| v.parse::<usize>().ok()) {
+                Some(turn) => {
+                    config.write().drop_session_turn(turn)?;
+                    println!("✓ Dropped turn {turn} from the session.");
+                }
+                None => println!("Usage: .drop <turn>"),
+            },
+            ".pin" | ".unpin" => match args.and_then(|v| v.parse::<usize>().ok()) {
+                Some(turn) => {
+                    let pinned = cmd == ".pin";
+                    config.write().pin_session_turn(turn, pinned)?;
+                }
+                None => println!("Usage: {cmd} <turn>"),
+            },
+            ".branch" => match split_first_arg(args) {
+                None | Some(("list", None)) => {
+                    let output = config.read().session_branches()?;
+                    println!("{output}");
+                }
+                Some(("fork", extra)) => {
+                    let mut name = None;
+                    let mut turns = None;
+                    for arg in extra.unwrap_or_default().split_whitespace() {
+                        match arg.parse::<usize>() {
+                            Ok(v) => turns = Some(v),
+                            Err(_) => name = Some(arg),
+                        }
+                    }
+                    config.write().fork_session_branch(name, turns)?;
+                }
+                Some(("switch", Some(name))) => {
+                    config.write().switch_session_branch(name.trim())?;
+                }
+                Some(("diff", Some(names))) => {
+                    let mut names = names.split_whitespace();
+                    let name = names.next().unwrap_or_default();
+                    let output = config.read().diff_session_branches(name, names.next())?;
+                    println!("{output}");
+                }
+                _ => println!(
+                    r#"Usage: .branch [list|fork|switch|diff] ...
+
+.branch                     List branches of the session
+.branch fork [name] [turn]  Fork the current branch, keeping the first <turn> turns
+.branch switch <name>       Switch to a branch
+.branch diff <name> [name]  Compare two branches, or the current branch with another"#
+                ),
+            },
             ".rebuild" => match args {
                 Some("rag") => {
                     Config::rebuild_rag(config, abort_signal.clone()).await?;
@@ -579,6 +765,32 @@ pub async fn run_repl_command(
                     println!(r#"Usage: .sources rag"#)
                 }
             },
+            ".rag-filter" => match args {
+                Some("clear") => {
+                    Config::set_rag_filter(config, None)?;
+                }
+                Some(args) => {
+                    Config::set_rag_filter(config, Some(args))?;
+                }
+                None => {
+                    let filter = config
+                        .read()
+                        .rag
+                        .as_ref()
+                        .and_then(|v| v.filter().map(|v| v.expr().to_string()));
+                    if let Some(filter) = filter {
+                        println!("Current filter: {filter}\n");
+                    }
+                    println!(
+                        r#"Usage: .rag-filter <key>:<value>... | clear
+
+.rag-filter path:src/** lang:rust
+.rag-filter path:docs/api ext:md ext:mdx
+.rag-filter loader:jina
+.rag-filter clear"#
+                    )
+                }
+            },
             ".macro" => match split_first_arg(args) {
                 Some((name, extra)) => {
                     if !Config::has_macro(name) && extra.is_none() {
@@ -589,6 +801,20 @@ pub async fn run_repl_command(
                 }
                 None => println!("Usage: .macro <name> <text>..."),
             },
+            ".template" => match split_first_arg(args) {
+                Some((name, extra)) => {
+                    if !Config::has_template(name) && extra.is_none() {
+                        config.write().new_template(name)?;
+                    } else {
+                        let input =
+                            template_input(config, name, extra, abort_signal.clone()).await?;
+                        ask(config, abort_signal.clone(), input, true).await?;
+                    }
+                }
+                None => println!(
+                    "Usage: .template <name> [<var>=<value>]... [<file>]... [-- <text>...]"
+                ),
+            },
             ".file" => match args {
                 Some(args) => {
                     let (files, text) = split_args_text(args, cfg!(windows));
@@ -657,9 +883,21 @@ pub async fn run_repl_command(
                     Config::delete(config, args)?;
                 }
                 _ => {
-                    println!("Usage: .delete <role|session|rag|macro|agent-data>")
+                    println!("Usage: .delete <role|session|rag|macro|template|agent-data>")
                 }
             },
+            ".trace" => {
+                let count = match args {
+                    Some(v) => v
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|v| *v > 0)
+                        .ok_or_else(|| anyhow!("Usage: .trace [turns]"))?,
+                    None => 1,
+                };
+                let output = config.read().trace_info(count)?;
+                println!("{output}");
+            }
             ".copy" => {
                 let output = match config
                     .read()
@@ -678,6 +916,7 @@ pub async fn run_repl_command(
                     config.write().exit_role()?;
                 }
                 Some("session") => {
+                    extract_session_memories(config, abort_signal.clone()).await;
                     if config.read().agent.is_some() {
                         config.write().exit_agent_session()?;
                     } else {
@@ -704,7 +943,7 @@ pub async fn run_repl_command(
             _ => unknown_command()?,
         },
         None => {
-            let input = Input::from_str(config, line, None);
+            let input = Input::from_text(config, line, None, abort_signal.clone()).await?;
             ask(config, abort_signal.clone(), input, true).await?;
         }
     }
@@ -758,6 +997,21 @@ async fn ask(
     }
 }
 
+/// Best effort, a failure only warns so that leaving the session is never blocked
+async fn extract_session_memories(config: &GlobalConfig, abort_signal: AbortSignal) {
+    let ret = abortable_run_with_spinner(
+        extract_memories(config),
+        "Extracting memories",
+        abort_signal,
+    )
+    .await;
+    match ret {
+        Ok(0) => {}
+        Ok(count) => println!("✓ Remembered {count} facts from the session."),
+        Err(err) => warn!("Failed to extract memories: {err}"),
+    }
+}
+
 fn unknown_command() -> Result<()> {
     bail!(r#"Unknown command. Type ".help" for additional help."#);
 }
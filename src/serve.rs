@@ -256,7 +256,16 @@ impl Server {
             .map_err(|err| anyhow!("Invalid request json, {err}"))?;
 
         debug!("search rag request: {req_body}");
-        let SearchRagReqBody { name, input } = serde_json::from_value(req_body)
+        let SearchRagReqBody {
+            name,
+            input,
+            filter,
+        } = serde_json::from_value(req_body)
+            .map_err(|err| anyhow!("Invalid request body, {err}"))?;
+        let filter = filter
+            .as_deref()
+            .map(|v| v.parse::<RagFilter>())
+            .transpose()
             .map_err(|err| anyhow!("Invalid request body, {err}"))?;
 
         let config = Arc::new(RwLock::new(self.config.clone()));
@@ -264,11 +273,12 @@ impl Server {
         let abort_signal = create_abort_signal();
 
         let rag_path = config.read().rag_file(&name);
-        let rag = Rag::load(&config, &name, &rag_path)?;
+        let mut rag = Rag::load(&config, &name, &rag_path)?;
+        rag.set_filter(filter);
 
-        let rag_result = Config::search_rag(&config, &rag, &input, abort_signal).await?;
+        let (rag_result, images) = Config::search_rag(&config, &rag, &input, abort_signal).await?;
 
-        let data = json!({ "data": rag_result });
+        let data = json!({ "data": rag_result, "images": images });
         let res = Response::builder()
             .header("Content-Type", "application/json; charset=utf-8")
             .body(Full::new(Bytes::from(data.to_string())).boxed())?;
@@ -597,6 +607,7 @@ impl Server {
 struct SearchRagReqBody {
     name: String,
     input: String,
+    filter: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -1,3 +1,8 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Result};
 use base64::{engine::general_purpose::STANDARD, Engine};
 use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
@@ -9,7 +14,8 @@ pub fn sha256(input: &str) -> String {
 }
 
 pub fn hmac_sha256(key: &[u8], msg: &str) -> Vec<u8> {
-    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC can take key of any size");
     mac.update(msg.as_bytes());
     mac.finalize().into_bytes().to_vec()
 }
@@ -33,3 +39,33 @@ pub fn base64_encode<T: AsRef<[u8]>>(input: T) -> String {
 pub fn base64_decode<T: AsRef<[u8]>>(input: T) -> Result<Vec<u8>, base64::DecodeError> {
     STANDARD.decode(input)
 }
+
+pub fn generate_aes_key() -> Vec<u8> {
+    Aes256Gcm::generate_key(OsRng).to_vec()
+}
+
+/// Encrypt with AES-256-GCM, prefixing the output with the random nonce
+pub fn aes_gcm_encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != 32 {
+        bail!("Invalid key length");
+    }
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Failed to encrypt"))?;
+    let mut output = nonce.to_vec();
+    output.extend(ciphertext);
+    Ok(output)
+}
+
+pub fn aes_gcm_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != 32 || data.len() < 12 {
+        bail!("Invalid key or data length");
+    }
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let (nonce, ciphertext) = data.split_at(12);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt"))
+}
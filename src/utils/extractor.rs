@@ -0,0 +1,420 @@
+use super::*;
+
+use anyhow::{anyhow, bail, Context, Result};
+use calamine::{open_workbook_auto_from_rs, Reader};
+use fancy_regex::Regex;
+use indexmap::{IndexMap, IndexSet};
+use quick_xml::{events::Event, Reader as XmlReader};
+use reqwest::Url;
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+    sync::LazyLock,
+};
+use zip::ZipArchive;
+
+pub const NATIVE_EXTENSIONS: [&str; 6] = ["pdf", "docx", "pptx", "xlsx", "xls", "epub"];
+
+pub const PAGE_METADATA: &str = "page";
+pub const SLIDE_METADATA: &str = "slide";
+pub const SHEET_METADATA: &str = "sheet";
+pub const CHAPTER_METADATA: &str = "chapter";
+
+const MIN_IMAGE_SIZE: i64 = 64;
+
+static MARKDOWN_IMAGE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"!\[[^\]]*\]\(\s*<?([^)\s>]+)"#).unwrap());
+static HTML_IMAGE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)<img\b[^>]*?\bsrc\s*=\s*["']([^"']+)["']"#).unwrap());
+
+#[derive(Debug, Clone, Default)]
+pub struct DocumentSection {
+    pub contents: String,
+    pub metadata: DocumentMetadata,
+    /// Image paths, urls or data urls found in this section
+    pub images: Vec<String>,
+}
+
+impl DocumentSection {
+    fn new(contents: String, key: &str, value: String) -> Self {
+        let mut metadata: DocumentMetadata = Default::default();
+        metadata.insert(key.into(), value);
+        Self {
+            contents,
+            metadata,
+            images: vec![],
+        }
+    }
+}
+
+pub fn is_native_extension(extension: &str) -> bool {
+    NATIVE_EXTENSIONS.contains(&extension)
+}
+
+/// Extract text from office formats, returning the sections and the extension of the output text
+pub fn extract_document(data: &[u8], extension: &str) -> Result<(Vec<DocumentSection>, String)> {
+    let (sections, output_extension) = match extension {
+        "pdf" => (extract_pdf(data)?, DEFAULT_EXTENSION),
+        "docx" => (extract_docx(data)?, DEFAULT_EXTENSION),
+        "pptx" => (extract_pptx(data)?, DEFAULT_EXTENSION),
+        "xlsx" | "xls" => (extract_spreadsheet(data)?, DEFAULT_EXTENSION),
+        "epub" => (extract_epub(data)?, "md"),
+        _ => bail!("No native loader for '{extension}'"),
+    };
+    let sections = sections
+        .into_iter()
+        .filter(|v| !v.contents.trim().is_empty() || !v.images.is_empty())
+        .collect();
+    Ok((sections, output_extension.to_string()))
+}
+
+fn extract_pdf(data: &[u8]) -> Result<Vec<DocumentSection>> {
+    let document = lopdf::Document::load_mem(data).context("Invalid pdf")?;
+    if document.is_encrypted() {
+        bail!("Encrypted pdf is not supported");
+    }
+    let mut sections = vec![];
+    for (page_number, page_id) in document.get_pages() {
+        let contents = match document.extract_text(&[page_number]) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("Failed to extract text from page {page_number}: {err}");
+                String::new()
+            }
+        };
+        let mut section = DocumentSection::new(contents, PAGE_METADATA, page_number.to_string());
+        section.images = document
+            .get_page_images(page_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|image| {
+                image.width >= MIN_IMAGE_SIZE
+                    && image.height >= MIN_IMAGE_SIZE
+                    && image.filters.as_deref() == Some(&["DCTDecode".to_string()])
+            })
+            .map(|image| format!("data:image/jpeg;base64,{}", base64_encode(image.content)))
+            .collect();
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+fn extract_docx(data: &[u8]) -> Result<Vec<DocumentSection>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).context("Invalid docx")?;
+    let xml = read_zip_entry(&mut archive, "word/document.xml")?;
+    let contents = extract_xml_text(&xml, b"w:t", &[b"w:p"], &[b"w:br", b"w:cr"])?;
+    Ok(vec![DocumentSection {
+        contents,
+        ..Default::default()
+    }])
+}
+
+fn extract_pptx(data: &[u8]) -> Result<Vec<DocumentSection>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).context("Invalid pptx")?;
+    let mut slides: Vec<(usize, String)> = archive
+        .file_names()
+        .filter_map(|name| {
+            let number = name
+                .strip_prefix("ppt/slides/slide")?
+                .strip_suffix(".xml")?
+                .parse()
+                .ok()?;
+            Some((number, name.to_string()))
+        })
+        .collect();
+    slides.sort_unstable();
+    let mut sections = vec![];
+    for (number, name) in slides {
+        let xml = read_zip_entry(&mut archive, &name)?;
+        let contents = extract_xml_text(&xml, b"a:t", &[b"a:p"], &[b"a:br"])?;
+        sections.push(DocumentSection::new(
+            contents,
+            SLIDE_METADATA,
+            number.to_string(),
+        ));
+    }
+    Ok(sections)
+}
+
+fn extract_spreadsheet(data: &[u8]) -> Result<Vec<DocumentSection>> {
+    let mut workbook =
+        open_workbook_auto_from_rs(Cursor::new(data)).context("Invalid spreadsheet")?;
+    let mut sections = vec![];
+    for name in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&name)
+            .with_context(|| format!("Failed to read sheet '{name}'"))?;
+        let contents = range
+            .rows()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| cell.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\t")
+                    .trim_end()
+                    .to_string()
+            })
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        sections.push(DocumentSection::new(contents, SHEET_METADATA, name));
+    }
+    Ok(sections)
+}
+
+fn extract_epub(data: &[u8]) -> Result<Vec<DocumentSection>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).context("Invalid epub")?;
+    let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+    let opf_path = find_xml_attribute(&container, b"rootfile", b"full-path")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Invalid epub, no rootfile"))?;
+    let opf = read_zip_entry(&mut archive, &opf_path)?;
+    let base_dir = match opf_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{dir}/"),
+        None => String::new(),
+    };
+
+    let mut manifest = IndexMap::new();
+    let mut spine = vec![];
+    let mut reader = XmlReader::from_str(&opf);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"item" => {
+                    let id = get_xml_attribute(&e, b"id");
+                    let href = get_xml_attribute(&e, b"href");
+                    if let (Some(id), Some(href)) = (id, href) {
+                        manifest.insert(id, href);
+                    }
+                }
+                b"itemref" => {
+                    if let Some(idref) = get_xml_attribute(&e, b"idref") {
+                        spine.push(idref);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let mut sections = vec![];
+    for (index, idref) in spine.iter().enumerate() {
+        let Some(href) = manifest.get(idref) else {
+            continue;
+        };
+        let path = format!("{base_dir}{}", decode_uri(href));
+        let html = read_zip_entry(&mut archive, &path)?;
+        sections.push(DocumentSection::new(
+            html_to_md(&html),
+            CHAPTER_METADATA,
+            (index + 1).to_string(),
+        ));
+    }
+    Ok(sections)
+}
+
+/// Find the images referenced by a markdown or html document, resolved against its path
+pub fn extract_image_urls(contents: &str, extension: &str, path: &str) -> Vec<String> {
+    let re = match extension {
+        "md" | "markdown" | "mdx" => &MARKDOWN_IMAGE_RE,
+        "html" | "htm" => &HTML_IMAGE_RE,
+        _ => return vec![],
+    };
+    let mut output = IndexSet::new();
+    for captures in re.captures_iter(contents).flatten() {
+        let src = captures[1].trim();
+        let url = if src.starts_with("data:image/") || is_url(src) {
+            src.to_string()
+        } else if is_url(path) {
+            match Url::parse(path).and_then(|v| v.join(src)) {
+                Ok(v) => v.to_string(),
+                Err(_) => continue,
+            }
+        } else {
+            if !is_image(src) {
+                continue;
+            }
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            match to_absolute_path(&dir.join(decode_uri(src)).display().to_string()) {
+                Ok(v) => v,
+                Err(_) => continue,
+            }
+        };
+        output.insert(url);
+    }
+    output.into_iter().collect()
+}
+
+fn read_zip_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<String> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("Missing '{name}'"))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .with_context(|| format!("Failed to read '{name}'"))?;
+    Ok(contents)
+}
+
+fn extract_xml_text(
+    xml: &str,
+    text_tag: &[u8],
+    paragraph_tags: &[&[u8]],
+    break_tags: &[&[u8]],
+) -> Result<String> {
+    let mut reader = XmlReader::from_str(xml);
+    let mut output = String::new();
+    let mut in_text = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name().as_ref() == text_tag => in_text = true,
+            Event::End(e) if e.name().as_ref() == text_tag => in_text = false,
+            Event::End(e) if paragraph_tags.contains(&e.name().as_ref()) => output.push('\n'),
+            Event::Empty(e) if break_tags.contains(&e.name().as_ref()) => output.push('\n'),
+            Event::Empty(e) if e.name().as_ref() == b"w:tab" => output.push('\t'),
+            Event::Text(e) if in_text => output.push_str(&e.unescape()?),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+fn find_xml_attribute(xml: &str, tag: &[u8], attribute: &[u8]) -> Result<Vec<String>> {
+    let mut reader = XmlReader::from_str(xml);
+    let mut output = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == tag => {
+                if let Some(value) = get_xml_attribute(&e, attribute) {
+                    output.push(value);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(output)
+}
+
+fn get_xml_attribute(element: &quick_xml::events::BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|v| v.key.local_name().as_ref() == name)
+        .and_then(|v| v.unescape_value().ok().map(|v| v.to_string()))
+}
+
+fn decode_uri(value: &str) -> String {
+    urlencoding::decode(value)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_xml_text() {
+        let xml = r#"<w:document><w:body><w:p><w:r><w:t>Hello</w:t></w:r><w:r><w:tab/><w:t xml:space="preserve"> world &amp; more</w:t></w:r></w:p><w:p><w:r><w:t>Second</w:t></w:r></w:p></w:body></w:document>"#;
+        let text = extract_xml_text(xml, b"w:t", &[b"w:p"], &[b"w:br"]).unwrap();
+        assert_eq!(text, "Hello\t world & more\nSecond\n");
+    }
+
+    fn extract_fixture(data: &[u8], extension: &str) -> Vec<(String, String)> {
+        let (sections, _) = extract_document(data, extension).unwrap();
+        sections
+            .into_iter()
+            .map(|v| {
+                let metadata = v
+                    .metadata
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (metadata, v.contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_pdf() {
+        assert_eq!(
+            extract_fixture(include_bytes!("../../tests/fixtures/sample.pdf"), "pdf"),
+            vec![
+                ("page=1".into(), "First page text\n".into()),
+                ("page=2".into(), "Second page text\n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_docx() {
+        assert_eq!(
+            extract_fixture(include_bytes!("../../tests/fixtures/sample.docx"), "docx"),
+            vec![(
+                "".into(),
+                "Quarterly report\nRevenue grew 12%\nCosts fell\n".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_extract_pptx() {
+        assert_eq!(
+            extract_fixture(include_bytes!("../../tests/fixtures/sample.pptx"), "pptx"),
+            vec![
+                ("slide=2".into(), "Agenda\n".into()),
+                ("slide=10".into(), "Roadmap\n".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_xlsx() {
+        assert_eq!(
+            extract_fixture(include_bytes!("../../tests/fixtures/sample.xlsx"), "xlsx"),
+            vec![
+                (
+                    "sheet=Sales".into(),
+                    "Region\tTotal\nNorth\t120\nSouth\t80".into()
+                ),
+                ("sheet=Notes".into(), "Checked by finance".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_epub() {
+        assert_eq!(
+            extract_fixture(include_bytes!("../../tests/fixtures/sample.epub"), "epub"),
+            vec![
+                (
+                    "chapter=1".into(),
+                    "# Beginnings\n\nIt was a quiet morning.".into()
+                ),
+                (
+                    "chapter=2".into(),
+                    "# Endings\n\nThe story ends here.".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_image_urls() {
+        let contents = "![arch](./images/arch.png) ![logo](https://example.com/logo.svg) [link](a.md) ![x](notes.txt)";
+        assert_eq!(
+            extract_image_urls(contents, "md", "/docs/design.md"),
+            vec!["/docs/images/arch.png", "https://example.com/logo.svg"]
+        );
+        let contents = r#"<p><img alt="flow" src="img/flow.jpg"></p>"#;
+        assert_eq!(
+            extract_image_urls(contents, "html", "https://example.com/docs/index.html"),
+            vec!["https://example.com/docs/img/flow.jpg"]
+        );
+    }
+}
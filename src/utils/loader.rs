@@ -1,11 +1,15 @@
 use super::*;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, fs::File, io::Read};
 
 pub const EXTENSION_METADATA: &str = "__extension__";
+pub const LOADER_METADATA: &str = "__loader__";
+pub const IMAGE_METADATA: &str = "__image__";
+
+pub const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
 
 pub type DocumentMetadata = IndexMap<String, String>;
 
@@ -15,6 +19,8 @@ pub struct LoadedDocument {
     pub contents: String,
     #[serde(default)]
     pub metadata: DocumentMetadata,
+    #[serde(skip)]
+    pub sections: Vec<DocumentSection>,
 }
 
 impl LoadedDocument {
@@ -23,6 +29,7 @@ impl LoadedDocument {
             path,
             contents,
             metadata,
+            sections: vec![],
         }
     }
 }
@@ -32,14 +39,15 @@ pub async fn load_recursive_url(
     path: &str,
 ) -> Result<Vec<LoadedDocument>> {
     let extension = RECURSIVE_URL_LOADER;
-    let pages: Vec<Page> = match loaders.get(extension) {
+    let (pages, with_loader): (Vec<Page>, bool) = match loaders.get(extension) {
         Some(loader_command) => {
             let contents = run_loader_command(path, extension, loader_command)?;
-            serde_json::from_str(&contents).context(r#"The crawler response is invalid. It should follow the JSON format: `[{"path":"...", "text":"..."}]`."#)?
+            let pages = serde_json::from_str(&contents).context(r#"The crawler response is invalid. It should follow the JSON format: `[{"path":"...", "text":"..."}]`."#)?;
+            (pages, true)
         }
         None => {
             let options = CrawlOptions::preset(path);
-            crawl_website(path, options).await?
+            (crawl_website(path, options).await?, false)
         }
     };
     let output = pages
@@ -48,6 +56,9 @@ pub async fn load_recursive_url(
             let Page { path, text } = v;
             let mut metadata: DocumentMetadata = Default::default();
             metadata.insert(EXTENSION_METADATA.into(), "md".into());
+            if with_loader {
+                metadata.insert(LOADER_METADATA.into(), extension.into());
+            }
             LoadedDocument::new(path, text, metadata)
         })
         .collect();
@@ -58,6 +69,8 @@ pub async fn load_file(loaders: &HashMap<String, String>, path: &str) -> Result<
     let extension = get_patch_extension(path).unwrap_or_else(|| DEFAULT_EXTENSION.into());
     match loaders.get(&extension) {
         Some(loader_command) => load_with_command(path, &extension, loader_command),
+        None if is_native_extension(&extension) => load_native(path, &extension).await,
+        None if IMAGE_EXTS.contains(&extension.as_str()) => load_image_file(path, &extension),
         None => load_plain(path, &extension).await,
     }
 }
@@ -76,10 +89,41 @@ async fn load_plain(path: &str, extension: &str) -> Result<LoadedDocument> {
     Ok(LoadedDocument::new(path.into(), contents, metadata))
 }
 
+async fn load_native(path: &str, extension: &str) -> Result<LoadedDocument> {
+    let data = tokio::fs::read(path).await?;
+    let (sections, output_extension) = extract_document(&data, extension)
+        .with_context(|| format!("Failed to extract text from '{path}'"))?;
+    let contents = sections
+        .iter()
+        .map(|v| v.contents.as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let mut metadata: DocumentMetadata = Default::default();
+    metadata.insert(EXTENSION_METADATA.into(), output_extension);
+    metadata.insert(LOADER_METADATA.into(), extension.to_string());
+    let mut document = LoadedDocument::new(path.into(), contents, metadata);
+    document.sections = sections;
+    Ok(document)
+}
+
+fn load_image_file(path: &str, extension: &str) -> Result<LoadedDocument> {
+    // The data url only feeds the file hash, the image itself is indexed from its section
+    let contents = read_media_to_data_url(path)?;
+    let mut metadata: DocumentMetadata = Default::default();
+    metadata.insert(EXTENSION_METADATA.into(), extension.to_string());
+    let mut document = LoadedDocument::new(path.into(), contents, metadata);
+    document.sections = vec![DocumentSection {
+        images: vec![path.to_string()],
+        ..Default::default()
+    }];
+    Ok(document)
+}
+
 fn load_with_command(path: &str, extension: &str, loader_command: &str) -> Result<LoadedDocument> {
     let contents = run_loader_command(path, extension, loader_command)?;
     let mut metadata: DocumentMetadata = Default::default();
     metadata.insert(EXTENSION_METADATA.into(), DEFAULT_EXTENSION.to_string());
+    metadata.insert(LOADER_METADATA.into(), extension.to_string());
     Ok(LoadedDocument::new(path.into(), contents, metadata))
 }
 
@@ -105,6 +149,8 @@ pub fn load_protocol_path(
     let output = if let Ok(list) = serde_json::from_str::<Vec<LoadedDocument>>(&contents) {
         list.into_iter()
             .map(|mut v| {
+                v.metadata
+                    .insert(LOADER_METADATA.into(), protocol.to_string());
                 if v.path.starts_with(path) {
                 } else if v.path.starts_with(new_path) {
                     v.path = format!("{}:{}", protocol, v.path);
@@ -115,11 +161,49 @@ pub fn load_protocol_path(
             })
             .collect()
     } else {
-        vec![LoadedDocument::new(
-            path.into(),
-            contents,
-            Default::default(),
-        )]
+        let mut metadata: DocumentMetadata = Default::default();
+        metadata.insert(LOADER_METADATA.into(), protocol.to_string());
+        vec![LoadedDocument::new(path.into(), contents, metadata)]
     };
     Ok(output)
 }
+
+/// Load an image path, url or data url as a data url
+pub async fn load_image(loaders: &HashMap<String, String>, path: &str) -> Result<String> {
+    if path.starts_with("data:") {
+        Ok(path.to_string())
+    } else if is_url(path) {
+        let (contents, extension) = fetch_with_loaders(loaders, path, true).await?;
+        if extension != MEDIA_URL_EXTENSION {
+            bail!("'{path}' is not an image");
+        }
+        Ok(contents)
+    } else {
+        read_media_to_data_url(path)
+    }
+}
+
+pub fn is_image(path: &str) -> bool {
+    get_patch_extension(path)
+        .map(|v| IMAGE_EXTS.contains(&v.as_str()))
+        .unwrap_or_default()
+}
+
+pub fn read_media_to_data_url(image_path: &str) -> Result<String> {
+    let extension = get_patch_extension(image_path).unwrap_or_default();
+    let mime_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        _ => bail!("Unexpected media type"),
+    };
+    let mut file = File::open(image_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+
+    let encoded_image = base64_encode(buffer);
+    let data_url = format!("data:{mime_type};base64,{encoded_image}");
+
+    Ok(data_url)
+}
@@ -2,12 +2,14 @@ mod abort_signal;
 mod clipboard;
 mod command;
 mod crypto;
+mod extractor;
 mod html_to_md;
 mod input;
 mod loader;
 mod path;
 mod render_prompt;
 mod request;
+mod sandbox;
 mod spinner;
 mod variables;
 
@@ -15,12 +17,14 @@ pub use self::abort_signal::*;
 pub use self::clipboard::set_text;
 pub use self::command::*;
 pub use self::crypto::*;
+pub use self::extractor::*;
 pub use self::html_to_md::*;
 pub use self::input::*;
 pub use self::loader::*;
 pub use self::path::*;
 pub use self::render_prompt::render_prompt;
 pub use self::request::*;
+pub use self::sandbox::*;
 pub use self::spinner::*;
 pub use self::variables::*;
 
@@ -176,6 +180,24 @@ pub fn dimmed_text(input: &str) -> String {
     nu_ansi_term::Style::new().dimmed().paint(input).to_string()
 }
 
+/// The first line of the prompt, cut to 80 characters
+pub fn truncate_prompt(prompt: &str) -> String {
+    let line = prompt.lines().next().unwrap_or_default();
+    match line.char_indices().nth(80) {
+        Some((index, _)) => format!("{}…", &line[..index]),
+        None if line.len() < prompt.len() => format!("{line}…"),
+        None => line.to_string(),
+    }
+}
+
+/// The last `limit` characters of a command output
+pub fn truncate_output(output: &str, limit: usize) -> String {
+    match output.char_indices().rev().nth(limit) {
+        Some((index, _)) => format!("…{}", &output[index..]),
+        None => output.to_string(),
+    }
+}
+
 pub fn multiline_text(input: &str) -> String {
     input
         .split('\n')
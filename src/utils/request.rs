@@ -153,6 +153,17 @@ pub async fn fetch_with_loaders(
                 };
                 (contents, DEFAULT_EXTENSION.into())
             }
+            None if is_native_extension(&extension) => {
+                let data = res.bytes().await?;
+                let (sections, extension) = extract_document(&data, &extension)
+                    .with_context(|| format!("Failed to extract text from '{path}'"))?;
+                let contents = sections
+                    .into_iter()
+                    .map(|v| v.contents)
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                (contents, extension)
+            }
             None => {
                 let contents = res.text().await?;
                 if extension == "html" {
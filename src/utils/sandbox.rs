@@ -0,0 +1,155 @@
+use super::warning_text;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    sync::Once,
+};
+use tokio::process::Command;
+
+static FALLBACK_WARNING: Once = Once::new();
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    /// The only writable directory, defaults to the current directory
+    pub workdir: Option<PathBuf>,
+    pub network: bool,
+    /// Virtual memory limit in MiB
+    pub max_memory: Option<u64>,
+    /// CPU time limit in seconds
+    pub max_cpu_time: Option<u64>,
+    /// Environment variables passed through to tools
+    pub keep_env: Vec<String>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            workdir: None,
+            network: false,
+            max_memory: None,
+            max_cpu_time: None,
+            keep_env: ["HOME", "LANG", "TERM", "USER"]
+                .into_iter()
+                .map(|v| v.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl SandboxConfig {
+    /// Build a command that runs `program` with a read-only filesystem, a scrubbed environment and resource limits.
+    /// Only the workdir and `writable_paths` stay writable.
+    pub fn command(
+        &self,
+        program: &str,
+        args: &[String],
+        envs: HashMap<String, String>,
+        writable_paths: &[&Path],
+    ) -> Result<Command> {
+        if !cfg!(target_os = "linux") {
+            bail!("The tool sandbox is only supported on Linux");
+        }
+        let workdir = match &self.workdir {
+            Some(v) => v.clone(),
+            None => env::current_dir()?,
+        };
+        let mut argv: Vec<String> = vec![];
+        if let Ok(bwrap) = which::which("bwrap") {
+            argv.push(bwrap.display().to_string());
+            argv.extend(
+                [
+                    "--ro-bind",
+                    "/",
+                    "/",
+                    "--dev",
+                    "/dev",
+                    "--proc",
+                    "/proc",
+                    "--tmpfs",
+                    "/tmp",
+                ]
+                .map(|v| v.to_string()),
+            );
+            for path in std::iter::once(workdir.as_path()).chain(writable_paths.iter().copied()) {
+                let path = path.display().to_string();
+                argv.extend(["--bind".into(), path.clone(), path]);
+            }
+            argv.extend(
+                ["--unshare-all", "--die-with-parent", "--new-session"].map(|v| v.to_string()),
+            );
+            if self.network {
+                argv.push("--share-net".into());
+            }
+            argv.push("--".into());
+        } else {
+            FALLBACK_WARNING.call_once(|| {
+                let message =
+                    "⚠️ `bwrap` not found, the tool sandbox cannot protect the filesystem";
+                eprintln!("{}", warning_text(message));
+            });
+            if !self.network {
+                let Ok(unshare) = which::which("unshare") else {
+                    bail!(
+                        "The tool sandbox requires `bwrap` or `unshare` to disable network access"
+                    );
+                };
+                argv.push(unshare.display().to_string());
+                argv.extend(["--user", "--map-root-user", "--net", "--"].map(|v| v.to_string()));
+            }
+        }
+        let limits = self.ulimit_script();
+        if !limits.is_empty() {
+            argv.extend([
+                "sh".into(),
+                "-c".into(),
+                format!("{limits} exec \"$0\" \"$@\""),
+            ]);
+        }
+        argv.push(program.to_string());
+        argv.extend(args.iter().cloned());
+
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]).current_dir(&workdir).env_clear();
+        for key in &self.keep_env {
+            if let Ok(value) = env::var(key) {
+                command.env(key, value);
+            }
+        }
+        command.envs(envs);
+        Ok(command)
+    }
+
+    fn ulimit_script(&self) -> String {
+        let mut script = String::new();
+        if let Some(max_memory) = self.max_memory {
+            script.push_str(&format!("ulimit -v {};", max_memory * 1024));
+        }
+        if let Some(max_cpu_time) = self.max_cpu_time {
+            script.push_str(&format!("ulimit -t {max_cpu_time};"));
+        }
+        script
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ulimit_script() {
+        let config = SandboxConfig {
+            max_memory: Some(512),
+            max_cpu_time: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(config.ulimit_script(), "ulimit -v 524288;ulimit -t 30;");
+        assert_eq!(SandboxConfig::default().ulimit_script(), "");
+    }
+}
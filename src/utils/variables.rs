@@ -25,8 +25,27 @@ pub fn interpolate_variables(text: &mut String) {
                 "__cwd__" => env::current_dir()
                     .map(|v| v.display().to_string())
                     .unwrap_or_default(),
+                "__git_status__" => git_status_summary(),
                 _ => format!("{{{{{key}}}}}"),
             }
         })
         .to_string();
 }
+
+fn git_status_summary() -> String {
+    const MAX_LINES: usize = 20;
+    let output = match run_command_with_output("git", &["status", "--short", "--branch"], None) {
+        Ok((true, stdout, _)) => stdout,
+        _ => return "not a git repository".into(),
+    };
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.len() > MAX_LINES {
+        format!(
+            "{}\n... {} more changes",
+            lines[..MAX_LINES].join("\n"),
+            lines.len() - MAX_LINES
+        )
+    } else {
+        lines.join("\n")
+    }
+}